@@ -0,0 +1,835 @@
+//! Stack unwinding
+
+use core::mem;
+use std::{
+    borrow::Cow,
+    collections::{btree_map, BTreeMap, HashSet},
+    fmt,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use addr2line::fallible_iterator::FallibleIterator as _;
+use anyhow::{anyhow, bail, Context};
+use gimli::{
+    read::{
+        CallFrameInstruction, CfaRule, DebugFrame, EvaluationResult, Location as PieceLocation,
+        UnwindSection, Value,
+    },
+    BaseAddresses, Encoding, EndianSlice, Expression, Format, LittleEndian, RegisterRule,
+    UninitializedUnwindContext,
+};
+use object::read::{File as ElfFile, Object as _, SymbolMap};
+use probe_rs::CoreRegisterAddress;
+use serde::Serialize;
+
+use crate::{
+    elf::VectorTable,
+    exception::Exception,
+    locals::{self, FrameState},
+    stacked::Stacked,
+    target::TargetAccess,
+    LR, LR_END, SP, THUMB_BIT, XPSR,
+};
+
+/// The result of unwinding the stack of a halted device
+#[derive(Debug)]
+pub struct Backtrace {
+    /// Frames, innermost first
+    pub frames: Vec<Frame>,
+    /// The exception, if any, that the device was servicing when it halted
+    pub top_exception: Option<TopException>,
+    /// Unwinding stopped early because the stack appears to be corrupted
+    pub corrupted: bool,
+    /// Unwinding stopped early because the unwind information of a frame could not be evaluated
+    pub incomplete: Option<String>,
+    /// Registers stacked on entry to the innermost exception
+    pub exception_frame: Option<Stacked>,
+}
+
+impl Backtrace {
+    /// Program counter of the code that the innermost exception interrupted, as stacked on
+    /// exception entry
+    pub fn interrupted_pc(&self) -> Option<u32> {
+        self.frames
+            .iter()
+            .skip_while(|frame| !matches!(frame, Frame::Exception(_)))
+            .find_map(|frame| match frame {
+                Frame::Subroutine(subroutine) => Some(subroutine.pc),
+                Frame::Exception(_) => None,
+            })
+    }
+}
+
+/// A backtrace frame
+#[derive(Debug)]
+pub enum Frame {
+    Subroutine(Subroutine),
+    /// An exception / interrupt was entered at this point
+    Exception(ExceptionEntry),
+}
+
+/// The exception / interrupt whose handler is the frame above an exception entry
+#[derive(Debug)]
+pub struct ExceptionEntry {
+    /// `None` when the exception number is not known
+    pub exception: Option<Exception>,
+    /// Name of the interrupt handler, according to the vector table; only set for interrupts
+    pub handler: Option<String>,
+}
+
+/// Formats the entry like `<exception entry: IRQ 23 (USART1)>`
+impl fmt::Display for ExceptionEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.exception, &self.handler) {
+            (Some(exception), Some(handler)) => {
+                write!(f, "<exception entry: {} ({})>", exception, handler)
+            }
+            (Some(exception), None) => write!(f, "<exception entry: {}>", exception),
+            (None, _) => f.write_str("<exception entry>"),
+        }
+    }
+}
+
+/// A function, possibly inlined, that was executing when the device halted
+#[derive(Debug)]
+pub struct Subroutine {
+    /// Demangled function name; `"???"` when unknown
+    pub name: String,
+    /// Program counter of the physical frame this function belongs to
+    pub pc: u32,
+    /// Source location; relative to the current directory when possible
+    pub location: Option<Location>,
+    /// This function was inlined into its caller
+    pub is_inlined: bool,
+    /// Arguments and local variables; only read with `--backtrace-locals`
+    pub locals: Vec<Variable>,
+}
+
+/// An argument or local variable of a function
+#[derive(Debug, Serialize)]
+pub struct Variable {
+    pub name: String,
+    /// The formatted value, or the reason why it's not available, e.g. `<optimized out>`
+    pub value: String,
+}
+
+/// A location in the source code
+#[derive(Debug)]
+pub struct Location {
+    pub file: PathBuf,
+    pub line: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TopException {
+    /// The device halted on entry to a fault handler: HardFault's or, with `--catch-faults`, that
+    /// of a configurable fault
+    HardFault {
+        /// The exception being serviced; not necessarily a fault when the handler is shared
+        exception: Exception,
+        stack_overflow: bool,
+    },
+    Other,
+}
+
+/// Formats the backtrace like `std` backtraces
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "stack backtrace:")?;
+        let mut frame_index = 0;
+        for frame in &self.frames {
+            match frame {
+                Frame::Subroutine(subroutine) => {
+                    writeln!(f, "{:>4}: {}", frame_index, subroutine.name)?;
+                    frame_index += 1;
+
+                    if let Some(location) = &subroutine.location {
+                        writeln!(
+                            f,
+                            "        at {}:{}",
+                            location.file.display(),
+                            location.line
+                        )?;
+                    }
+
+                    for local in &subroutine.locals {
+                        writeln!(f, "          {} = {}", local.name, local.value)?;
+                    }
+                }
+
+                Frame::Exception(entry) => writeln!(f, "      {}", entry)?,
+            }
+        }
+
+        if self.corrupted {
+            writeln!(
+                f,
+                "error: the stack appears to be corrupted beyond this point"
+            )?;
+        }
+
+        if let Some(reason) = &self.incomplete {
+            writeln!(f, "error: cannot unwind further: {}", reason)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Prints `backtrace` to stdout
+pub fn print(backtrace: &Backtrace) {
+    print!("{}", backtrace);
+}
+
+fn gimli2probe(reg: &gimli::Register) -> CoreRegisterAddress {
+    CoreRegisterAddress(reg.0)
+}
+
+/// Encoding of the expressions in `.debug_frame`
+const ENCODING: Encoding = Encoding {
+    address_size: 4,
+    format: Format::Dwarf32,
+    version: 4,
+};
+
+/// Registers that a function may clobber without restoring them: R0-R3 and R12
+const CALLER_SAVED: [u16; 5] = [0, 1, 2, 3, 12];
+
+/// The registers of the frame being unwound
+///
+/// The CFA of the frame is stored as the value of SP, which is the value SP has in the caller.
+struct Registers<'c, T: TargetAccess> {
+    cache: BTreeMap<u16, u32>,
+    /// Registers whose value can't be recovered in the caller (`RegisterRule::Undefined`)
+    undefined: HashSet<u16>,
+    core: &'c mut T,
+}
+
+impl<'c, T: TargetAccess> Registers<'c, T> {
+    fn new(lr: u32, sp: u32, core: &'c mut T) -> Self {
+        let mut cache = BTreeMap::new();
+        cache.insert(LR.0, lr);
+        cache.insert(SP.0, sp);
+        Self {
+            cache,
+            undefined: HashSet::new(),
+            core,
+        }
+    }
+
+    fn get(&mut self, reg: CoreRegisterAddress) -> Result<u32, anyhow::Error> {
+        if self.undefined.contains(&reg.0) {
+            bail!("the value of register {} is unknown in this frame", reg.0);
+        }
+
+        Ok(match self.cache.entry(reg.0) {
+            btree_map::Entry::Occupied(entry) => *entry.get(),
+            btree_map::Entry::Vacant(entry) => *entry.insert(self.core.read_core_reg(reg)?),
+        })
+    }
+
+    fn insert(&mut self, reg: CoreRegisterAddress, val: u32) {
+        self.undefined.remove(&reg.0);
+        self.cache.insert(reg.0, val);
+    }
+
+    fn is_undefined(&self, reg: CoreRegisterAddress) -> bool {
+        self.undefined.contains(&reg.0)
+    }
+
+    /// The CFA of the frame being unwound, according to `rule`
+    fn cfa(&mut self, rule: &CfaRule<EndianSlice<LittleEndian>>) -> Result<u32, anyhow::Error> {
+        Ok(match rule {
+            CfaRule::RegisterAndOffset { register, offset } => {
+                (i64::from(self.get(gimli2probe(register))?) + offset) as u32
+            }
+
+            CfaRule::Expression(expr) => self.evaluate(*expr, None)?,
+        })
+    }
+
+    /// Applies an unwind table row: the CFA `rule`, whose result becomes the value of SP, the
+    /// register `rules` and the `undefined` registers
+    fn update<'r>(
+        &mut self,
+        cfa_rule: &CfaRule<EndianSlice<LittleEndian>>,
+        rules: impl Iterator<Item = &'r (gimli::Register, RegisterRule<EndianSlice<'r, LittleEndian>>)>,
+        undefined: &HashSet<u16>,
+    ) -> Result</* cfa_changed: */ bool, anyhow::Error> {
+        // all rules, the CFA rule included, refer to the values the registers have in the frame
+        // being unwound so compute them all before updating any register
+        let cfa = self.cfa(cfa_rule)?;
+        let mut values = vec![];
+        for (reg, rule) in rules {
+            let value = self
+                .recover(reg, rule, cfa)
+                .with_context(|| format!("failed to recover register {}", reg.0))?;
+            values.push((gimli2probe(reg), value));
+        }
+
+        let old_cfa = self.cache.get(&SP.0);
+        let changed = old_cfa != Some(&cfa);
+        if changed {
+            log::debug!("update: CFA changed {:8x?} -> {:8x}", old_cfa, cfa);
+        }
+        self.insert(SP, cfa);
+
+        for (reg, value) in values {
+            match value {
+                Some(value) => self.insert(reg, value),
+                None => {
+                    self.cache.remove(&reg.0);
+                    self.undefined.insert(reg.0);
+                }
+            }
+        }
+
+        for reg in undefined {
+            self.cache.remove(reg);
+            self.undefined.insert(*reg);
+        }
+
+        Ok(changed)
+    }
+
+    /// Forgets the caller-saved registers, whose values in the caller are unknown unless the
+    /// unwind information says otherwise
+    fn clobber_caller_saved(&mut self) {
+        for reg in &CALLER_SAVED {
+            self.cache.remove(reg);
+            self.undefined.insert(*reg);
+        }
+    }
+
+    /// The value `reg` has in the caller, according to `rule` and the `cfa` of the frame being
+    /// unwound; `None` if it can't be recovered
+    fn recover(
+        &mut self,
+        reg: &gimli::Register,
+        rule: &RegisterRule<EndianSlice<LittleEndian>>,
+        cfa: u32,
+    ) -> Result<Option<u32>, anyhow::Error> {
+        Ok(Some(match rule {
+            RegisterRule::Undefined => return Ok(None),
+
+            RegisterRule::SameValue => self.get(gimli2probe(reg))?,
+
+            RegisterRule::Offset(offset) => {
+                let addr = (i64::from(cfa) + offset) as u32;
+                self.core.read_word_32(addr)?
+            }
+
+            RegisterRule::ValOffset(offset) => (i64::from(cfa) + offset) as u32,
+
+            RegisterRule::Register(other) => self.get(gimli2probe(other))?,
+
+            RegisterRule::Expression(expr) => {
+                let addr = self.evaluate(*expr, Some(cfa))?;
+                self.core.read_word_32(addr)?
+            }
+
+            RegisterRule::ValExpression(expr) => self.evaluate(*expr, Some(cfa))?,
+
+            RegisterRule::Architectural => bail!("architectural register rules are not supported"),
+        }))
+    }
+
+    /// Evaluates a DWARF expression, reading registers and target memory as needed
+    ///
+    /// `cfa` is pushed on the expression stack before evaluation, as register rules require, and
+    /// is the value of `DW_OP_call_frame_cfa`; it's `None` while the CFA itself is computed.
+    fn evaluate(
+        &mut self,
+        expr: Expression<EndianSlice<LittleEndian>>,
+        cfa: Option<u32>,
+    ) -> Result<u32, anyhow::Error> {
+        let mut eval = expr.evaluation(ENCODING);
+        if let Some(cfa) = cfa {
+            eval.set_initial_value(cfa.into());
+        }
+
+        let mut result = eval.evaluate()?;
+        loop {
+            result = match result {
+                EvaluationResult::Complete => break,
+
+                EvaluationResult::RequiresMemory { address, size, .. } => {
+                    let mut bytes = [0; 4];
+                    let size = usize::from(size).min(bytes.len());
+                    self.core.read_8(address as u32, &mut bytes[..size])?;
+                    let value = u32::from_le_bytes(bytes);
+                    eval.resume_with_memory(Value::Generic(value.into()))?
+                }
+
+                EvaluationResult::RequiresRegister { register, .. } => {
+                    let value = self.get(gimli2probe(&register))?;
+                    eval.resume_with_register(Value::Generic(value.into()))?
+                }
+
+                EvaluationResult::RequiresCallFrameCfa => match cfa {
+                    Some(cfa) => eval.resume_with_call_frame_cfa(cfa.into())?,
+                    None => bail!("the CFA expression refers to the CFA"),
+                },
+
+                // statically linked binary -- there are no relative addresses
+                EvaluationResult::RequiresRelocatedAddress(address) => {
+                    eval.resume_with_relocated_address(address)?
+                }
+
+                other => bail!("unsupported DWARF expression operation ({:?})", other),
+            };
+        }
+
+        let pieces = eval.result();
+        match pieces.first().map(|piece| &piece.location) {
+            Some(PieceLocation::Address { address }) => Ok(*address as u32),
+            Some(PieceLocation::Value { value }) => Ok(value.to_u64(u64::from(u32::MAX))? as u32),
+            Some(PieceLocation::Register { register }) => self.get(gimli2probe(register)),
+            location => Err(anyhow!(
+                "unsupported DWARF expression result ({:?})",
+                location
+            )),
+        }
+    }
+}
+
+impl<T: TargetAccess> FrameState for Registers<'_, T> {
+    fn register(&mut self, reg: u16) -> Result<u32, anyhow::Error> {
+        self.get(CoreRegisterAddress(reg))
+    }
+
+    fn read_memory(&mut self, address: u32, data: &mut [u8]) -> Result<(), anyhow::Error> {
+        self.core.read_8(address, data)
+    }
+}
+
+/// Unwinds the stack of the halted `core`, starting at `pc`
+///
+/// `sp_ram_region` is the address range of the RAM region that contains the initial stack
+/// pointer; it's used to detect stack overflows.
+///
+/// With `locals` the arguments and local variables of each frame are read as well.
+///
+/// Once a frame has been found, errors don't fail the unwinding; the frames found so far are
+/// returned with [`Backtrace::incomplete`] set instead, e.g. for a core locked up at an address
+/// outside of the firmware.
+#[allow(clippy::too_many_arguments)]
+pub fn backtrace(
+    core: &mut impl TargetAccess,
+    pc: u32,
+    debug_frame: &[u8],
+    elf: &ElfFile,
+    vector_table: &VectorTable,
+    sp_ram_region: Option<&Range<u32>>,
+    live_functions: &HashSet<&str>,
+    current_dir: &Path,
+    locals: bool,
+) -> Result<Backtrace, anyhow::Error> {
+    let mut backtrace = Backtrace {
+        frames: vec![],
+        top_exception: None,
+        corrupted: false,
+        incomplete: None,
+        exception_frame: None,
+    };
+
+    if let Err(e) = unwind(
+        &mut backtrace,
+        core,
+        pc,
+        debug_frame,
+        elf,
+        vector_table,
+        sp_ram_region,
+        live_functions,
+        current_dir,
+        locals,
+    ) {
+        if backtrace.frames.is_empty() {
+            return Err(e);
+        }
+
+        log::debug!("cannot unwind further: {:?}", e);
+        backtrace.incomplete = Some(format!("{:#}", e));
+    }
+
+    Ok(backtrace)
+}
+
+/// Adds the frames of the stack of `core` to `backtrace`, innermost first
+#[allow(clippy::too_many_arguments)]
+fn unwind(
+    backtrace: &mut Backtrace,
+    core: &mut impl TargetAccess,
+    mut pc: u32,
+    debug_frame: &[u8],
+    elf: &ElfFile,
+    vector_table: &VectorTable,
+    sp_ram_region: Option<&Range<u32>>,
+    live_functions: &HashSet<&str>,
+    current_dir: &Path,
+    locals: bool,
+) -> Result<(), anyhow::Error> {
+    let mut debug_frame = DebugFrame::new(debug_frame, LittleEndian);
+    // 32-bit ARM -- this defaults to the host's address size which is likely going to be 8
+    debug_frame.set_address_size(mem::size_of::<u32>() as u8);
+
+    let sp = core.read_core_reg(SP)?;
+    let lr = core.read_core_reg(LR)?;
+    // the exception whose handler is running; the IPSR bits of xPSR are 0 in thread mode
+    let mut exception = match core.read_core_reg(XPSR) {
+        Ok(xpsr) => Some(Exception::from_xpsr(xpsr)),
+        Err(e) => {
+            log::debug!("failed to read xPSR: {}", e);
+            None
+        }
+    };
+
+    // statically linked binary -- there are no relative addresses
+    let bases = &BaseAddresses::default();
+    let ctx = &mut UninitializedUnwindContext::new();
+
+    let addr2line = addr2line::Context::new(elf)?;
+    let mut registers = Registers::new(lr, sp, core);
+    let symtab = elf.symbol_map();
+    loop {
+        let frames = addr2line.find_frames(pc as u64)?.collect::<Vec<_>>()?;
+        // when the input of `find_frames` is the PC of a subroutine that has no debug information
+        // (e.g. external assembly), it will either return an empty `FrameIter` OR the frames that
+        // correspond to a subroutine GC-ed by the linker, instead of an `Err`or.
+        // To detect the second failure mode we check that the last frame (the non-inline one) is
+        // actually "live" (exists in the final binary).
+        // When there's no debuginfo we fallback to a symtab lookup to at least provide the name of
+        // the function that contains the PC.
+        let subroutine = frames.last();
+        let has_valid_debuginfo = if let Some(function) =
+            subroutine.and_then(|subroutine| subroutine.function.as_ref())
+        {
+            live_functions.contains(&*function.raw_name()?)
+        } else {
+            false
+        };
+
+        // (index into `backtrace.frames`, DIE) of the functions whose locals will be read
+        let mut functions = vec![];
+        if has_valid_debuginfo {
+            let num_frames = frames.len();
+            for (index, frame) in frames.iter().enumerate() {
+                let name = frame
+                    .function
+                    .as_ref()
+                    .map(|function| function.demangle())
+                    .transpose()?
+                    .unwrap_or(Cow::Borrowed("???"));
+
+                let location = frame
+                    .location
+                    .as_ref()
+                    .and_then(|loc| loc.file.and_then(|file| loc.line.map(|line| (file, line))))
+                    .map(|(file, line)| {
+                        let file = Path::new(file);
+                        let relpath = if let Ok(relpath) = file.strip_prefix(&current_dir) {
+                            relpath
+                        } else {
+                            // not within current directory; use full path
+                            file
+                        };
+
+                        Location {
+                            file: relpath.to_owned(),
+                            line: line.into(),
+                        }
+                    });
+
+                if let Some(offset) = frame.dw_die_offset {
+                    functions.push((backtrace.frames.len(), offset));
+                }
+
+                backtrace.frames.push(Frame::Subroutine(Subroutine {
+                    name: name.into_owned(),
+                    pc,
+                    location,
+                    // only the last frame is the "physical" (non-inlined) one
+                    is_inlined: index + 1 != num_frames,
+                    locals: vec![],
+                }));
+            }
+        } else {
+            // .symtab fallback
+            // the .symtab appears to use address ranges that have their thumb bits set (e.g.
+            // `0x101..0x200`). Passing the `pc` with the thumb bit cleared (e.g. `0x100`) to the
+            // lookup function sometimes returns the *previous* symbol. Work around the issue by
+            // setting `pc`'s thumb bit before looking it up
+            let address = (pc | THUMB_BIT) as u64;
+            let name = symtab
+                .get(address)
+                .and_then(|symbol| symbol.name())
+                .unwrap_or("???");
+            backtrace.frames.push(Frame::Subroutine(Subroutine {
+                name: name.to_owned(),
+                pc,
+                location: None,
+                is_inlined: false,
+                locals: vec![],
+            }));
+        }
+
+        // on hard fault exception entry we hit the breakpoint before the subroutine prelude (`push
+        // lr`) is executed so special handling is required
+        // also note that hard fault will always be the first frame we unwind
+        if backtrace.top_exception.is_none() {
+            backtrace.top_exception = Some(if let Some(fault) = vector_table.fault_at(pc) {
+                // HardFaultTrampoline, or the handler of a configurable fault
+
+                let stack_overflow = if let Some(sp_ram_region) = sp_ram_region {
+                    // NOTE stack is full descending; meaning the stack pointer can be `ORIGIN(RAM) +
+                    // LENGTH(RAM)`
+                    let range = sp_ram_region.start..=sp_ram_region.end;
+                    !range.contains(&sp)
+                } else {
+                    log::warn!(
+                        "no RAM region appears to contain the stack; cannot determine if this was a stack overflow"
+                    );
+
+                    false
+                };
+
+                // a handler may be shared by several exceptions, like `DefaultHandler`; IPSR
+                // tells which one it is servicing
+                let exception = exception
+                    .filter(|exception| exception.0 != 0)
+                    .unwrap_or(fault);
+
+                TopException::HardFault {
+                    exception,
+                    stack_overflow,
+                }
+            } else {
+                TopException::Other
+            });
+        }
+
+        let uwt_row = debug_frame.unwind_info_for_address(bases, ctx, pc.into(), DebugFrame::cie_from_offset).with_context(|| {
+            "debug information is missing. Likely fixes:
+1. compile the Rust code with `debug = 1` or higher. This is configured in the `profile.*` section of Cargo.toml
+2. use a recent version of the `cortex-m` crates (e.g. cortex-m 0.6.3 or newer). Check versions in Cargo.lock
+3. if linking to C code, compile the C code with the `-g` flag"
+        })?;
+
+        if locals {
+            // the physical function, which owns the frame, is the last one
+            let subprogram = subroutine.and_then(|subroutine| subroutine.dw_die_offset);
+            if let (Some(subprogram), Some(unit)) =
+                (subprogram, addr2line.find_dwarf_unit(pc.into()))
+            {
+                match registers.cfa(uwt_row.cfa()) {
+                    Ok(cfa) => {
+                        let mut frame = locals::Frame {
+                            dwarf: addr2line.dwarf(),
+                            unit,
+                            pc,
+                            cfa,
+                            state: &mut registers,
+                        };
+
+                        for (index, function) in functions {
+                            match frame.variables(function, subprogram) {
+                                Ok(variables) => {
+                                    if let Frame::Subroutine(subroutine) =
+                                        &mut backtrace.frames[index]
+                                    {
+                                        subroutine.locals = variables;
+                                    }
+                                }
+                                Err(e) => log::debug!(
+                                    "failed to read the locals at pc=0x{:08x}: {:?}",
+                                    pc,
+                                    e
+                                ),
+                            }
+                        }
+                    }
+                    Err(e) => log::debug!("cannot compute the CFA at pc=0x{:08x}: {:?}", pc, e),
+                }
+            }
+        }
+
+        let cfa_changed = match undefined_registers(&debug_frame, bases, pc)
+            .and_then(|undefined| registers.update(uwt_row.cfa(), uwt_row.registers(), &undefined))
+        {
+            Ok(cfa_changed) => cfa_changed,
+            Err(e) => {
+                log::debug!("cannot unwind 0x{:08x}: {:?}", pc, e);
+                backtrace.incomplete = Some(format!("{:#}", e));
+                return Ok(());
+            }
+        };
+
+        // an undefined return address marks the outermost frame
+        if registers.is_undefined(LR) {
+            log::debug!("return address is undefined at pc=0x{:08x}", pc);
+            break;
+        }
+
+        let lr = registers.get(LR)?;
+        log::debug!("lr=0x{:08x} pc=0x{:08x}", lr, pc);
+        if lr == LR_END {
+            break;
+        }
+
+        // If the frame didn't move, and the program counter didn't change, bail out (otherwise we
+        // might print the same frame over and over).
+        // Since we strip the thumb bit from `pc`, ignore it in this comparison.
+        if !cfa_changed && lr & !THUMB_BIT == pc & !THUMB_BIT {
+            backtrace.corrupted = true;
+            return Ok(());
+        }
+
+        if lr > 0xffff_ffe0 {
+            let fpu = match lr {
+                0xFFFFFFF1 | 0xFFFFFFF9 | 0xFFFFFFFD => false,
+                0xFFFFFFE1 | 0xFFFFFFE9 | 0xFFFFFFED => true,
+                _ => bail!("LR contains invalid EXC_RETURN value 0x{:08X}", lr),
+            };
+
+            let entry = exception_entry(exception, vector_table, &symtab);
+            backtrace.frames.push(Frame::Exception(entry));
+
+            let sp = registers.get(SP)?;
+            let stacked = Stacked::read(registers.core, sp, fpu)?;
+            // the interrupted code may itself be an exception handler
+            exception = Some(Exception::from_xpsr(stacked.xpsr));
+
+            registers.insert(LR, stacked.lr);
+            // the exception entry saved the caller-saved registers of the interrupted code
+            for (reg, value) in CALLER_SAVED.iter().zip(&[
+                stacked.r0,
+                stacked.r1,
+                stacked.r2,
+                stacked.r3,
+                stacked.r12,
+            ]) {
+                registers.insert(CoreRegisterAddress(*reg), *value);
+            }
+            // adjust the stack pointer for stacked registers
+            registers.insert(SP, sp + stacked.size());
+            pc = stacked.pc;
+
+            if backtrace.exception_frame.is_none() {
+                backtrace.exception_frame = Some(stacked);
+            }
+        } else {
+            if lr & 1 == 0 {
+                bail!("bug? LR ({:#010x}) didn't have the Thumb bit set", lr)
+            }
+            pc = lr & !THUMB_BIT;
+            registers.clobber_caller_saved();
+        }
+    }
+
+    Ok(())
+}
+
+/// The registers that the unwind information of the frame at `pc` marks as undefined
+///
+/// `gimli` leaves `DW_CFA_undefined` rules out of its unwind table rows, where they can't be told
+/// apart from registers without a rule, so the call frame instructions are replayed here.
+fn undefined_registers(
+    debug_frame: &DebugFrame<EndianSlice<LittleEndian>>,
+    bases: &BaseAddresses,
+    pc: u32,
+) -> Result<HashSet<u16>, anyhow::Error> {
+    let fde = debug_frame.fde_for_address(bases, pc.into(), DebugFrame::cie_from_offset)?;
+    let cie = fde.cie();
+
+    let mut initial = HashSet::new();
+    let mut instructions = cie.instructions(debug_frame, bases);
+    while let Some(instruction) = instructions.next()? {
+        // `DW_CFA_restore` is not allowed in the initial instructions
+        apply_undefined(&mut initial, &HashSet::new(), &instruction);
+    }
+
+    let mut undefined = initial.clone();
+    let mut remembered = vec![];
+    let mut location = fde.initial_address();
+    let mut instructions = fde.instructions(debug_frame, bases);
+    while let Some(instruction) = instructions.next()? {
+        match instruction {
+            CallFrameInstruction::AdvanceLoc { delta } => {
+                location += u64::from(delta) * cie.code_alignment_factor();
+            }
+            CallFrameInstruction::SetLoc { address } => location = address,
+            CallFrameInstruction::RememberState => remembered.push(undefined.clone()),
+            CallFrameInstruction::RestoreState => {
+                undefined = remembered
+                    .pop()
+                    .ok_or_else(|| anyhow!("DW_CFA_restore_state without a remembered state"))?;
+            }
+            _ => apply_undefined(&mut undefined, &initial, &instruction),
+        }
+
+        // the instructions that follow describe the code after `pc`
+        if location > u64::from(pc) {
+            break;
+        }
+    }
+
+    Ok(undefined)
+}
+
+/// Updates the set of `undefined` registers according to a call frame `instruction`; `initial`
+/// holds the registers the CIE marks as undefined
+fn apply_undefined(
+    undefined: &mut HashSet<u16>,
+    initial: &HashSet<u16>,
+    instruction: &CallFrameInstruction<EndianSlice<LittleEndian>>,
+) {
+    match *instruction {
+        CallFrameInstruction::Undefined { register } => {
+            undefined.insert(register.0);
+        }
+
+        CallFrameInstruction::Restore { register } => {
+            if initial.contains(&register.0) {
+                undefined.insert(register.0);
+            } else {
+                undefined.remove(&register.0);
+            }
+        }
+
+        CallFrameInstruction::SameValue { register }
+        | CallFrameInstruction::Offset { register, .. }
+        | CallFrameInstruction::OffsetExtendedSf { register, .. }
+        | CallFrameInstruction::ValOffset { register, .. }
+        | CallFrameInstruction::ValOffsetSf { register, .. }
+        | CallFrameInstruction::Register {
+            dest_register: register,
+            ..
+        }
+        | CallFrameInstruction::Expression { register, .. }
+        | CallFrameInstruction::ValExpression { register, .. } => {
+            undefined.remove(&register.0);
+        }
+
+        _ => {}
+    }
+}
+
+/// Describes the entry into `exception`, naming the handler when it is an interrupt
+fn exception_entry(
+    exception: Option<Exception>,
+    vector_table: &VectorTable,
+    symtab: &SymbolMap,
+) -> ExceptionEntry {
+    // a handler never runs with an IPSR of 0; if it appears to, the xPSR can't be trusted
+    let exception = exception.filter(|exception| exception.0 != 0);
+    let handler = exception
+        .filter(|exception| exception.irq().is_some())
+        .and_then(|exception| vector_table.handler(exception))
+        .and_then(|address| symtab.get(u64::from(address | THUMB_BIT)))
+        .and_then(|symbol| symbol.name())
+        .map(str::to_owned);
+
+    ExceptionEntry { exception, handler }
+}
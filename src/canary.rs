@@ -0,0 +1,236 @@
+//! Stack canary used to detect stack overflows
+//!
+//! By default only the lowest part of the free stack space is painted. With `--measure-stack` all
+//! of it is painted, so the peak stack usage can be measured exactly.
+//!
+//! When the program has a heap, the canary is placed between the end of the heap and the stack
+//! instead of right after the static data.
+
+use core::{cmp, ops::Range};
+
+use crate::{elf::ProcessedElf, target::TargetAccess};
+
+const STACK_CANARY: u8 = 0xAA;
+
+/// What the stack canary revealed at the end of the run
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CanaryState {
+    /// The canary was not touched
+    Intact,
+    /// The program wrote into the canary; the stack may have overflowed into static data or
+    /// the heap
+    Touched {
+        /// Lower bound of the stack usage, in bytes
+        min_stack_usage: u32,
+        /// The stack reached the end of the heap, which sits right below the canary
+        heap_collision: bool,
+    },
+}
+
+/// Stack usage measured by painting all of the free stack space
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StackUsage {
+    /// Peak stack usage, in bytes
+    pub peak: u32,
+    /// Space between the end of static data and the initial stack pointer, in bytes
+    pub available: u32,
+}
+
+impl StackUsage {
+    /// Stack space that was never used, in bytes
+    pub fn headroom(&self) -> u32 {
+        self.available.saturating_sub(self.peak)
+    }
+
+    /// Peak stack usage, as a percentage of the available stack space
+    pub fn percent_used(&self) -> f64 {
+        if self.available == 0 {
+            100.0
+        } else {
+            f64::from(self.peak) * 100.0 / f64::from(self.available)
+        }
+    }
+}
+
+/// A region of RAM, between static data and the stack, filled with a known pattern
+#[derive(Clone, Copy)]
+pub(crate) struct Canary {
+    address: u32,
+    /// Size of the canary proper; touching it is reported as a potential stack overflow
+    size: u32,
+    /// Size of the painted region, which starts with the canary; larger than `size` when
+    /// measuring the stack usage
+    painted_size: u32,
+    initial_sp: u32,
+    /// Whether the canary starts right at the end of the heap
+    above_heap: bool,
+}
+
+impl Canary {
+    /// Decides if and where to place the stack canary and writes it to the target's RAM
+    ///
+    /// With `measure_stack` all of the free stack space is painted.
+    pub(crate) fn install(
+        core: &mut impl TargetAccess,
+        elf: &ProcessedElf,
+        heap: Option<&Range<u32>>,
+        measure_stack: bool,
+    ) -> Result<Option<Self>, anyhow::Error> {
+        let mut canary = if let Some(canary) = Self::locate(elf, heap) {
+            canary
+        } else {
+            if measure_stack {
+                log::warn!("the stack usage can't be measured for this program");
+            }
+            return Ok(None);
+        };
+
+        if measure_stack {
+            canary.painted_size = canary.initial_sp - canary.address;
+            log::info!(
+                "painting {} bytes of stack to measure its usage",
+                canary.painted_size
+            );
+        }
+
+        let data = vec![STACK_CANARY; canary.painted_size as usize];
+        core.write_8(canary.address, &data)?;
+
+        Ok(Some(canary))
+    }
+
+    /// Decides if and where to place the stack canary, without touching the target
+    ///
+    /// This is where [`Canary::install`] placed the canary when running the same firmware on the
+    /// same chip.
+    ///
+    /// `heap` is the heap region, if known; see [`ProcessedElf::heap_region`].
+    pub(crate) fn locate(elf: &ProcessedElf, heap: Option<&Range<u32>>) -> Option<Self> {
+        let ram = elf.stack_ram_region.as_ref()?;
+
+        let initial_sp = elf.vector_table.initial_sp;
+        let highest_ram_addr_in_use = elf.highest_ram_addr_in_use;
+
+        // When the stack has a RAM region of its own, like CCM or DTCM, it can grow down to the
+        // start of the region
+        let static_end = if highest_ram_addr_in_use == 0 {
+            ram.range.start
+        } else {
+            highest_ram_addr_in_use + 1
+        };
+
+        // Canary starts right after `highest_ram_addr_in_use` or, if the heap extends past
+        // static data, right after the end of the heap.
+        let (address, above_heap) = match heap {
+            Some(heap) if ram.range.contains(&heap.start) && heap.end >= static_end => {
+                (heap.end, true)
+            }
+            Some(_) => (static_end, false),
+            None if elf.uses_heap => {
+                log::warn!(
+                    "the program uses a heap but its location is unknown, so the stack canary is \
+                    disabled; use `--heap-symbols <START>,<END>` to name the symbols that delimit it"
+                );
+                return None;
+            }
+            None => (static_end, false),
+        };
+
+        // Initial SP must be past canary location.
+        if address >= initial_sp {
+            return None;
+        }
+
+        let stack_available = initial_sp - address;
+
+        // We consider >90% stack usage a potential stack overflow, but don't go beyond 1 kb
+        // since filling a lot of RAM is slow (and 1 kb should be "good enough" for what
+        // we're doing).
+        let size = cmp::min(stack_available / 10, 1024);
+
+        log::debug!(
+            "{} bytes of stack available (0x{:08X}-0x{:08X}), using {} byte canary to detect overflows",
+            stack_available,
+            address,
+            initial_sp,
+            size,
+        );
+
+        Some(Self {
+            address,
+            size,
+            painted_size: size,
+            initial_sp,
+            above_heap,
+        })
+    }
+
+    /// The canary placed at `region` by an earlier run, e.g. as recorded in a core dump
+    ///
+    /// `heap` is the heap region, if known; see [`ProcessedElf::heap_region`].
+    pub(crate) fn at(
+        region: Range<u32>,
+        elf: &ProcessedElf,
+        heap: Option<&Range<u32>>,
+    ) -> Option<Self> {
+        let initial_sp = elf.vector_table.initial_sp;
+        if region.start >= region.end || region.end > initial_sp {
+            return None;
+        }
+
+        let size = region.end - region.start;
+        Some(Self {
+            address: region.start,
+            size,
+            painted_size: size,
+            initial_sp,
+            above_heap: heap.map(|heap| heap.end) == Some(region.start),
+        })
+    }
+
+    /// Address range of the canary proper
+    pub(crate) fn region(&self) -> Range<u32> {
+        self.address..self.address + self.size
+    }
+
+    /// Reads back the canary and reports whether it was touched and, if all of the free stack
+    /// space was painted, the stack usage
+    pub(crate) fn check(
+        &self,
+        core: &mut impl TargetAccess,
+    ) -> Result<(CanaryState, Option<StackUsage>), anyhow::Error> {
+        let mut buf = vec![0; self.painted_size as usize];
+        core.read_8(self.address, &mut buf)?;
+
+        // the stack grows downwards so the lowest touched byte marks the peak usage
+        let touched_addr = buf
+            .iter()
+            .position(|b| *b != STACK_CANARY)
+            .map(|pos| self.address + pos as u32);
+
+        let state = match touched_addr {
+            Some(addr) if addr < self.address + self.size => {
+                log::debug!("canary was touched at 0x{:08X}", addr);
+
+                CanaryState::Touched {
+                    min_stack_usage: self.initial_sp - addr,
+                    // a stack that wrote to the lowest byte of the canary has reached, and most
+                    // likely overwritten, the end of the heap
+                    heap_collision: self.above_heap && addr == self.address,
+                }
+            }
+            _ => CanaryState::Intact,
+        };
+
+        let usage = if self.painted_size > self.size {
+            Some(StackUsage {
+                peak: self.initial_sp - touched_addr.unwrap_or(self.initial_sp),
+                available: self.initial_sp - self.address,
+            })
+        } else {
+            None
+        };
+
+        Ok((state, usage))
+    }
+}
@@ -0,0 +1,218 @@
+//! Recordings of the raw RTT stream
+//!
+//! A capture file starts with a header identifying the firmware that produced it, followed by
+//! the chunks of bytes read from the RTT up channels, each one stamped with the host time at
+//! which it was read and the name of its channel. All integers are little endian.
+//!
+//! ```text
+//! header: magic (8 bytes) | version: u32 | ELF path: string | ELF size: u64 | ELF hash: u64
+//! chunk:  timestamp (microseconds since the UNIX epoch): u64 | channel name: string
+//!         | length: u32 | bytes
+//! string: length: u32 | UTF-8 bytes
+//! ```
+
+use std::{
+    fmt, fs,
+    fs::File,
+    io::{BufWriter, Write as _},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Context as _};
+
+const MAGIC: &[u8; 8] = b"PRRTTCAP";
+const VERSION: u32 = 1;
+
+/// Identifies a firmware ELF file
+#[derive(Clone, Debug, PartialEq)]
+pub struct ElfIdentity {
+    pub path: PathBuf,
+    pub size: u64,
+    /// 64-bit FNV-1a hash of the file contents
+    pub hash: u64,
+}
+
+impl ElfIdentity {
+    /// Identity of the ELF file at `path`, whose contents are `bytes`
+    pub fn new(path: &Path, bytes: &[u8]) -> Self {
+        const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+        let hash = bytes.iter().fold(FNV_OFFSET_BASIS, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME)
+        });
+
+        Self {
+            path: path.canonicalize().unwrap_or_else(|_| path.to_owned()),
+            size: bytes.len() as u64,
+            hash,
+        }
+    }
+
+    /// Whether both identities refer to the same file contents, regardless of their paths
+    pub fn same_contents(&self, other: &Self) -> bool {
+        self.size == other.size && self.hash == other.hash
+    }
+}
+
+impl fmt::Display for ElfIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({} bytes, hash {:016x})",
+            self.path.display(),
+            self.size,
+            self.hash
+        )
+    }
+}
+
+/// Bytes read from an RTT channel in one go
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chunk {
+    /// Host time at which the bytes were read
+    pub timestamp: SystemTime,
+    /// Name of the channel or, if it has none, its number
+    pub channel: String,
+    pub data: Vec<u8>,
+}
+
+/// The contents of a capture file
+#[derive(Clone, Debug, PartialEq)]
+pub struct Capture {
+    /// The firmware that was running on the device
+    pub elf: ElfIdentity,
+    pub chunks: Vec<Chunk>,
+}
+
+impl Capture {
+    /// Reads the capture file at `path`
+    pub fn read(path: &Path) -> Result<Self, anyhow::Error> {
+        let bytes = fs::read(path)?;
+        Self::parse(&bytes).with_context(|| format!("failed to parse {}", path.display()))
+    }
+
+    /// Parses the contents of a capture file
+    ///
+    /// A truncated last chunk, as left behind when `probe-run` is killed, is ignored.
+    pub fn parse(bytes: &[u8]) -> Result<Self, anyhow::Error> {
+        let mut reader = Reader { bytes };
+
+        if reader.take(MAGIC.len())? != MAGIC {
+            bail!("not an RTT capture file");
+        }
+        let version = reader.u32()?;
+        if version != VERSION {
+            bail!("unsupported capture file version {}", version);
+        }
+
+        let elf = ElfIdentity {
+            path: PathBuf::from(reader.string()?),
+            size: reader.u64()?,
+            hash: reader.u64()?,
+        };
+
+        let mut chunks = vec![];
+        while !reader.bytes.is_empty() {
+            let chunk = (|| {
+                let micros = reader.u64()?;
+                let channel = reader.string()?;
+                let len = reader.u32()? as usize;
+                Ok::<_, anyhow::Error>(Chunk {
+                    timestamp: UNIX_EPOCH + Duration::from_micros(micros),
+                    channel,
+                    data: reader.take(len)?.to_vec(),
+                })
+            })();
+
+            match chunk {
+                Ok(chunk) => chunks.push(chunk),
+                Err(_) => {
+                    log::warn!("the capture file ends with an incomplete chunk; ignoring it");
+                    break;
+                }
+            }
+        }
+
+        Ok(Self { elf, chunks })
+    }
+}
+
+/// Writes a capture file as the RTT data arrives
+pub struct CaptureWriter {
+    file: BufWriter<File>,
+}
+
+impl CaptureWriter {
+    /// Creates the capture file at `path` and writes its header
+    pub fn create(path: &Path, elf: &ElfIdentity) -> Result<Self, anyhow::Error> {
+        let file =
+            File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+        let mut writer = Self {
+            file: BufWriter::new(file),
+        };
+
+        writer.file.write_all(MAGIC)?;
+        writer.file.write_all(&VERSION.to_le_bytes())?;
+        writer.write_string(&elf.path.display().to_string())?;
+        writer.file.write_all(&elf.size.to_le_bytes())?;
+        writer.file.write_all(&elf.hash.to_le_bytes())?;
+        writer.file.flush()?;
+
+        Ok(writer)
+    }
+
+    /// Appends `data` read from `channel`, stamped with the current time
+    pub fn write_chunk(&mut self, channel: &str, data: &[u8]) -> Result<(), anyhow::Error> {
+        let micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| anyhow!("system time is before the UNIX epoch"))?
+            .as_micros() as u64;
+
+        self.file.write_all(&micros.to_le_bytes())?;
+        self.write_string(channel)?;
+        self.file.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.file.write_all(data)?;
+        // keep the file usable if `probe-run` doesn't exit cleanly
+        self.file.flush()?;
+        Ok(())
+    }
+
+    fn write_string(&mut self, s: &str) -> Result<(), anyhow::Error> {
+        self.file.write_all(&(s.len() as u32).to_le_bytes())?;
+        self.file.write_all(s.as_bytes())?;
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], anyhow::Error> {
+        if self.bytes.len() < len {
+            bail!("unexpected end of file");
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32, anyhow::Error> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, anyhow::Error> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn string(&mut self) -> Result<String, anyhow::Error> {
+        let len = self.u32()? as usize;
+        Ok(String::from_utf8(self.take(len)?.to_vec())?)
+    }
+}
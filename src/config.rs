@@ -0,0 +1,637 @@
+//! Runner options read from configuration files
+//!
+//! Options are looked up, from highest to lowest precedence, in:
+//!
+//! - the command line
+//! - the `PROBE_RUN_CHIP` and `PROBE_RUN_PROBE` environment variables
+//! - the first `probe-run.toml` file found in the directory of the ELF file or any of its
+//!   ancestors (or, if there's none, in the current directory or any of its ancestors)
+//! - the `[package.metadata.probe-run]` table of the firmware's `Cargo.toml`
+
+use std::{
+    convert::TryFrom,
+    env, fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use serde::Deserialize;
+
+use crate::{RunConfig, DEFAULT_STACK_USAGE_THRESHOLD};
+
+/// Name of the stand-alone configuration file
+pub const CONFIG_FILE_NAME: &str = "probe-run.toml";
+
+/// A set of options; any of them may be missing
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Options {
+    pub chip: Option<String>,
+    pub probe: Option<String>,
+    pub speed: Option<u32>,
+    pub connect_under_reset: Option<bool>,
+    pub no_flash: Option<bool>,
+    pub backtrace: Option<BacktracePolicy>,
+    pub format: Option<OutputFormat>,
+    pub timeout: Option<Timeout>,
+    pub measure_stack: Option<bool>,
+    pub stack_usage_threshold: Option<u8>,
+    pub catch_faults: Option<bool>,
+    pub on_reset: Option<ResetPolicy>,
+    pub heap_symbols: Option<HeapSymbols>,
+}
+
+/// When to print the stack backtrace at the end of a run
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BacktracePolicy {
+    /// Only when the program did not exit successfully, e.g. on a HardFault, or was interrupted
+    Auto,
+    Always,
+    Never,
+}
+
+impl Default for BacktracePolicy {
+    fn default() -> Self {
+        BacktracePolicy::Always
+    }
+}
+
+impl FromStr for BacktracePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(BacktracePolicy::Auto),
+            "always" => Ok(BacktracePolicy::Always),
+            "never" => Ok(BacktracePolicy::Never),
+            _ => bail!(
+                "unknown backtrace policy `{}`; expected one of `auto`, `always` or `never`",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for BacktracePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BacktracePolicy::Auto => "auto",
+            BacktracePolicy::Always => "always",
+            BacktracePolicy::Never => "never",
+        })
+    }
+}
+
+/// What to do when the device resets in the middle of a run, e.g. because of a watchdog
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResetPolicy {
+    /// End the run with an error
+    Fail,
+    /// Attach to the RTT channels the firmware sets up again and keep running
+    Reattach,
+    /// End the run like when the device halts
+    Stop,
+}
+
+impl Default for ResetPolicy {
+    fn default() -> Self {
+        ResetPolicy::Fail
+    }
+}
+
+impl FromStr for ResetPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(ResetPolicy::Fail),
+            "reattach" => Ok(ResetPolicy::Reattach),
+            "stop" => Ok(ResetPolicy::Stop),
+            _ => bail!(
+                "unknown reset policy `{}`; expected one of `fail`, `reattach` or `stop`",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for ResetPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ResetPolicy::Fail => "fail",
+            ResetPolicy::Reattach => "reattach",
+            ResetPolicy::Stop => "stop",
+        })
+    }
+}
+
+/// How `probe-run` reports what happened during a run
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Text meant to be read by humans
+    Human,
+    /// Newline-delimited JSON events; see [`Event`](crate::event::Event)
+    Json,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat::Human
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(OutputFormat::Human),
+            "json" => Ok(OutputFormat::Json),
+            _ => bail!(
+                "unknown output format `{}`; expected one of `human` or `json`",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OutputFormat::Human => "human",
+            OutputFormat::Json => "json",
+        })
+    }
+}
+
+/// How the host's stdin is forwarded to the device
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StdinMode {
+    /// Whole lines, once the newline has been read
+    Line,
+    /// Bytes as soon as they are read
+    Raw,
+    /// stdin is not read
+    Off,
+}
+
+impl Default for StdinMode {
+    fn default() -> Self {
+        StdinMode::Off
+    }
+}
+
+impl FromStr for StdinMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "line" => Ok(StdinMode::Line),
+            "raw" => Ok(StdinMode::Raw),
+            "off" => Ok(StdinMode::Off),
+            _ => bail!(
+                "unknown stdin mode `{}`; expected one of `line`, `raw` or `off`",
+                s
+            ),
+        }
+    }
+}
+
+/// How long a run may take; parsed from e.g. `90`, `90s`, `1500ms`, `5m` or `1h`
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Timeout(pub Duration);
+
+impl FromStr for Timeout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let unit_start = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, unit) = s.split_at(unit_start);
+        let number = number
+            .parse::<u64>()
+            .map_err(|_| anyhow!("invalid timeout `{}`; expected e.g. `90s` or `5m`", s))?;
+
+        let secs = |factor: u64| {
+            number
+                .checked_mul(factor)
+                .map(Duration::from_secs)
+                .ok_or_else(|| anyhow!("timeout `{}` is too long", s))
+        };
+
+        Ok(Timeout(match unit {
+            "ms" => Duration::from_millis(number),
+            "" | "s" => Duration::from_secs(number),
+            "m" => secs(60)?,
+            "h" => secs(60 * 60)?,
+            _ => bail!(
+                "unknown unit `{}` in timeout `{}`; expected one of `ms`, `s`, `m` or `h`",
+                unit,
+                s
+            ),
+        }))
+    }
+}
+
+impl TryFrom<String> for Timeout {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.subsec_millis() == 0 {
+            write!(f, "{}s", self.0.as_secs())
+        } else {
+            write!(f, "{}ms", self.0.as_millis())
+        }
+    }
+}
+
+/// Names of the symbols at the start and end of the heap; parsed from `<start>,<end>`
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct HeapSymbols {
+    pub start: String,
+    pub end: String,
+}
+
+impl FromStr for HeapSymbols {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ',');
+        match (parts.next(), parts.next()) {
+            (Some(start), Some(end)) if !start.is_empty() && !end.is_empty() => Ok(Self {
+                start: start.to_string(),
+                end: end.to_string(),
+            }),
+            _ => bail!("invalid heap symbols `{}`; expected `<start>,<end>`", s),
+        }
+    }
+}
+
+impl TryFrom<String> for HeapSymbols {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for HeapSymbols {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{}", self.start, self.end)
+    }
+}
+
+/// Where the output of an RTT up channel goes; parsed from `<channel>=<file>`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelRoute {
+    /// Name of the channel or, for unnamed channels, its number
+    pub channel: String,
+    /// `None` routes the channel to stdout; written as `-`
+    pub file: Option<PathBuf>,
+}
+
+impl FromStr for ChannelRoute {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(channel), Some(file)) if !channel.is_empty() && !file.is_empty() => Ok(Self {
+                channel: channel.to_string(),
+                file: if file == "-" {
+                    None
+                } else {
+                    Some(PathBuf::from(file))
+                },
+            }),
+            _ => bail!(
+                "invalid RTT channel route `{}`; expected `<channel>=<file>` or `<channel>=-`",
+                s
+            ),
+        }
+    }
+}
+
+/// Where the value of an option came from
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+    Default,
+    CommandLine,
+    Env,
+    ConfigFile(PathBuf),
+    CargoManifest(PathBuf),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => f.write_str("default"),
+            Source::CommandLine => f.write_str("command line"),
+            Source::Env => f.write_str("environment variable"),
+            Source::ConfigFile(path) => write!(f, "{}", path.display()),
+            Source::CargoManifest(path) => {
+                write!(f, "{} [package.metadata.probe-run]", path.display())
+            }
+        }
+    }
+}
+
+/// Options that all come from the same `source`
+#[derive(Debug)]
+pub struct Layer {
+    pub source: Source,
+    pub options: Options,
+}
+
+/// Finds the configuration files that apply to the firmware at `elf_path`
+///
+/// Layers are returned from highest to lowest precedence.
+pub fn discover(elf_path: &Path) -> Result<Vec<Layer>, anyhow::Error> {
+    let mut layers = vec![];
+    let current_dir = env::current_dir()?;
+
+    let elf_dir = elf_path
+        .canonicalize()
+        .ok()
+        .and_then(|path| path.parent().map(Path::to_owned));
+    let config_file = elf_dir
+        .as_deref()
+        .and_then(|dir| find_upwards(dir, CONFIG_FILE_NAME))
+        .or_else(|| find_upwards(&current_dir, CONFIG_FILE_NAME));
+    if let Some(path) = config_file {
+        log::debug!("using configuration file {}", path.display());
+        let contents = fs::read_to_string(&path)?;
+        let options = toml::from_str(&contents)
+            .with_context(|| format!("failed to parse {}", path.display()))?;
+        layers.push(Layer {
+            source: Source::ConfigFile(path),
+            options,
+        });
+    }
+
+    // `cargo run` sets this variable to the directory of the package that's being run
+    let manifest = env::var_os("CARGO_MANIFEST_DIR")
+        .map(|dir| PathBuf::from(dir).join("Cargo.toml"))
+        .filter(|path| path.is_file())
+        .or_else(|| find_upwards(&current_dir, "Cargo.toml"));
+    if let Some(path) = manifest {
+        if let Some(options) = read_cargo_metadata(&path)? {
+            log::debug!("using [package.metadata.probe-run] of {}", path.display());
+            layers.push(Layer {
+                source: Source::CargoManifest(path),
+                options,
+            });
+        }
+    }
+
+    Ok(layers)
+}
+
+fn find_upwards(dir: &Path, file_name: &str) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(file_name))
+        .find(|path| path.is_file())
+}
+
+fn read_cargo_metadata(path: &Path) -> Result<Option<Options>, anyhow::Error> {
+    let contents = fs::read_to_string(path)?;
+    let manifest = contents
+        .parse::<toml::Value>()
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+
+    manifest
+        .get("package")
+        .and_then(|package| package.get("metadata"))
+        .and_then(|metadata| metadata.get("probe-run"))
+        .map(|table| {
+            table.clone().try_into().with_context(|| {
+                format!(
+                    "failed to parse [package.metadata.probe-run] of {}",
+                    path.display()
+                )
+            })
+        })
+        .transpose()
+}
+
+/// The value of an option and where it came from
+#[derive(Debug)]
+pub struct Setting<T> {
+    pub value: T,
+    pub source: Source,
+}
+
+/// Options after merging all layers
+#[derive(Debug)]
+pub struct Settings {
+    pub chip: Setting<Option<String>>,
+    pub probe: Setting<Option<String>>,
+    pub speed: Setting<Option<u32>>,
+    pub connect_under_reset: Setting<bool>,
+    pub no_flash: Setting<bool>,
+    pub backtrace: Setting<BacktracePolicy>,
+    pub format: Setting<OutputFormat>,
+    pub timeout: Setting<Option<Timeout>>,
+    pub measure_stack: Setting<bool>,
+    pub stack_usage_threshold: Setting<u8>,
+    pub catch_faults: Setting<bool>,
+    pub on_reset: Setting<ResetPolicy>,
+    pub heap_symbols: Setting<Option<HeapSymbols>>,
+}
+
+impl Settings {
+    /// Merges `layers`, which must be sorted from highest to lowest precedence
+    pub fn resolve(layers: &[Layer]) -> Self {
+        fn pick<T: Clone>(
+            layers: &[Layer],
+            get: impl Fn(&Options) -> &Option<T>,
+        ) -> Setting<Option<T>> {
+            layers
+                .iter()
+                .find_map(|layer| {
+                    get(&layer.options).clone().map(|value| Setting {
+                        value: Some(value),
+                        source: layer.source.clone(),
+                    })
+                })
+                .unwrap_or(Setting {
+                    value: None,
+                    source: Source::Default,
+                })
+        }
+
+        fn or_default<T: Default>(setting: Setting<Option<T>>) -> Setting<T> {
+            Setting {
+                value: setting.value.unwrap_or_default(),
+                source: setting.source,
+            }
+        }
+
+        Self {
+            chip: pick(layers, |options| &options.chip),
+            probe: pick(layers, |options| &options.probe),
+            speed: pick(layers, |options| &options.speed),
+            connect_under_reset: or_default(pick(layers, |options| &options.connect_under_reset)),
+            no_flash: or_default(pick(layers, |options| &options.no_flash)),
+            backtrace: or_default(pick(layers, |options| &options.backtrace)),
+            format: or_default(pick(layers, |options| &options.format)),
+            timeout: pick(layers, |options| &options.timeout),
+            heap_symbols: pick(layers, |options| &options.heap_symbols),
+            measure_stack: or_default(pick(layers, |options| &options.measure_stack)),
+            stack_usage_threshold: {
+                let setting = pick(layers, |options| &options.stack_usage_threshold);
+                Setting {
+                    value: setting.value.unwrap_or(DEFAULT_STACK_USAGE_THRESHOLD),
+                    source: setting.source,
+                }
+            },
+            catch_faults: or_default(pick(layers, |options| &options.catch_faults)),
+            on_reset: or_default(pick(layers, |options| &options.on_reset)),
+        }
+    }
+
+    /// Prints every option, its value and where the value came from
+    pub fn print(&self) {
+        print!("{}", self);
+    }
+
+    /// The name, value and source of every option
+    fn rows(&self) -> Vec<(&'static str, String, &Source)> {
+        fn or_unset<T: ToString>(value: &Option<T>) -> String {
+            value
+                .as_ref()
+                .map(ToString::to_string)
+                .unwrap_or_else(|| "<unset>".to_string())
+        }
+
+        vec![
+            ("chip", or_unset(&self.chip.value), &self.chip.source),
+            ("probe", or_unset(&self.probe.value), &self.probe.source),
+            ("speed", or_unset(&self.speed.value), &self.speed.source),
+            (
+                "connect-under-reset",
+                self.connect_under_reset.value.to_string(),
+                &self.connect_under_reset.source,
+            ),
+            (
+                "no-flash",
+                self.no_flash.value.to_string(),
+                &self.no_flash.source,
+            ),
+            (
+                "backtrace",
+                self.backtrace.value.to_string(),
+                &self.backtrace.source,
+            ),
+            ("format", self.format.value.to_string(), &self.format.source),
+            (
+                "timeout",
+                or_unset(&self.timeout.value),
+                &self.timeout.source,
+            ),
+            (
+                "measure-stack",
+                self.measure_stack.value.to_string(),
+                &self.measure_stack.source,
+            ),
+            (
+                "stack-usage-threshold",
+                self.stack_usage_threshold.value.to_string(),
+                &self.stack_usage_threshold.source,
+            ),
+            (
+                "heap-symbols",
+                or_unset(&self.heap_symbols.value),
+                &self.heap_symbols.source,
+            ),
+            (
+                "catch-faults",
+                self.catch_faults.value.to_string(),
+                &self.catch_faults.source,
+            ),
+            (
+                "on-reset",
+                self.on_reset.value.to_string(),
+                &self.on_reset.source,
+            ),
+        ]
+    }
+
+    /// Builds the configuration of a [`Runner`](crate::Runner) for the firmware at `elf`
+    pub fn run_config(&self, elf: PathBuf) -> Result<RunConfig, anyhow::Error> {
+        let chip = self.chip.value.clone().ok_or_else(|| {
+            anyhow!(
+                "no chip was specified; use the `--chip` flag, the `PROBE_RUN_CHIP` environment \
+                variable or the `chip` key of a `{}` file",
+                CONFIG_FILE_NAME
+            )
+        })?;
+
+        Ok(RunConfig {
+            elf,
+            chip,
+            probe: self.probe.value.clone(),
+            speed: self.speed.value,
+            no_flash: self.no_flash.value,
+            connect_under_reset: self.connect_under_reset.value,
+            coredump: None,
+            backtrace_locals: false,
+            show_registers: false,
+            rtt_capture: None,
+            rtt_channels: vec![],
+            stdin: StdinMode::default(),
+            rtt_down_channel: None,
+            semihosting_root: None,
+            timeout: self.timeout.value.map(|timeout| timeout.0),
+            measure_stack: self.measure_stack.value,
+            stack_usage_threshold: self.stack_usage_threshold.value,
+            heap_symbols: self.heap_symbols.value.clone(),
+            catch_faults: self.catch_faults.value,
+            on_reset: self.on_reset.value,
+            format: self.format.value,
+            abort: Arc::new(AtomicBool::new(false)),
+        })
+    }
+}
+
+/// One line per option, e.g. `chip                  = nRF52840_xxAA            (probe-run.toml)`
+impl fmt::Display for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rows = self.rows();
+        let name_width = rows
+            .iter()
+            .map(|(name, _, _)| name.len())
+            .max()
+            .unwrap_or(0);
+        let value_width = rows
+            .iter()
+            .map(|(_, value, _)| value.len())
+            .max()
+            .unwrap_or(0);
+        for (name, value, source) in rows {
+            writeln!(
+                f,
+                "{:<name_width$} = {:<value_width$} ({})",
+                name,
+                value,
+                source,
+                name_width = name_width,
+                value_width = value_width
+            )?;
+        }
+        Ok(())
+    }
+}
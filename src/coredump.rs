@@ -0,0 +1,466 @@
+//! ELF core dumps of the device state
+//!
+//! A core dump contains every RAM region of the device as a `PT_LOAD` segment plus a number of
+//! notes:
+//!
+//! - `NT_PRSTATUS`: general purpose registers in the Linux ARM format understood by GDB. When
+//!   the device was servicing an exception, a second `NT_PRSTATUS` note (a second "thread" in
+//!   GDB) describes the code that was interrupted, using the registers stacked on exception entry
+//! - `NT_ARM_VFP`: FPU registers, if the FPU is enabled
+//! - `probe-run` notes: path to the firmware ELF, name of the chip, the raw value of every
+//!   register that was read, indexed by its `CoreRegisterAddress`, and the location of the stack
+//!   canary
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use probe_rs::CoreRegisterAddress;
+
+use crate::{
+    stacked::Stacked,
+    target::{FakeTarget, TargetAccess},
+    LR, SP, XPSR,
+};
+
+const ET_CORE: u16 = 4;
+const EM_ARM: u16 = 40;
+const EF_ARM_EABI_VER5: u32 = 0x0500_0000;
+
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const ELF_HEADER_SIZE: u32 = 52;
+const PROGRAM_HEADER_SIZE: u32 = 32;
+
+const NT_PRSTATUS: u32 = 1;
+const NT_ARM_VFP: u32 = 0x400;
+
+const PROBE_RUN_NOTE: &str = "probe-run";
+const NT_FIRMWARE: u32 = 1;
+const NT_CHIP: u32 = 2;
+const NT_REGISTERS: u32 = 3;
+const NT_CANARY: u32 = 4;
+
+/// Size of the Linux ARM `elf_prstatus` struct
+const PRSTATUS_SIZE: usize = 148;
+/// Offset of `pr_cursig` in `elf_prstatus`
+const PRSTATUS_CURSIG: usize = 12;
+/// Offset of `pr_pid` in `elf_prstatus`
+const PRSTATUS_PID: usize = 24;
+/// Offset of `pr_reg` in `elf_prstatus`
+const PRSTATUS_REG: usize = 72;
+
+/// POSIX signal number of `SIGABRT`, reported to GDB as `pr_cursig` in `NT_PRSTATUS`
+///
+/// Not to be confused with [`crate::SIGABRT`], the exit code of a run that ended in a HardFault.
+const SIGNAL_ABRT: u16 = 6;
+
+/// `CoreRegisterAddress` of FPSCR
+const FPSCR: u16 = 33;
+/// `CoreRegisterAddress` of S0; S1-S31 follow
+const S0: u16 = 64;
+const NUM_FPU_REGS: u16 = 32;
+
+/// Registers that are always present: R0-R12, SP, LR, PC, xPSR, MSP, PSP and
+/// CONTROL/FAULTMASK/BASEPRI/PRIMASK
+const CORE_REGISTERS: &[u16] = &[
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, XPSR.0, 17, 18, 20,
+];
+
+/// Coprocessor Access Control Register
+const CPACR: u32 = 0xE000_ED88;
+
+/// A snapshot of the state of a halted device
+#[derive(Clone, Debug, Default)]
+pub struct CoreDump {
+    /// Register values indexed by their `CoreRegisterAddress`
+    pub registers: BTreeMap<u16, u32>,
+    /// Memory regions as `(start address, contents)` pairs
+    pub memory: Vec<(u32, Vec<u8>)>,
+    /// Path to the firmware that was running on the device
+    pub firmware: Option<PathBuf>,
+    pub chip: Option<String>,
+    /// Address range of the stack canary painted for the run; `None` when there was none
+    pub canary: Option<Range<u32>>,
+}
+
+impl CoreDump {
+    /// Reads the registers and the `ram_regions` of the halted `core`
+    pub fn capture(
+        core: &mut impl TargetAccess,
+        ram_regions: &[Range<u32>],
+    ) -> Result<Self, anyhow::Error> {
+        let mut registers = BTreeMap::new();
+        for &reg in CORE_REGISTERS {
+            registers.insert(reg, core.read_core_reg(CoreRegisterAddress(reg))?);
+        }
+
+        // CP10 and CP11 need to be enabled to access the FPU registers
+        let fpu_enabled = core.read_word_32(CPACR)? & (0b1111 << 20) != 0;
+        if fpu_enabled {
+            for reg in std::iter::once(FPSCR).chain(S0..S0 + NUM_FPU_REGS) {
+                registers.insert(reg, core.read_core_reg(CoreRegisterAddress(reg))?);
+            }
+        }
+
+        let mut memory = vec![];
+        for range in ram_regions {
+            let mut data = vec![0; (range.end - range.start) as usize];
+            match core.read_8(range.start, &mut data) {
+                Ok(()) => memory.push((range.start, data)),
+                Err(e) => log::warn!(
+                    "RAM region 0x{:08X}-0x{:08X} could not be read and will be missing from the core dump: {}",
+                    range.start,
+                    range.end - 1,
+                    e
+                ),
+            }
+        }
+
+        Ok(Self {
+            registers,
+            memory,
+            firmware: None,
+            chip: None,
+            canary: None,
+        })
+    }
+
+    /// Encodes the dump as an ELF core file
+    pub fn to_elf(&self) -> Vec<u8> {
+        let mut notes = vec![];
+
+        let prstatus = self.prstatus(1, |reg| self.registers.get(&reg).copied());
+        push_note(&mut notes, "CORE", NT_PRSTATUS, &prstatus);
+
+        if let Some(stacked) = self.exception_frame() {
+            // the code interrupted by the exception
+            let sp = self.registers[&SP.0] + stacked.size();
+            let interrupted = |reg| match reg {
+                0 => Some(stacked.r0),
+                1 => Some(stacked.r1),
+                2 => Some(stacked.r2),
+                3 => Some(stacked.r3),
+                12 => Some(stacked.r12),
+                13 => Some(sp),
+                14 => Some(stacked.lr),
+                15 => Some(stacked.pc),
+                reg if reg == XPSR.0 => Some(stacked.xpsr),
+                _ => self.registers.get(&reg).copied(),
+            };
+            let prstatus = self.prstatus(2, interrupted);
+            push_note(&mut notes, "CORE", NT_PRSTATUS, &prstatus);
+        }
+
+        if self.registers.contains_key(&FPSCR) {
+            // 32 double precision registers followed by FPSCR; Cortex-M only has D0-D15
+            let mut vfp = vec![];
+            for reg in S0..S0 + NUM_FPU_REGS {
+                vfp.extend_from_slice(&self.registers[&reg].to_le_bytes());
+            }
+            // D16-D31
+            vfp.extend_from_slice(&[0; 16 * 8]);
+            vfp.extend_from_slice(&self.registers[&FPSCR].to_le_bytes());
+            push_note(&mut notes, "LINUX", NT_ARM_VFP, &vfp);
+        }
+
+        if let Some(firmware) = &self.firmware {
+            let mut path = firmware.display().to_string().into_bytes();
+            path.push(0);
+            push_note(&mut notes, PROBE_RUN_NOTE, NT_FIRMWARE, &path);
+        }
+
+        if let Some(chip) = &self.chip {
+            let mut chip = chip.clone().into_bytes();
+            chip.push(0);
+            push_note(&mut notes, PROBE_RUN_NOTE, NT_CHIP, &chip);
+        }
+
+        if let Some(canary) = &self.canary {
+            let mut location = canary.start.to_le_bytes().to_vec();
+            location.extend_from_slice(&(canary.end - canary.start).to_le_bytes());
+            push_note(&mut notes, PROBE_RUN_NOTE, NT_CANARY, &location);
+        }
+
+        let mut registers = vec![];
+        for (reg, value) in &self.registers {
+            registers.extend_from_slice(&u32::from(*reg).to_le_bytes());
+            registers.extend_from_slice(&value.to_le_bytes());
+        }
+        push_note(&mut notes, PROBE_RUN_NOTE, NT_REGISTERS, &registers);
+
+        let num_program_headers = 1 + self.memory.len() as u32;
+        let notes_offset = ELF_HEADER_SIZE + num_program_headers * PROGRAM_HEADER_SIZE;
+
+        let mut elf = vec![];
+        // e_ident: 32-bit, little endian, version 1, System V ABI
+        elf.extend_from_slice(&[0x7f, b'E', b'L', b'F', 1, 1, 1, 0]);
+        elf.extend_from_slice(&[0; 8]);
+        push_u16(&mut elf, ET_CORE);
+        push_u16(&mut elf, EM_ARM);
+        push_u32(&mut elf, 1); // e_version
+        push_u32(&mut elf, 0); // e_entry
+        push_u32(&mut elf, ELF_HEADER_SIZE); // e_phoff
+        push_u32(&mut elf, 0); // e_shoff
+        push_u32(&mut elf, EF_ARM_EABI_VER5);
+        push_u16(&mut elf, ELF_HEADER_SIZE as u16);
+        push_u16(&mut elf, PROGRAM_HEADER_SIZE as u16);
+        push_u16(&mut elf, num_program_headers as u16);
+        push_u16(&mut elf, 40); // e_shentsize
+        push_u16(&mut elf, 0); // e_shnum
+        push_u16(&mut elf, 0); // e_shstrndx
+
+        push_program_header(&mut elf, PT_NOTE, notes_offset, 0, notes.len() as u32, PF_R);
+        let mut offset = notes_offset + notes.len() as u32;
+        for (start, data) in &self.memory {
+            push_program_header(
+                &mut elf,
+                PT_LOAD,
+                offset,
+                *start,
+                data.len() as u32,
+                PF_R | PF_W,
+            );
+            offset += data.len() as u32;
+        }
+
+        elf.extend_from_slice(&notes);
+        for (_, data) in &self.memory {
+            elf.extend_from_slice(data);
+        }
+
+        elf
+    }
+
+    /// Writes the dump to `path` as an ELF core file
+    pub fn write(&self, path: &Path) -> Result<(), anyhow::Error> {
+        fs::write(path, self.to_elf())?;
+        Ok(())
+    }
+
+    /// Reads an ELF core file written by [`CoreDump::write`]
+    pub fn read(path: &Path) -> Result<Self, anyhow::Error> {
+        let bytes = fs::read(path)?;
+        Self::parse(&bytes).with_context(|| format!("failed to parse {}", path.display()))
+    }
+
+    /// Parses an ELF core file
+    ///
+    /// Core files written by other tools are accepted as long as they contain an `NT_PRSTATUS`
+    /// note; only R0-R15 and xPSR will be available in that case.
+    pub fn parse(bytes: &[u8]) -> Result<Self, anyhow::Error> {
+        if bytes.len() < ELF_HEADER_SIZE as usize || bytes[..4] != [0x7f, b'E', b'L', b'F'] {
+            bail!("not an ELF file");
+        }
+        // 32-bit, little endian
+        if bytes[4] != 1 || bytes[5] != 1 {
+            bail!("not a 32-bit little endian ELF file");
+        }
+        if read_u16(bytes, 16)? != ET_CORE || read_u16(bytes, 18)? != EM_ARM {
+            bail!("not an ARM core file");
+        }
+
+        let phoff = read_u32(bytes, 28)? as usize;
+        let phentsize = read_u16(bytes, 42)? as usize;
+        let phnum = read_u16(bytes, 44)? as usize;
+
+        let mut dump = Self::default();
+        let mut prstatus = None;
+        for index in 0..phnum {
+            let header = phoff + index * phentsize;
+            let kind = read_u32(bytes, header)?;
+            let offset = read_u32(bytes, header + 4)? as usize;
+            let address = read_u32(bytes, header + 8)?;
+            let size = read_u32(bytes, header + 16)? as usize;
+            let data = bytes
+                .get(offset..offset + size)
+                .ok_or_else(|| anyhow!("segment {} is out of bounds", index))?;
+
+            match kind {
+                PT_LOAD => dump.memory.push((address, data.to_vec())),
+                PT_NOTE => {
+                    for Note { name, kind, desc } in notes(data)? {
+                        match (name, kind) {
+                            // only the first thread; the others are derived from it
+                            ("CORE", NT_PRSTATUS) if prstatus.is_none() => prstatus = Some(desc),
+                            (PROBE_RUN_NOTE, NT_FIRMWARE) => {
+                                dump.firmware = Some(PathBuf::from(c_string(desc)?))
+                            }
+                            (PROBE_RUN_NOTE, NT_CHIP) => dump.chip = Some(c_string(desc)?),
+                            (PROBE_RUN_NOTE, NT_CANARY) => {
+                                let address = read_u32(desc, 0)?;
+                                dump.canary = Some(address..address + read_u32(desc, 4)?);
+                            }
+                            (PROBE_RUN_NOTE, NT_REGISTERS) => {
+                                for pair in desc.chunks_exact(8) {
+                                    let reg = read_u32(pair, 0)? as u16;
+                                    dump.registers.insert(reg, read_u32(pair, 4)?);
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if dump.registers.is_empty() {
+            let prstatus =
+                prstatus.ok_or_else(|| anyhow!("the core file contains no registers"))?;
+            for (index, reg) in (0..16).chain(Some(XPSR.0)).enumerate() {
+                let value = read_u32(prstatus, PRSTATUS_REG + 4 * index)?;
+                dump.registers.insert(reg, value);
+            }
+        }
+
+        Ok(dump)
+    }
+
+    /// Registers stacked on entry to the exception that was active when the dump was taken
+    fn exception_frame(&self) -> Option<Stacked> {
+        let lr = *self.registers.get(&LR.0)?;
+        let fpu = match lr {
+            0xFFFFFFF1 | 0xFFFFFFF9 | 0xFFFFFFFD => false,
+            0xFFFFFFE1 | 0xFFFFFFE9 | 0xFFFFFFED => true,
+            _ => return None,
+        };
+
+        let sp = *self.registers.get(&SP.0)?;
+        match Stacked::read(&mut self.target(), sp, fpu) {
+            Ok(stacked) => Some(stacked),
+            Err(e) => {
+                log::debug!("exception frame is not part of the core dump: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Returns a `TargetAccess` implementation backed by the dumped state
+    pub fn target(&self) -> FakeTarget {
+        let mut target = FakeTarget::new();
+        for (reg, value) in &self.registers {
+            target = target.with_register(CoreRegisterAddress(*reg), *value);
+        }
+        for (start, data) in &self.memory {
+            target = target.with_memory(*start, data.clone());
+        }
+        target
+    }
+
+    fn prstatus(&self, pid: u32, register: impl Fn(u16) -> Option<u32>) -> Vec<u8> {
+        let mut prstatus = vec![0; PRSTATUS_SIZE];
+        prstatus[PRSTATUS_CURSIG..PRSTATUS_CURSIG + 2].copy_from_slice(&SIGNAL_ABRT.to_le_bytes());
+        prstatus[PRSTATUS_PID..PRSTATUS_PID + 4].copy_from_slice(&pid.to_le_bytes());
+        // R0-R15 and CPSR (xPSR on Cortex-M); ORIG_R0 is left as zero
+        for (index, reg) in (0..16).chain(Some(XPSR.0)).enumerate() {
+            let offset = PRSTATUS_REG + 4 * index;
+            let value = register(reg).unwrap_or(0);
+            prstatus[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        prstatus
+    }
+}
+
+fn push_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_program_header(
+    buf: &mut Vec<u8>,
+    kind: u32,
+    offset: u32,
+    address: u32,
+    size: u32,
+    flags: u32,
+) {
+    push_u32(buf, kind);
+    push_u32(buf, offset);
+    push_u32(buf, address); // p_vaddr
+    push_u32(buf, address); // p_paddr
+    push_u32(buf, size); // p_filesz
+    push_u32(buf, size); // p_memsz
+    push_u32(buf, flags);
+    push_u32(buf, 4); // p_align
+}
+
+fn push_note(buf: &mut Vec<u8>, name: &str, kind: u32, desc: &[u8]) {
+    push_u32(buf, name.len() as u32 + 1);
+    push_u32(buf, desc.len() as u32);
+    push_u32(buf, kind);
+    buf.extend_from_slice(name.as_bytes());
+    buf.push(0);
+    pad(buf);
+    buf.extend_from_slice(desc);
+    pad(buf);
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, anyhow::Error> {
+    let bytes = bytes
+        .get(offset..offset + 2)
+        .ok_or_else(|| anyhow!("unexpected end of file"))?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, anyhow::Error> {
+    let bytes = bytes
+        .get(offset..offset + 4)
+        .ok_or_else(|| anyhow!("unexpected end of file"))?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+struct Note<'a> {
+    name: &'a str,
+    kind: u32,
+    desc: &'a [u8],
+}
+
+/// Splits the contents of a `PT_NOTE` segment into notes
+fn notes(mut data: &[u8]) -> Result<Vec<Note<'_>>, anyhow::Error> {
+    fn aligned(len: usize) -> usize {
+        (len + 3) & !3
+    }
+
+    let mut notes = vec![];
+    while !data.is_empty() {
+        let name_size = read_u32(data, 0)? as usize;
+        let desc_size = read_u32(data, 4)? as usize;
+        let kind = read_u32(data, 8)?;
+        let desc_start = 12 + aligned(name_size);
+        let end = desc_start + aligned(desc_size);
+        if end > data.len() {
+            bail!("note is out of bounds");
+        }
+
+        // the name is NUL terminated
+        let name = std::str::from_utf8(&data[12..12 + name_size.saturating_sub(1)])?;
+        notes.push(Note {
+            name,
+            kind,
+            desc: &data[desc_start..desc_start + desc_size],
+        });
+        data = &data[end..];
+    }
+    Ok(notes)
+}
+
+fn c_string(bytes: &[u8]) -> Result<String, anyhow::Error> {
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    Ok(String::from_utf8(bytes[..len].to_vec())?)
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    let len = (buf.len() + 3) & !3;
+    buf.resize(len, 0);
+}
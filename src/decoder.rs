@@ -0,0 +1,126 @@
+//! Decoding of the defmt stream sent over RTT
+
+use std::path::Path;
+
+use crate::{config::OutputFormat, elf::ProcessedElf, event::Event, rtt::Output, LogFrame};
+
+/// Turns the bytes of a defmt RTT channel into log frames
+///
+/// Bytes may arrive in arbitrarily sized chunks; incomplete frames are kept until the rest of
+/// their bytes arrive.
+pub(crate) struct Decoder<'a> {
+    table: &'a defmt_decoder::Table,
+    locations: Option<&'a defmt_elf2table::Locations>,
+    current_dir: &'a Path,
+    format: OutputFormat,
+    /// Where to write the frames instead of reporting them in the output `format`
+    output: Option<Output>,
+    frames: Vec<u8>,
+}
+
+impl<'a> Decoder<'a> {
+    /// Returns `None` if the firmware contains no defmt data
+    pub(crate) fn new(
+        elf: &'a ProcessedElf,
+        current_dir: &'a Path,
+        format: OutputFormat,
+    ) -> Option<Self> {
+        Some(Self {
+            table: elf.defmt_table.as_ref()?,
+            locations: elf.defmt_locations.as_ref(),
+            current_dir,
+            format,
+            output: None,
+            frames: vec![],
+        })
+    }
+
+    /// Writes the frames, one per line, to `output`
+    pub(crate) fn with_output(mut self, output: Output) -> Self {
+        self.output = Some(output);
+        self
+    }
+
+    /// Drops the bytes of an incomplete frame, e.g. one cut short by a reset of the device
+    pub(crate) fn reset(&mut self) {
+        self.frames.clear();
+    }
+
+    /// Decodes the frames completed by `bytes`, reports them in the output `format` and appends
+    /// them to `logs`
+    pub(crate) fn received(
+        &mut self,
+        bytes: &[u8],
+        logs: &mut Vec<LogFrame>,
+    ) -> Result<(), anyhow::Error> {
+        self.frames.extend_from_slice(bytes);
+
+        loop {
+            match defmt_decoder::decode(&self.frames, self.table) {
+                Ok((frame, consumed)) => {
+                    // NOTE(`[]` indexing) all indices in `table` have already been
+                    // verified to exist in the `locs` map
+                    let loc = self.locations.map(|locs| &locs[&frame.index()]);
+
+                    let (mut file, mut line, mut mod_path) = (None, None, None);
+                    if let Some(loc) = loc {
+                        let relpath = if let Ok(relpath) = loc.file.strip_prefix(self.current_dir) {
+                            relpath
+                        } else {
+                            // not relative; use full path
+                            &loc.file
+                        };
+                        file = Some(relpath.display().to_string());
+                        line = Some(loc.line as u32);
+                        mod_path = Some(loc.module.clone());
+                    }
+
+                    let level = match frame.level() {
+                        defmt_decoder::Level::Trace => log::Level::Trace,
+                        defmt_decoder::Level::Debug => log::Level::Debug,
+                        defmt_decoder::Level::Info => log::Level::Info,
+                        defmt_decoder::Level::Warn => log::Level::Warn,
+                        defmt_decoder::Level::Error => log::Level::Error,
+                    };
+                    let log_frame = LogFrame {
+                        level,
+                        timestamp: frame.timestamp(),
+                        message: frame.display(false).to_string(),
+                        args: frame.display_args().to_string(),
+                        file,
+                        line,
+                        module_path: mod_path,
+                    };
+
+                    match (&mut self.output, self.format) {
+                        (Some(output), _) => {
+                            let mut line = log_frame.message.clone().into_bytes();
+                            line.push(b'\n');
+                            output.write(&line)?;
+                        }
+                        // Forward the defmt frame to our logger.
+                        (None, OutputFormat::Human) => defmt_logger::log_defmt(
+                            &frame,
+                            log_frame.file.as_deref(),
+                            log_frame.line,
+                            log_frame.module_path.as_deref(),
+                        ),
+                        (None, OutputFormat::Json) => Event::log(&log_frame).emit()?,
+                    }
+                    logs.push(log_frame);
+
+                    let num_frames = self.frames.len();
+                    self.frames.rotate_left(consumed);
+                    self.frames.truncate(num_frames - consumed);
+                }
+                Err(defmt_decoder::DecodeError::UnexpectedEof) => break,
+                Err(defmt_decoder::DecodeError::Malformed) => {
+                    log::error!("failed to decode defmt data: {:x?}", self.frames);
+                    Err(defmt_decoder::DecodeError::Malformed)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
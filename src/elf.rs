@@ -0,0 +1,349 @@
+use std::{collections::HashSet, convert::TryInto, ops::Range};
+
+use anyhow::{anyhow, bail};
+use arrayref::array_ref;
+use object::{
+    read::{File as ElfFile, Object as _, ObjectSection as _},
+    ObjectSegment, SymbolSection,
+};
+use probe_rs::config::RamRegion;
+
+use crate::{config::HeapSymbols, exception::Exception, THUMB_BIT};
+
+/// Symbols that delimit the heap in commonly used linker scripts, as (start, end) pairs
+///
+/// `cortex-m-rt` defines only the start of the heap, `__sheap`; names given to its end vary, so
+/// they have to be passed with `--heap-symbols`.
+const HEAP_SYMBOLS: &[(&str, &str)] = &[
+    ("_heap_start", "_heap_end"),
+    ("__heap_start", "__heap_end"),
+    ("__HeapBase", "__HeapLimit"),
+];
+
+/// Everything `probe-run` needs to know about the firmware, extracted from its ELF file
+pub struct ProcessedElf<'file> {
+    pub elf: ElfFile<'file>,
+    pub defmt_table: Option<defmt_decoder::Table>,
+    pub defmt_locations: Option<defmt_elf2table::Locations>,
+    pub debug_frame: Option<&'file [u8]>,
+    pub vector_table: VectorTable,
+    pub live_functions: HashSet<&'file str>,
+    /// The RAM region that contains the stack, i.e. the initial stack pointer
+    pub stack_ram_region: Option<RamRegion>,
+    /// Highest address used by static variables in `stack_ram_region`; 0 if there are none
+    pub highest_ram_addr_in_use: u32,
+    pub rtt_addr: Option<u32>,
+    pub uses_heap: bool,
+    pub main: u32,
+}
+
+impl<'file> ProcessedElf<'file> {
+    /// Parses the firmware `bytes`
+    ///
+    /// `ram_regions` are the RAM regions of the chip; the one that contains the stack is used to
+    /// compute the highest RAM address used by static variables
+    pub fn parse(bytes: &'file [u8], ram_regions: &[RamRegion]) -> Result<Self, anyhow::Error> {
+        let elf = ElfFile::parse(bytes)?;
+
+        // NOTE we want to raise the linking error before calling `defmt_elf2table::parse`
+        let text = elf
+            .section_by_name(".text")
+            .map(|section| section.index())
+            .ok_or_else(|| {
+                anyhow!(
+                    "`.text` section is missing, please make sure that the linker script was passed \
+                    to the linker (check `.cargo/config.toml` and the `RUSTFLAGS` variable)"
+                )
+            })?;
+
+        let (defmt_table, defmt_locations) = {
+            let table = defmt_elf2table::parse(bytes)?;
+
+            let locs = if let Some(table) = table.as_ref() {
+                let locs = defmt_elf2table::get_locations(bytes, table)?;
+
+                if !table.is_empty() && locs.is_empty() {
+                    log::warn!("insufficient DWARF info; compile your program with `debug = 2` to enable location info");
+                    None
+                } else {
+                    if table.indices().all(|idx| locs.contains_key(&(idx as u64))) {
+                        Some(locs)
+                    } else {
+                        log::warn!(
+                            "(BUG) location info is incomplete; it will be omitted from the output"
+                        );
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+
+            (table, locs)
+        };
+
+        // sections used in cortex-m-rt
+        // NOTE we won't load `.uninit` so it is not included here
+        // NOTE we don't load `.bss` because the app (cortex-m-rt) will zero it
+        let candidates = [".vector_table", ".text", ".rodata", ".data"];
+
+        // (name, start address, last address) of the non-empty sections
+        let mut section_ranges = vec![];
+        let mut debug_frame = None;
+        let mut vector_table = None;
+        for sect in elf.sections() {
+            if sect.size() != 0 {
+                let last_addr: u32 = (sect.address() + sect.size() - 1).try_into()?;
+                section_ranges.push((
+                    sect.name().unwrap_or("<unknown>").to_string(),
+                    sect.address(),
+                    last_addr,
+                ));
+            }
+
+            if let Ok(name) = sect.name() {
+                if name == ".debug_frame" {
+                    debug_frame = Some(sect.data()?);
+                    continue;
+                }
+
+                let size = sect.size();
+                // skip empty sections
+                if candidates.contains(&name) && size != 0 {
+                    let start = sect.address();
+                    if size % 4 != 0 || start % 4 != 0 {
+                        // we could support unaligned sections but let's not do that now
+                        bail!("section `{}` is not 4-byte aligned", name);
+                    }
+
+                    let start = start.try_into()?;
+                    let data = sect
+                        .data()?
+                        .chunks_exact(4)
+                        .map(|chunk| u32::from_le_bytes(*array_ref!(chunk, 0, 4)))
+                        .collect::<Vec<_>>();
+
+                    if name == ".vector_table" {
+                        vector_table = Some(VectorTable {
+                            location: start,
+                            // Initial stack pointer
+                            initial_sp: data[0],
+                            reset: data[1],
+                            hard_fault: data[3],
+                            vectors: data,
+                        });
+                    }
+                }
+            }
+        }
+
+        let live_functions = elf
+            .symbol_map()
+            .symbols()
+            .iter()
+            .filter_map(|sym| {
+                if sym.section() == SymbolSection::Section(text) {
+                    sym.name()
+                } else {
+                    None
+                }
+            })
+            .collect::<HashSet<_>>();
+
+        let (rtt_addr, uses_heap, main) = get_rtt_heap_main_from(&elf)?;
+
+        let vector_table =
+            vector_table.ok_or_else(|| anyhow!("`.vector_table` section is missing"))?;
+        log::debug!("vector table: {:x?}", vector_table);
+
+        let stack_ram_region = stack_ram_region(ram_regions, vector_table.initial_sp);
+
+        // If a section resides in the stack's RAM region, track the highest RAM address in use.
+        let mut highest_ram_addr_in_use = 0;
+        if let Some(ram) = &stack_ram_region {
+            for (name, start, last_addr) in section_ranges {
+                if ram.range.contains(&last_addr) {
+                    log::debug!(
+                        "section `{}` is in RAM at 0x{:08X}-0x{:08X}",
+                        name,
+                        start,
+                        last_addr,
+                    );
+                    highest_ram_addr_in_use = highest_ram_addr_in_use.max(last_addr);
+                }
+            }
+        }
+
+        Ok(Self {
+            elf,
+            defmt_table,
+            defmt_locations,
+            debug_frame,
+            vector_table,
+            live_functions,
+            stack_ram_region,
+            highest_ram_addr_in_use,
+            rtt_addr,
+            uses_heap,
+            main,
+        })
+    }
+
+    /// The heap region, delimited by the `symbols` given by the user or by well-known symbols
+    ///
+    /// Returns `None` if the symbols are missing from the firmware. The start of the heap alone,
+    /// as given by `cortex-m-rt`'s `__sheap`, is not enough: its size is only known at runtime.
+    pub fn heap_region(&self, symbols: Option<&HeapSymbols>) -> Option<Range<u32>> {
+        let address = |name: &str| {
+            self.elf
+                .symbols()
+                .find(|(_, symbol)| symbol.name() == Some(name))
+                .map(|(_, symbol)| symbol.address() as u32)
+        };
+
+        let heap = if let Some(symbols) = symbols {
+            match (address(&symbols.start), address(&symbols.end)) {
+                (Some(start), Some(end)) => Some(start..end),
+                _ => {
+                    log::warn!(
+                        "heap symbols `{}` and `{}` not found in the firmware",
+                        symbols.start,
+                        symbols.end
+                    );
+                    None
+                }
+            }
+        } else {
+            HEAP_SYMBOLS
+                .iter()
+                .find_map(|(start, end)| Some(address(start)?..address(end)?))
+        };
+
+        let heap = heap.filter(|heap| heap.start <= heap.end)?;
+        log::debug!("heap: 0x{:08X}-0x{:08X}", heap.start, heap.end);
+        Some(heap)
+    }
+
+    /// Size of the program, in bytes, as it will be written to Flash
+    pub fn program_size(&self) -> u64 {
+        // `segments` iterates only over *loadable* segments, which are the segments that will be loaded to Flash by probe-rs
+        self.elf.segments().map(|segment| segment.size()).sum()
+    }
+}
+
+/// The RAM region that contains the stack, which starts at `initial_sp`
+fn stack_ram_region(ram_regions: &[RamRegion], initial_sp: u32) -> Option<RamRegion> {
+    let region = ram_regions.iter().find(|region| {
+        // NOTE stack is full descending; meaning the stack pointer can be `ORIGIN(RAM) +
+        // LENGTH(RAM)` but not `ORIGIN(RAM)`
+        region.range.start < initial_sp && initial_sp <= region.range.end
+    });
+
+    match region {
+        Some(region) => log::debug!(
+            "the stack is in the RAM region 0x{:08X}-0x{:08X}",
+            region.range.start,
+            region.range.end - 1
+        ),
+        None if !ram_regions.is_empty() => log::debug!(
+            "no RAM region contains the initial stack pointer 0x{:08X}; stack canary will not \
+            be available",
+            initial_sp
+        ),
+        None => {}
+    }
+
+    region.cloned()
+}
+
+fn get_rtt_heap_main_from(
+    elf: &ElfFile,
+) -> Result<(Option<u32>, bool /* uses heap */, u32), anyhow::Error> {
+    let mut rtt = None;
+    let mut uses_heap = false;
+    let mut main = None;
+
+    for (_, symbol) in elf.symbols() {
+        let name = match symbol.name() {
+            Some(name) => name,
+            None => continue,
+        };
+
+        match name {
+            "main" => main = Some(symbol.address() as u32 & !THUMB_BIT),
+            "_SEGGER_RTT" => rtt = Some(symbol.address() as u32),
+            "__rust_alloc" | "__rg_alloc" | "__rdl_alloc" | "malloc" if !uses_heap => {
+                log::debug!("symbol `{}` indicates heap is in use", name);
+                uses_heap = true;
+            }
+            _ => {}
+        }
+    }
+
+    Ok((
+        rtt,
+        uses_heap,
+        main.ok_or_else(|| anyhow!("`main` symbol not found"))?,
+    ))
+}
+
+/// The contents of the vector table
+#[derive(Debug)]
+pub struct VectorTable {
+    pub location: u32,
+    // entry 0
+    pub initial_sp: u32,
+    // entry 1: Reset handler
+    pub reset: u32,
+    // entry 3: HardFault handler
+    pub hard_fault: u32,
+    /// All the entries; entry `n` is the handler of exception number `n`
+    pub vectors: Vec<u32>,
+}
+
+impl VectorTable {
+    /// Address of the handler of `exception`, with the Thumb bit set
+    pub fn handler(&self, exception: Exception) -> Option<u32> {
+        // entry 0 is the initial stack pointer, not a handler
+        if exception.0 == 0 {
+            return None;
+        }
+
+        self.vectors.get(usize::from(exception.0)).copied()
+    }
+
+    /// Addresses, without the Thumb bit, of the handlers to break on to detect `faults`
+    ///
+    /// A handler shared by several faults, like `cortex-m-rt`'s `DefaultHandler`, appears once,
+    /// paired with the first of them.
+    pub fn fault_handlers(&self, faults: &[Exception]) -> Vec<(Exception, u32)> {
+        let mut handlers: Vec<(Exception, u32)> = vec![];
+        for &fault in faults {
+            // the configurable faults' entries are reserved, and zero, on ARMv6-M
+            if let Some(handler) = self.handler(fault).filter(|&handler| handler != 0) {
+                let handler = handler & !THUMB_BIT;
+                if handlers.iter().all(|&(_, other)| other != handler) {
+                    handlers.push((fault, handler));
+                }
+            }
+        }
+        handlers
+    }
+
+    /// The fault, HardFault or configurable, whose handler starts at `pc`
+    pub fn fault_at(&self, pc: u32) -> Option<Exception> {
+        if pc & !THUMB_BIT == self.hard_fault & !THUMB_BIT {
+            return Some(Exception::HARD_FAULT);
+        }
+
+        Exception::CONFIGURABLE_FAULTS
+            .iter()
+            .copied()
+            .find(|&fault| {
+                self.handler(fault)
+                    .filter(|&handler| handler != 0)
+                    .map(|handler| handler & !THUMB_BIT)
+                    == Some(pc & !THUMB_BIT)
+            })
+    }
+}
@@ -0,0 +1,268 @@
+//! Machine-readable output, enabled with `--format json`
+//!
+//! Every stage of a run is reported as one JSON object per line on stdout. The `event` field of
+//! each object names the kind of event.
+
+use std::io::{self, Write as _};
+
+use serde::Serialize;
+
+use crate::{
+    backtrace::{Backtrace, Frame, Variable},
+    exception::Exception,
+    fault::FaultStatus,
+    registers::CoreRegisters,
+    CanaryState, ExitReason, LogFrame, RunOutcome, StackUsage,
+};
+
+/// Something that happened during a run
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// The program is about to be written to flash
+    FlashStart { size: u64 },
+    /// The program was written to flash
+    FlashFinish { size: u64 },
+    /// Flashing was skipped because of `--no-flash`
+    FlashSkipped,
+    /// A defmt log frame
+    Log {
+        level: String,
+        timestamp: u64,
+        message: &'a str,
+        args: &'a str,
+        file: Option<&'a str>,
+        line: Option<u32>,
+        module_path: Option<&'a str>,
+    },
+    /// Bytes received on an RTT channel that doesn't carry defmt data, decoded as UTF-8
+    Output {
+        /// Name of the channel or, for unnamed channels, its number
+        channel: &'a str,
+        data: String,
+    },
+    /// Result of the stack canary check
+    StackCanary {
+        intact: bool,
+        min_stack_usage: Option<u32>,
+        heap_collision: bool,
+    },
+    /// Stack usage measured with `--measure-stack`
+    StackUsage {
+        peak: u32,
+        available: u32,
+        headroom: u32,
+        percent_used: f64,
+    },
+    /// A function in the backtrace; `index` counts physical and inlined frames like the human
+    /// readable backtrace does
+    BacktraceFrame {
+        index: usize,
+        function: &'a str,
+        file: Option<String>,
+        line: Option<u64>,
+        pc: u32,
+        inlined: bool,
+        /// Arguments and local variables, read with `--backtrace-locals`
+        #[serde(skip_serializing_if = "<[_]>::is_empty")]
+        locals: &'a [Variable],
+    },
+    /// An exception was entered at this point of the backtrace
+    ExceptionEntry {
+        /// e.g. `SysTick` or `IRQ 23`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        exception: Option<String>,
+        /// Name of the interrupt handler
+        #[serde(skip_serializing_if = "Option::is_none")]
+        handler: Option<&'a str>,
+    },
+    /// Unwinding stopped because the stack appears to be corrupted
+    BacktraceCorrupted,
+    /// Unwinding stopped because the unwind information of a frame could not be evaluated
+    BacktraceIncomplete { reason: &'a str },
+    /// Decoded fault status registers, after the device ended in the HardFault handler
+    Fault {
+        causes: Vec<String>,
+        /// Address of the faulting instruction
+        pc: Option<u32>,
+        hfsr: u32,
+        cfsr: u32,
+        mmfar: u32,
+        bfar: u32,
+        afsr: u32,
+        sfsr: Option<u32>,
+        sfar: Option<u32>,
+    },
+    /// Registers at the time of halt, printed with `--show-registers`
+    Registers {
+        /// The exception the core halted in, e.g. `HardFault`; `thread mode` outside of exceptions
+        exception: String,
+        /// The exception interrupted by `exception`, decoded from the stacked xPSR
+        interrupted: Option<String>,
+        registers: &'a CoreRegisters,
+    },
+    /// The device reset in the middle of the run; `policy` is what `probe-run` does about it:
+    /// `fail`, `reattach` or `stop`
+    TargetReset { policy: String },
+    /// The run is over
+    Exit {
+        /// `halted`, `hard_fault`, `interrupted`, `timed_out`, `reset`, `lockup`,
+        /// `unexpected_halt` or `semihosting_exit`
+        reason: &'static str,
+        /// With `hard_fault`, the exception whose handler the device ended in
+        #[serde(skip_serializing_if = "Option::is_none")]
+        exception: Option<String>,
+        stack_overflow: bool,
+        /// The DFSR bits set when the core halted on its own, e.g. `["BKPT"]`
+        #[serde(skip_serializing_if = "<[_]>::is_empty")]
+        halt_reason: Vec<&'static str>,
+        code: i32,
+    },
+}
+
+impl<'a> Event<'a> {
+    pub fn log(frame: &'a LogFrame) -> Self {
+        Event::Log {
+            level: frame.level.to_string().to_lowercase(),
+            timestamp: frame.timestamp,
+            message: &frame.message,
+            args: &frame.args,
+            file: frame.file.as_deref(),
+            line: frame.line,
+            module_path: frame.module_path.as_deref(),
+        }
+    }
+
+    pub fn output(channel: &'a str, bytes: &[u8]) -> Self {
+        Event::Output {
+            channel,
+            data: String::from_utf8_lossy(bytes).into_owned(),
+        }
+    }
+
+    pub fn stack_canary(state: CanaryState) -> Self {
+        match state {
+            CanaryState::Intact => Event::StackCanary {
+                intact: true,
+                min_stack_usage: None,
+                heap_collision: false,
+            },
+            CanaryState::Touched {
+                min_stack_usage,
+                heap_collision,
+            } => Event::StackCanary {
+                intact: false,
+                min_stack_usage: Some(min_stack_usage),
+                heap_collision,
+            },
+        }
+    }
+
+    pub fn stack_usage(usage: StackUsage) -> Self {
+        Event::StackUsage {
+            peak: usage.peak,
+            available: usage.available,
+            headroom: usage.headroom(),
+            percent_used: usage.percent_used(),
+        }
+    }
+
+    /// Events describing `backtrace`, outermost frame last
+    pub fn backtrace(backtrace: &'a Backtrace) -> Vec<Self> {
+        let mut events = vec![];
+        let mut index = 0;
+        for frame in &backtrace.frames {
+            match frame {
+                Frame::Subroutine(subroutine) => {
+                    let location = subroutine.location.as_ref();
+                    events.push(Event::BacktraceFrame {
+                        index,
+                        function: &subroutine.name,
+                        file: location.map(|location| location.file.display().to_string()),
+                        line: location.map(|location| location.line),
+                        pc: subroutine.pc,
+                        inlined: subroutine.is_inlined,
+                        locals: &subroutine.locals,
+                    });
+                    index += 1;
+                }
+                Frame::Exception(entry) => events.push(Event::ExceptionEntry {
+                    exception: entry.exception.map(|exception| exception.to_string()),
+                    handler: entry.handler.as_deref(),
+                }),
+            }
+        }
+
+        if backtrace.corrupted {
+            events.push(Event::BacktraceCorrupted);
+        }
+
+        if let Some(reason) = &backtrace.incomplete {
+            events.push(Event::BacktraceIncomplete { reason });
+        }
+
+        events
+    }
+
+    pub fn fault(fault: &FaultStatus) -> Self {
+        Event::Fault {
+            causes: fault.causes(),
+            pc: fault.pc,
+            hfsr: fault.hfsr,
+            cfsr: fault.cfsr,
+            mmfar: fault.mmfar,
+            bfar: fault.bfar,
+            afsr: fault.afsr,
+            sfsr: fault.sfsr,
+            sfar: fault.sfar,
+        }
+    }
+
+    pub fn registers(registers: &'a CoreRegisters) -> Self {
+        Event::Registers {
+            exception: registers.exception().to_string(),
+            interrupted: registers
+                .stacked
+                .as_ref()
+                .map(|stacked| Exception::from_xpsr(stacked.xpsr).to_string()),
+            registers,
+        }
+    }
+
+    pub fn exit(outcome: &RunOutcome) -> Self {
+        let (reason, exception, stack_overflow) = match outcome.exit_reason {
+            ExitReason::Halted => ("halted", None, false),
+            ExitReason::HardFault {
+                exception,
+                stack_overflow,
+            } => ("hard_fault", Some(exception.to_string()), stack_overflow),
+            ExitReason::Interrupted => ("interrupted", None, false),
+            ExitReason::TimedOut => ("timed_out", None, false),
+            ExitReason::Reset => ("reset", None, false),
+            ExitReason::Lockup => ("lockup", None, false),
+            ExitReason::UnexpectedHalt { .. } => ("unexpected_halt", None, false),
+            ExitReason::SemihostingExit { .. } => ("semihosting_exit", None, false),
+        };
+
+        Event::Exit {
+            reason,
+            exception,
+            stack_overflow,
+            halt_reason: outcome
+                .halt_reason
+                .map(|reason| reason.names())
+                .unwrap_or_default(),
+            code: outcome.exit_code(),
+        }
+    }
+
+    /// Prints the event to stdout as a single line of JSON
+    pub fn emit(&self) -> Result<(), anyhow::Error> {
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        serde_json::to_writer(&mut stdout, self)?;
+        stdout.write_all(b"\n")?;
+        stdout.flush()?;
+        Ok(())
+    }
+}
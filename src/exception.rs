@@ -0,0 +1,67 @@
+//! Cortex-M exception numbers
+
+use std::fmt;
+
+/// Mask of the IPSR bits of xPSR
+const IPSR_MASK: u32 = 0x1FF;
+
+/// Exception numbers below this one are system exceptions; the rest are interrupts
+const FIRST_IRQ: u16 = 16;
+
+/// An exception number, as found in the IPSR bits of xPSR; 0 in thread mode
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Exception(pub u16);
+
+impl Exception {
+    pub const HARD_FAULT: Self = Exception(3);
+    pub const MEM_MANAGE: Self = Exception(4);
+    pub const BUS_FAULT: Self = Exception(5);
+    pub const USAGE_FAULT: Self = Exception(6);
+    pub const SECURE_FAULT: Self = Exception(7);
+
+    /// The faults that escalate to a HardFault while their handlers are disabled
+    pub const CONFIGURABLE_FAULTS: [Self; 4] = [
+        Self::MEM_MANAGE,
+        Self::BUS_FAULT,
+        Self::USAGE_FAULT,
+        Self::SECURE_FAULT,
+    ];
+
+    /// The exception that was active when `xpsr` was read or stacked
+    pub fn from_xpsr(xpsr: u32) -> Self {
+        Exception((xpsr & IPSR_MASK) as u16)
+    }
+
+    /// The interrupt number, for exceptions that are device interrupts
+    pub fn irq(self) -> Option<u16> {
+        self.0.checked_sub(FIRST_IRQ)
+    }
+}
+
+/// Formats the exception by name, e.g. `SysTick` or `IRQ 23`
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self.0 {
+            0 => "thread mode",
+            1 => "Reset",
+            2 => "NMI",
+            3 => "HardFault",
+            4 => "MemManage",
+            5 => "BusFault",
+            6 => "UsageFault",
+            7 => "SecureFault",
+            11 => "SVCall",
+            12 => "DebugMonitor",
+            14 => "PendSV",
+            15 => "SysTick",
+            number => {
+                return match self.irq() {
+                    Some(irq) => write!(f, "IRQ {}", irq),
+                    None => write!(f, "reserved exception {}", number),
+                }
+            }
+        };
+
+        f.write_str(name)
+    }
+}
@@ -0,0 +1,274 @@
+//! Decoding of the fault status registers of the System Control Block
+//!
+//! When the device ends in the HardFault handler these registers tell why: the configurable
+//! fault (MemManage, BusFault, UsageFault) that was escalated to a HardFault and, for some
+//! faults, the address that was being accessed.
+
+use std::fmt;
+
+use crate::target::TargetAccess;
+
+/// Configurable Fault Status Register: MMFSR (byte 0), BFSR (byte 1) and UFSR (bytes 2-3)
+const CFSR: u32 = 0xE000_ED28;
+/// HardFault Status Register
+const HFSR: u32 = 0xE000_ED2C;
+/// MemManage Fault Address Register
+const MMFAR: u32 = 0xE000_ED34;
+/// BusFault Address Register
+const BFAR: u32 = 0xE000_ED38;
+/// Auxiliary Fault Status Register
+const AFSR: u32 = 0xE000_ED3C;
+/// Processor Feature Register 1; its `Security` field is non-zero on ARMv8-M cores with the
+/// Security Extension
+const ID_PFR1: u32 = 0xE000_ED44;
+/// Secure Fault Status Register (ARMv8-M)
+const SFSR: u32 = 0xE000_EDE4;
+/// Secure Fault Address Register (ARMv8-M)
+const SFAR: u32 = 0xE000_EDE8;
+
+// HFSR bits
+const VECTTBL: u32 = 1 << 1;
+const FORCED: u32 = 1 << 30;
+const DEBUGEVT: u32 = 1 << 31;
+
+// CFSR bits; MMFSR
+const IACCVIOL: u32 = 1 << 0;
+const DACCVIOL: u32 = 1 << 1;
+const MUNSTKERR: u32 = 1 << 3;
+const MSTKERR: u32 = 1 << 4;
+const MLSPERR: u32 = 1 << 5;
+const MMARVALID: u32 = 1 << 7;
+// BFSR
+const IBUSERR: u32 = 1 << 8;
+const PRECISERR: u32 = 1 << 9;
+const IMPRECISERR: u32 = 1 << 10;
+const UNSTKERR: u32 = 1 << 11;
+const STKERR: u32 = 1 << 12;
+const LSPERR: u32 = 1 << 13;
+const BFARVALID: u32 = 1 << 15;
+// UFSR
+const UNDEFINSTR: u32 = 1 << 16;
+const INVSTATE: u32 = 1 << 17;
+const INVPC: u32 = 1 << 18;
+const NOCP: u32 = 1 << 19;
+const STKOF: u32 = 1 << 20;
+const UNALIGNED: u32 = 1 << 24;
+const DIVBYZERO: u32 = 1 << 25;
+
+const MMFSR_MASK: u32 = 0x0000_00FF;
+const BFSR_MASK: u32 = 0x0000_FF00;
+const UFSR_MASK: u32 = 0xFFFF_0000;
+
+// SFSR bits
+const INVEP: u32 = 1 << 0;
+const INVIS: u32 = 1 << 1;
+const INVER: u32 = 1 << 2;
+const AUVIOL: u32 = 1 << 3;
+const INVTRAN: u32 = 1 << 4;
+const SFSR_LSPERR: u32 = 1 << 5;
+const SFARVALID: u32 = 1 << 6;
+const LSERR: u32 = 1 << 7;
+
+/// HFSR bits and the faults they indicate; `FORCED` is decoded along with the CFSR
+const HFSR_CAUSES: &[(u32, &str)] = &[
+    (VECTTBL, "bus fault while reading the vector table"),
+    (
+        DEBUGEVT,
+        "debug event, e.g. a breakpoint while no debugger was attached",
+    ),
+];
+
+/// CFSR bits and the faults they indicate
+const CFSR_CAUSES: &[(u32, &str)] = &[
+    // MemManage
+    (
+        IACCVIOL,
+        "instruction fetch from memory that doesn't permit execution",
+    ),
+    (DACCVIOL, "memory protection violation"),
+    (
+        MUNSTKERR,
+        "memory protection violation when unstacking an exception frame",
+    ),
+    (
+        MSTKERR,
+        "memory protection violation when stacking an exception frame",
+    ),
+    (
+        MLSPERR,
+        "memory protection violation during lazy floating-point state preservation",
+    ),
+    // BusFault
+    (IBUSERR, "bus fault on instruction fetch"),
+    (PRECISERR, "precise bus fault"),
+    (IMPRECISERR, "imprecise bus fault"),
+    (UNSTKERR, "bus fault when unstacking an exception frame"),
+    (STKERR, "bus fault when stacking an exception frame"),
+    (
+        LSPERR,
+        "bus fault during lazy floating-point state preservation",
+    ),
+    // UsageFault
+    (UNDEFINSTR, "undefined instruction"),
+    (
+        INVSTATE,
+        "invalid execution state, e.g. a branch to an address without the Thumb bit set",
+    ),
+    (INVPC, "invalid EXC_RETURN value on exception return"),
+    (
+        NOCP,
+        "coprocessor access while it is disabled, e.g. an FPU instruction",
+    ),
+    (STKOF, "stack overflow detected by the stack limit register"),
+    (UNALIGNED, "unaligned access"),
+    (DIVBYZERO, "divide by zero"),
+];
+
+/// SFSR bits and the faults they indicate
+const SFSR_CAUSES: &[(u32, &str)] = &[
+    (INVEP, "invalid Secure state entry point"),
+    (
+        INVIS,
+        "invalid integrity signature in an exception stack frame",
+    ),
+    (INVER, "invalid exception return"),
+    (AUVIOL, "Secure memory access from the Non-secure state"),
+    (
+        INVTRAN,
+        "branch to Non-secure memory without a state transition",
+    ),
+    (
+        SFSR_LSPERR,
+        "SecureFault during lazy floating-point state preservation",
+    ),
+    (
+        LSERR,
+        "SecureFault during lazy state activation or deactivation",
+    ),
+];
+
+/// The fault status registers, read after the device entered the HardFault handler
+#[derive(Clone, Debug, PartialEq)]
+pub struct FaultStatus {
+    pub hfsr: u32,
+    pub cfsr: u32,
+    pub mmfar: u32,
+    pub bfar: u32,
+    pub afsr: u32,
+    /// Only present on ARMv8-M cores with the Security Extension
+    pub sfsr: Option<u32>,
+    pub sfar: Option<u32>,
+    /// Address of the faulting instruction, i.e. the PC stacked on exception entry
+    pub pc: Option<u32>,
+}
+
+impl FaultStatus {
+    /// Reads the fault status registers of `core`
+    ///
+    /// `pc` is the program counter the exception entry stacked, if known.
+    pub fn read(core: &mut impl TargetAccess, pc: Option<u32>) -> Result<Self, anyhow::Error> {
+        let has_security_extension = core.read_word_32(ID_PFR1)? & 0xF0 != 0;
+        let (sfsr, sfar) = if has_security_extension {
+            (
+                Some(core.read_word_32(SFSR)?),
+                Some(core.read_word_32(SFAR)?),
+            )
+        } else {
+            (None, None)
+        };
+
+        Ok(Self {
+            hfsr: core.read_word_32(HFSR)?,
+            cfsr: core.read_word_32(CFSR)?,
+            mmfar: core.read_word_32(MMFAR)?,
+            bfar: core.read_word_32(BFAR)?,
+            afsr: core.read_word_32(AFSR)?,
+            sfsr,
+            sfar,
+            pc,
+        })
+    }
+
+    /// Human-readable explanations of the fault, most general first
+    pub fn causes(&self) -> Vec<String> {
+        let mut causes = vec![];
+
+        if self.hfsr & FORCED != 0 {
+            let escalated = [
+                (MMFSR_MASK, "MemManage"),
+                (BFSR_MASK, "BusFault"),
+                (UFSR_MASK, "UsageFault"),
+            ]
+            .iter()
+            .filter(|(mask, _)| self.cfsr & mask != 0)
+            .map(|(_, fault)| *fault)
+            .collect::<Vec<_>>();
+
+            causes.push(if escalated.is_empty() {
+                "escalated from a configurable fault".to_string()
+            } else {
+                format!("escalated from {}", escalated.join(" and "))
+            });
+        }
+
+        for (bit, cause) in HFSR_CAUSES {
+            if self.hfsr & bit != 0 {
+                causes.push(cause.to_string());
+            }
+        }
+
+        for (bit, cause) in CFSR_CAUSES {
+            if self.cfsr & bit != 0 {
+                let address = match *bit {
+                    DACCVIOL if self.cfsr & MMARVALID != 0 => Some(self.mmfar),
+                    PRECISERR if self.cfsr & BFARVALID != 0 => Some(self.bfar),
+                    _ => None,
+                };
+                causes.push(with_address(cause, address));
+            }
+        }
+
+        if let Some(sfsr) = self.sfsr {
+            for (bit, cause) in SFSR_CAUSES {
+                if sfsr & bit != 0 {
+                    let address = match *bit {
+                        AUVIOL if sfsr & SFARVALID != 0 => self.sfar,
+                        _ => None,
+                    };
+                    causes.push(with_address(cause, address));
+                }
+            }
+        }
+
+        causes
+    }
+
+    /// The bus fault was imprecise: the stacked PC is past the faulting instruction
+    pub fn is_imprecise(&self) -> bool {
+        self.cfsr & IMPRECISERR != 0
+    }
+}
+
+/// Formats the raw register values, e.g. `HFSR=0x40000000 CFSR=0x00008200 ..`
+impl fmt::Display for FaultStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "HFSR=0x{:08X} CFSR=0x{:08X} MMFAR=0x{:08X} BFAR=0x{:08X} AFSR=0x{:08X}",
+            self.hfsr, self.cfsr, self.mmfar, self.bfar, self.afsr
+        )?;
+
+        if let (Some(sfsr), Some(sfar)) = (self.sfsr, self.sfar) {
+            write!(f, " SFSR=0x{:08X} SFAR=0x{:08X}", sfsr, sfar)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn with_address(cause: &str, address: Option<u32>) -> String {
+    match address {
+        Some(address) => format!("{} accessing 0x{:08X}", cause, address),
+        None => cause.to_string(),
+    }
+}
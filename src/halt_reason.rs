@@ -0,0 +1,77 @@
+//! Why the core halted, as recorded in the Debug Fault Status Register
+//!
+//! The bits of DFSR are sticky: they accumulate every debug event since they were last cleared,
+//! so they are cleared whenever the core is resumed.
+
+use std::fmt;
+
+use crate::target::TargetAccess;
+
+/// Debug Fault Status Register
+const DFSR: u32 = 0xE000_ED30;
+
+// DFSR bits
+const HALTED: u32 = 1 << 0;
+const BKPT: u32 = 1 << 1;
+const DWTTRAP: u32 = 1 << 2;
+const VCATCH: u32 = 1 << 3;
+const EXTERNAL: u32 = 1 << 4;
+
+const DFSR_MASK: u32 = HALTED | BKPT | DWTTRAP | VCATCH | EXTERNAL;
+
+/// DFSR bits, their names and the debug events they indicate
+const REASONS: &[(u32, &str, &str)] = &[
+    (HALTED, "HALTED", "halt request or single step"),
+    (BKPT, "BKPT", "breakpoint"),
+    (DWTTRAP, "DWTTRAP", "DWT watchpoint or other DWT event"),
+    (VCATCH, "VCATCH", "vector catch"),
+    (EXTERNAL, "EXTERNAL", "external debug request"),
+];
+
+/// The debug events that halted the core since DFSR was last cleared
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HaltReason(pub u32);
+
+impl HaltReason {
+    /// Reads the DFSR of `core`
+    pub fn read(core: &mut impl TargetAccess) -> Result<Self, anyhow::Error> {
+        Ok(HaltReason(core.read_word_32(DFSR)? & DFSR_MASK))
+    }
+
+    /// Clears the DFSR of `core`, so that the next halt reports only its own reason
+    pub fn clear(core: &mut impl TargetAccess) -> Result<(), anyhow::Error> {
+        // the bits are write-one-to-clear
+        core.write_word_32(DFSR, DFSR_MASK)
+    }
+
+    /// The names of the DFSR bits that are set, e.g. `["BKPT"]`
+    pub fn names(self) -> Vec<&'static str> {
+        REASONS
+            .iter()
+            .filter(|(bit, _, _)| self.0 & bit != 0)
+            .map(|(_, name, _)| *name)
+            .collect()
+    }
+
+    /// Whether the core halted on a breakpoint and nothing else, i.e. the firmware ended the run
+    /// with a `bkpt` instruction
+    pub fn is_breakpoint(self) -> bool {
+        self.0 == BKPT
+    }
+}
+
+/// Formats the reasons with their meaning, e.g. `DWTTRAP (DWT watchpoint or other DWT event)`
+impl fmt::Display for HaltReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 == 0 {
+            return f.write_str("unknown");
+        }
+
+        let reasons = REASONS
+            .iter()
+            .filter(|(bit, _, _)| self.0 & bit != 0)
+            .map(|(_, name, description)| format!("{} ({})", name, description))
+            .collect::<Vec<_>>();
+        f.write_str(&reasons.join(", "))
+    }
+}
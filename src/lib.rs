@@ -0,0 +1,1325 @@
+//! Runs embedded programs just like native ones
+//!
+//! This is the library behind the `probe-run` tool. A [`Runner`] flashes a firmware ELF file onto
+//! a device, streams the device's RTT output until the device halts and then reports what
+//! happened as a [`RunOutcome`].
+
+pub mod backtrace;
+mod canary;
+pub mod capture;
+pub mod config;
+pub mod coredump;
+mod decoder;
+pub mod elf;
+pub mod event;
+pub mod exception;
+pub mod fault;
+pub mod halt_reason;
+mod locals;
+pub mod lockup;
+pub mod registers;
+pub mod reset;
+mod rtt;
+pub mod semihosting;
+mod stacked;
+pub mod stdin;
+pub mod target;
+pub mod vector_catch;
+
+use core::{
+    convert::TryInto,
+    mem,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+use colored::Colorize as _;
+use probe_rs::{
+    config::{MemoryRegion, RamRegion},
+    flashing::{self, Format},
+    Core, CoreRegisterAddress, DebugProbeInfo, DebugProbeSelector, MemoryInterface, Probe, Session,
+};
+use probe_rs_rtt::UpChannel;
+
+use crate::{
+    backtrace::{Backtrace, TopException},
+    canary::Canary,
+    capture::{Capture, CaptureWriter, ElfIdentity},
+    config::{ChannelRoute, HeapSymbols, OutputFormat, ResetPolicy, StdinMode},
+    coredump::CoreDump,
+    decoder::Decoder,
+    elf::ProcessedElf,
+    event::Event,
+    exception::Exception,
+    fault::FaultStatus,
+    halt_reason::HaltReason,
+    registers::CoreRegisters,
+    reset::RttWatch,
+    semihosting::{Action, Semihosting},
+    vector_catch::VectorCatch,
+};
+
+pub use crate::{
+    canary::{CanaryState, StackUsage},
+    stacked::{Stacked, StackedFpuRegs},
+};
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+const THUMB_BIT: u32 = 1;
+
+pub const LR: CoreRegisterAddress = CoreRegisterAddress(14);
+pub const PC: CoreRegisterAddress = CoreRegisterAddress(15);
+pub const SP: CoreRegisterAddress = CoreRegisterAddress(13);
+pub const XPSR: CoreRegisterAddress = CoreRegisterAddress(16);
+
+const LR_END: u32 = 0xFFFF_FFFF;
+
+/// Exit code used when the firmware ends in a HardFault
+pub const SIGABRT: i32 = 134;
+
+/// Stack usage, in percent of the available space, above which `--measure-stack` warns
+pub const DEFAULT_STACK_USAGE_THRESHOLD: u8 = 90;
+
+/// Exit code used when the run exceeds its time limit, like coreutils' `timeout`
+pub const TIMED_OUT: i32 = 124;
+
+/// Exit code used when the device resets in the middle of the run and the reset policy is `fail`
+pub const UNEXPECTED_RESET: i32 = 1;
+
+/// Exit code used when the core locks up, like a process killed by SIGILL
+pub const LOCKUP: i32 = 132;
+
+/// Exit code used when the core halts for a reason other than a breakpoint, e.g. a watchpoint
+pub const UNEXPECTED_HALT: i32 = 2;
+
+/// Number of lines of device output repeated when the device resets unexpectedly
+const LAST_LINES_ON_RESET: usize = 5;
+
+/// Configuration of a [`Runner`]
+#[derive(Clone, Debug)]
+pub struct RunConfig {
+    /// Path to an ELF firmware file.
+    pub elf: PathBuf,
+    /// The chip to program.
+    pub chip: String,
+    /// The probe to use (eg. VID:PID or VID:PID:Serial).
+    pub probe: Option<String>,
+    /// The probe clock frequency in kHz
+    pub speed: Option<u32>,
+    /// Skip writing the application binary to flash.
+    pub no_flash: bool,
+    /// Connect to device when NRST is pressed.
+    pub connect_under_reset: bool,
+    /// Write an ELF core dump to this path if the firmware ends in a HardFault.
+    pub coredump: Option<PathBuf>,
+    /// Include the arguments and local variables of each frame in the backtrace.
+    pub backtrace_locals: bool,
+    /// Read the core registers and the exception frame at the time of halt.
+    pub show_registers: bool,
+    /// Record the raw bytes of the RTT channel to this path.
+    pub rtt_capture: Option<PathBuf>,
+    /// Where to send the output of individual RTT up channels; all other channels are printed
+    /// to stdout.
+    pub rtt_channels: Vec<ChannelRoute>,
+    /// How stdin is forwarded to the device.
+    pub stdin: StdinMode,
+    /// The RTT down channel, given by name or number, that receives stdin; channel 0 if unset.
+    pub rtt_down_channel: Option<String>,
+    /// Directory in which the firmware can open files through semihosting.
+    pub semihosting_root: Option<PathBuf>,
+    /// Halt the device and end the run if it has not halted on its own after this long.
+    pub timeout: Option<Duration>,
+    /// Paint all of the free stack space to measure the peak stack usage.
+    pub measure_stack: bool,
+    /// Warn when the measured stack usage exceeds this percentage of the available space.
+    pub stack_usage_threshold: u8,
+    /// Symbols at the start and end of the heap; well-known symbols are looked up if unset.
+    pub heap_symbols: Option<HeapSymbols>,
+    /// Break on the handlers of the configurable faults as well as on the HardFault handler.
+    pub catch_faults: bool,
+    /// What to do when the device resets in the middle of the run.
+    pub on_reset: ResetPolicy,
+    /// How device output is reported.
+    pub format: OutputFormat,
+    /// Set, e.g. from a Ctrl-C handler, to halt the device and end the run.
+    pub abort: Arc<AtomicBool>,
+}
+
+impl RunConfig {
+    pub fn new(elf: impl Into<PathBuf>, chip: impl Into<String>) -> Self {
+        Self {
+            elf: elf.into(),
+            chip: chip.into(),
+            probe: None,
+            speed: None,
+            no_flash: false,
+            connect_under_reset: false,
+            coredump: None,
+            backtrace_locals: false,
+            show_registers: false,
+            rtt_capture: None,
+            rtt_channels: vec![],
+            stdin: StdinMode::Off,
+            rtt_down_channel: None,
+            semihosting_root: None,
+            timeout: None,
+            measure_stack: false,
+            stack_usage_threshold: DEFAULT_STACK_USAGE_THRESHOLD,
+            heap_symbols: None,
+            catch_faults: false,
+            on_reset: ResetPolicy::Fail,
+            format: OutputFormat::Human,
+            abort: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+/// Why the run ended
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExitReason {
+    /// The device halted outside of the HardFault handler, e.g. on a `bkpt` instruction
+    Halted,
+    /// The device entered the HardFault handler or, with `catch_faults`, the handler of a
+    /// configurable fault
+    HardFault {
+        exception: Exception,
+        stack_overflow: bool,
+    },
+    /// The run was interrupted by Ctrl-C
+    Interrupted,
+    /// The device did not halt within the run's time limit
+    TimedOut,
+    /// The device reset in the middle of the run and the reset policy is `fail`
+    Reset,
+    /// The core locked up, e.g. because of a fault in the HardFault handler
+    Lockup,
+    /// The core halted outside of the HardFault handler for a reason other than a breakpoint,
+    /// e.g. a DWT watchpoint or a halt request from another debugger
+    UnexpectedHalt { reason: HaltReason },
+    /// The program exited through semihosting (`SYS_EXIT` or `SYS_EXIT_EXTENDED`)
+    SemihostingExit { code: i32 },
+}
+
+impl ExitReason {
+    /// The exit code `probe-run` reports for this reason
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            ExitReason::HardFault { .. } => SIGABRT,
+            ExitReason::SemihostingExit { code } => code,
+            ExitReason::TimedOut => TIMED_OUT,
+            ExitReason::Reset => UNEXPECTED_RESET,
+            ExitReason::Lockup => LOCKUP,
+            ExitReason::UnexpectedHalt { .. } => UNEXPECTED_HALT,
+            ExitReason::Halted | ExitReason::Interrupted => 0,
+        }
+    }
+}
+
+/// The point in time at which a run times out
+#[derive(Clone, Copy, Debug)]
+pub struct Deadline(Option<Instant>);
+
+impl Deadline {
+    /// Starts counting down `timeout`; without one, or with one too long to represent, the run
+    /// never times out
+    pub fn start(timeout: Option<Duration>) -> Self {
+        Deadline(timeout.and_then(|timeout| Instant::now().checked_add(timeout)))
+    }
+
+    pub fn expired(&self) -> bool {
+        self.0.map(|deadline| Instant::now() >= deadline) == Some(true)
+    }
+}
+
+/// How the poll loop of a run ended
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LoopEnd {
+    /// The run was interrupted by Ctrl-C
+    pub interrupted: bool,
+    /// The run exceeded its time limit
+    pub timed_out: bool,
+    /// The device reset in the middle of the run
+    pub reset: bool,
+    /// The core locked up
+    pub locked_up: bool,
+    /// The program exited through semihosting with this code
+    pub semihosting_exit: Option<i32>,
+}
+
+impl LoopEnd {
+    /// Whether the core was still running when the loop ended, in which case it's `probe-run`
+    /// that halts it
+    pub fn still_running(&self) -> bool {
+        self.interrupted || self.timed_out || self.locked_up
+    }
+
+    /// Reads why the core halted; `None` when it was still running or when DFSR can't be read
+    ///
+    /// Must be called before `probe-run` halts the core itself.
+    pub fn halt_reason(&self, core: &mut impl target::TargetAccess) -> Option<HaltReason> {
+        if self.still_running() {
+            return None;
+        }
+
+        match HaltReason::read(core) {
+            Ok(reason) => Some(reason),
+            Err(e) => {
+                log::warn!("failed to read why the core halted: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Why the run ended, given the exception the core halted in and why it halted
+    pub fn exit_reason(
+        &self,
+        top_exception: Option<&TopException>,
+        halt_reason: Option<HaltReason>,
+        on_reset: ResetPolicy,
+    ) -> ExitReason {
+        if let Some(&TopException::HardFault {
+            exception,
+            stack_overflow,
+        }) = top_exception
+        {
+            ExitReason::HardFault {
+                exception,
+                stack_overflow,
+            }
+        } else if self.locked_up {
+            ExitReason::Lockup
+        } else if self.interrupted {
+            ExitReason::Interrupted
+        } else if self.timed_out {
+            ExitReason::TimedOut
+        } else if self.reset && on_reset == ResetPolicy::Fail {
+            ExitReason::Reset
+        } else if self.reset {
+            // `ResetPolicy::Stop`
+            ExitReason::Halted
+        } else if let Some(code) = self.semihosting_exit {
+            ExitReason::SemihostingExit { code }
+        } else {
+            match halt_reason {
+                Some(reason) if !reason.is_breakpoint() => ExitReason::UnexpectedHalt { reason },
+                _ => ExitReason::Halted,
+            }
+        }
+    }
+}
+
+/// A defmt log frame received from the device
+#[derive(Clone, Debug)]
+pub struct LogFrame {
+    pub level: log::Level,
+    pub timestamp: u64,
+    /// The formatted frame, as it would be displayed in a terminal
+    pub message: String,
+    /// The format string of the frame with its arguments filled in, without the timestamp and
+    /// level
+    pub args: String,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub module_path: Option<String>,
+}
+
+/// The result of a run
+#[derive(Debug)]
+pub struct RunOutcome {
+    pub exit_reason: ExitReason,
+    /// defmt log frames, in the order they were received
+    pub logs: Vec<LogFrame>,
+    /// Text output of the device, in the order it was received: that of the RTT channels that
+    /// don't carry defmt data and that of the semihosting console
+    pub output: Vec<u8>,
+    /// Output of each RTT channel that doesn't carry defmt data, keyed by channel name
+    pub channel_output: BTreeMap<String, Vec<u8>>,
+    /// Backtrace of the device at the time it halted
+    pub backtrace: Backtrace,
+    /// `None` when the stack canary could not be used
+    pub stack_canary: Option<CanaryState>,
+    /// Set when the stack usage was measured
+    pub stack_usage: Option<StackUsage>,
+    /// Fault status registers; set when the device ended in the HardFault handler
+    pub fault: Option<FaultStatus>,
+    /// Registers at the time of halt; set with `--show-registers`
+    pub registers: Option<CoreRegisters>,
+    /// Why the core halted; `None` when `probe-run` halted it or, post mortem, when unknown
+    pub halt_reason: Option<HaltReason>,
+}
+
+impl RunOutcome {
+    /// The exit code `probe-run` reports for this outcome
+    pub fn exit_code(&self) -> i32 {
+        self.exit_reason.exit_code()
+    }
+}
+
+/// Flashes and runs a firmware on a device
+pub struct Runner {
+    config: RunConfig,
+}
+
+impl Runner {
+    pub fn new(config: RunConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn config(&self) -> &RunConfig {
+        &self.config
+    }
+
+    /// Runs the firmware until the device halts or the `abort` flag of the configuration is set
+    ///
+    /// Device output is forwarded as it arrives: defmt frames to the `log` logger and text to
+    /// stdout.
+    pub fn run(&self) -> Result<RunOutcome, anyhow::Error> {
+        let opts = &self.config;
+        let bytes = fs::read(&opts.elf)?;
+
+        let target = probe_rs::config::get_target_by_name(&opts.chip)?;
+
+        let ram_regions = target
+            .memory_map
+            .iter()
+            .filter_map(|region| match region {
+                MemoryRegion::Ram(ram) => Some(ram.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let elf = ProcessedElf::parse(&bytes, &ram_regions)?;
+        let vector_table = &elf.vector_table;
+
+        let sp_ram_region = elf
+            .stack_ram_region
+            .as_ref()
+            .map(|region| region.range.clone());
+
+        let probes = Probe::list_all();
+        let probes = if let Some(probe_opt) = opts.probe.as_deref() {
+            let selector = probe_opt.try_into()?;
+            probes_filter(&probes, &selector)
+        } else {
+            probes
+        };
+        if probes.is_empty() {
+            bail!("no probe was found")
+        }
+        log::debug!("found {} probes", probes.len());
+        if probes.len() > 1 {
+            let probes = probes
+                .iter()
+                .enumerate()
+                .map(|(num, link)| format!("[{}]: {:?}", num, link))
+                .collect::<Vec<_>>();
+            bail!(
+                "more than one probe found; use --probe to specify which one to use:\n{}",
+                probes.join("\n")
+            );
+        }
+        let mut probe = probes[0].open()?;
+        log::debug!("opened probe");
+
+        if let Some(speed) = opts.speed {
+            probe.set_speed(speed)?;
+        }
+
+        let mut sess = if opts.connect_under_reset {
+            probe.attach_under_reset(target)?
+        } else {
+            probe.attach(target)?
+        };
+        log::debug!("started session");
+
+        let json = opts.format == OutputFormat::Json;
+        if opts.no_flash {
+            log::info!("skipped flashing");
+            if json {
+                Event::FlashSkipped.emit()?;
+            }
+        } else {
+            // program lives in Flash
+            let size = elf.program_size();
+            log::info!("flashing program ({:.02} KiB)", size as f64 / 1024 as f64);
+            if json {
+                Event::FlashStart { size }.emit()?;
+            }
+            flashing::download_file(&mut sess, &opts.elf, Format::Elf)?;
+            log::info!("success!");
+            if json {
+                Event::FlashFinish { size }.emit()?;
+            }
+        }
+
+        let sess = Arc::new(Mutex::new(sess));
+        let canary;
+        let vector_catch;
+        let mut restore_vector_catch;
+        {
+            let mut session = sess.lock().unwrap();
+            let mut core = session.core(0)?;
+            core.reset_and_halt(TIMEOUT)?;
+
+            let heap = elf.heap_region(opts.heap_symbols.as_ref());
+            canary = Canary::install(&mut core, &elf, heap.as_ref(), opts.measure_stack)?;
+
+            log::debug!("starting device");
+            let breakpoint_units = core.get_available_breakpoint_units()?;
+            if breakpoint_units == 0 && elf.rtt_addr.is_some() {
+                bail!("RTT not supported on device without HW breakpoints");
+            }
+
+            if let Some(rtt) = elf.rtt_addr {
+                run_to_main_and_block_rtt(&mut core, rtt, elf.main)?;
+            }
+
+            let mut faults = vec![Exception::HARD_FAULT];
+            if opts.catch_faults {
+                faults.extend_from_slice(&Exception::CONFIGURABLE_FAULTS);
+            }
+            vector_catch = VectorCatch::enable(&mut core, &faults, true)?;
+            restore_vector_catch = RestoreVectorCatch {
+                sess: sess.clone(),
+                vector_catch: Some(vector_catch),
+            };
+            if !vector_catch.catches_reset() {
+                log::debug!("the core can't catch resets; watching the RTT control block instead");
+            }
+
+            // fall back to breakpoints on the handlers of the faults the core can't catch
+            let uncaught = faults
+                .into_iter()
+                .filter(|&fault| !vector_catch.catches(fault))
+                .collect::<Vec<_>>();
+            for (fault, handler) in vector_table.fault_handlers(&uncaught) {
+                if fault == Exception::HARD_FAULT {
+                    if breakpoint_units == 0 {
+                        log::warn!("device supports neither vector catch nor HW breakpoints; HardFault will NOT make `probe-run` exit with an error code");
+                    } else {
+                        core.set_hw_breakpoint(handler)?;
+                    }
+                } else if let Err(e) = core.set_hw_breakpoint(handler) {
+                    log::warn!(
+                        "failed to break on the {} handler at 0x{:08x}; it will only be caught once it escalates to a HardFault: {}",
+                        fault,
+                        handler,
+                        e
+                    );
+                }
+            }
+            HaltReason::clear(&mut core)?;
+            core.run()?;
+        }
+
+        let rtt::Channels {
+            up: up_channels,
+            down: down_channels,
+        } = rtt::setup_channels(elf.rtt_addr, sess.clone())?;
+
+        let down_channel_name = opts.rtt_down_channel.as_deref().unwrap_or("0");
+        let down_channel = down_channels.into_iter().find(|channel| {
+            channel.name() == Some(down_channel_name)
+                || channel.number().to_string() == down_channel_name
+        });
+        let mut input = match down_channel {
+            _ if opts.stdin == StdinMode::Off => None,
+            Some(channel) => Some(rtt::Input::spawn(channel, opts.stdin)),
+            None if opts.rtt_down_channel.is_some() => {
+                log::warn!(
+                    "RTT down channel `{}` not found; stdin will not be forwarded",
+                    down_channel_name
+                );
+                None
+            }
+            None => None,
+        };
+
+        // `defmt-rtt` names the channel "defmt", so enable defmt decoding in that case.
+        let use_defmt = up_channels
+            .iter()
+            .any(|channel| channel.name() == Some("defmt"));
+
+        if use_defmt && opts.no_flash {
+            bail!(
+                "attempted to use `--no-flash` and `defmt` logging -- this combination is not allowed. Remove the `--no-flash` flag"
+            );
+        }
+
+        if use_defmt && elf.defmt_table.is_none() {
+            bail!(
+                "\"defmt\" RTT channel is in use, but the firmware binary contains no defmt data"
+            );
+        }
+
+        for route in &opts.rtt_channels {
+            if !up_channels
+                .iter()
+                .any(|channel| rtt::channel_name(channel) == route.channel)
+            {
+                log::warn!("RTT channel `{}` not found", route.channel);
+            }
+        }
+
+        // text channels are prefixed with their name when several of them are printed to stdout
+        let is_routed_to_file = |name: &str| {
+            opts.rtt_channels
+                .iter()
+                .any(|route| route.channel == name && route.file.is_some())
+        };
+        let num_text_channels_on_stdout = up_channels
+            .iter()
+            .filter(|channel| channel.name() != Some("defmt"))
+            .filter(|channel| !is_routed_to_file(&rtt::channel_name(channel)))
+            .count();
+
+        let current_dir = std::env::current_dir()?;
+        let mut channels = vec![];
+        for up_channel in up_channels {
+            let name = rtt::channel_name(&up_channel);
+            let route = opts.rtt_channels.iter().find(|route| route.channel == name);
+            let output = match route.and_then(|route| route.file.as_ref()) {
+                Some(path) => rtt::Output::file(path)?,
+                None if num_text_channels_on_stdout > 1 && !json => {
+                    rtt::Output::stdout(Some(format!("[{}] ", name)))
+                }
+                None => rtt::Output::stdout(None),
+            };
+
+            let decoder = if up_channel.name() == Some("defmt") {
+                Decoder::new(&elf, &current_dir, opts.format)
+            } else {
+                None
+            };
+            let (decoder, output) = match decoder {
+                Some(decoder) if output.is_stdout() => (Some(decoder), None),
+                Some(decoder) => (Some(decoder.with_output(output)), None),
+                None => (None, Some(output)),
+            };
+
+            channels.push(Channel {
+                up_channel,
+                name,
+                decoder,
+                output,
+            });
+        }
+
+        let mut capture = match &opts.rtt_capture {
+            Some(path) if !channels.is_empty() => Some(CaptureWriter::create(
+                path,
+                &ElfIdentity::new(&opts.elf, &bytes),
+            )?),
+            Some(_) => {
+                log::warn!("the firmware doesn't use RTT; no capture will be written");
+                None
+            }
+            None => None,
+        };
+
+        // without the reset catch, resets are detected through the RTT control block
+        let mut rtt_watch = match elf.rtt_addr {
+            Some(rtt_addr) if !vector_catch.catches_reset() => {
+                let mut session = sess.lock().unwrap();
+                Some(RttWatch::new(&mut session.core(0)?, rtt_addr)?)
+            }
+            _ => None,
+        };
+
+        if !json {
+            // Print a separator before the device messages start.
+            eprintln!("{}", "─".repeat(80).dimmed());
+        }
+
+        // wait for breakpoint
+        let mut read_buf = [0; 1024];
+        let mut logs = vec![];
+        let mut output = vec![];
+        let mut channel_output = BTreeMap::new();
+        let mut was_halted = false;
+        let mut semihosting = Semihosting::new(opts.format);
+        if let Some(root) = &opts.semihosting_root {
+            semihosting = semihosting.with_root(root.clone());
+        }
+        let deadline = Deadline::start(opts.timeout);
+        let mut end = LoopEnd::default();
+        // TODO strip prefix from crates-io paths (?)
+        'poll: while !opts.abort.load(Ordering::Relaxed) {
+            if deadline.expired() {
+                end.timed_out = true;
+                break;
+            }
+
+            if let Some(input) = &mut input {
+                if let Err(e) = input.forward() {
+                    log::error!("RTT error: {}", e);
+                    break;
+                }
+            }
+
+            for channel in &mut channels {
+                let num_bytes_read = match channel.up_channel.read(&mut read_buf) {
+                    Ok(n) => n,
+                    Err(e) => {
+                        log::error!("RTT error: {}", e);
+                        break 'poll;
+                    }
+                };
+
+                if num_bytes_read != 0 {
+                    let bytes = &read_buf[..num_bytes_read];
+
+                    if let Some(rtt_watch) = &mut rtt_watch {
+                        rtt_watch.consumed(channel.up_channel.number(), num_bytes_read);
+                    }
+
+                    if let Some(capture) = &mut capture {
+                        capture.write_chunk(&channel.name, bytes)?;
+                    }
+
+                    if let Some(decoder) = &mut channel.decoder {
+                        decoder.received(bytes, &mut logs)?;
+                    } else if let Some(rtt_output) = &mut channel.output {
+                        print_output(rtt_output, &channel.name, bytes, opts.format)?;
+                        output.extend_from_slice(bytes);
+                        channel_output
+                            .entry(channel.name.clone())
+                            .or_insert_with(Vec::new)
+                            .extend_from_slice(bytes);
+                    }
+                }
+            }
+
+            let mut session = sess.lock().unwrap();
+            let mut core = session.core(0)?;
+            let is_halted = core.core_halted()?;
+
+            let is_reset = if vector_catch.catches_reset() {
+                is_halted && reset::at_reset_handler(&mut core, vector_table.reset)?
+            } else if let Some(rtt_watch) = &mut rtt_watch {
+                !is_halted && rtt_watch.check(&mut core)?
+            } else {
+                false
+            };
+
+            if is_reset {
+                report_reset(&logs, &output, opts.on_reset, json)?;
+
+                if opts.on_reset != ResetPolicy::Reattach {
+                    if !is_halted {
+                        core.halt(TIMEOUT)?;
+                    }
+                    end.reset = true;
+                    break;
+                }
+
+                // without the reset catch the firmware is already running again
+                if is_halted {
+                    if let Some(rtt_addr) = elf.rtt_addr {
+                        run_to_main_and_block_rtt(&mut core, rtt_addr, elf.main)?;
+                    }
+                    HaltReason::clear(&mut core)?;
+                    core.run()?;
+                }
+                drop(session);
+
+                if let Some(rtt_addr) = elf.rtt_addr {
+                    let rtt = rtt::setup_channels(Some(rtt_addr), sess.clone())?;
+                    reattach_channels(&mut channels, &mut input, rtt, down_channel_name);
+
+                    if let Some(rtt_watch) = &mut rtt_watch {
+                        let mut session = sess.lock().unwrap();
+                        *rtt_watch = RttWatch::new(&mut session.core(0)?, rtt_addr)?;
+                    }
+                }
+                was_halted = false;
+                continue;
+            }
+
+            // a locked up core neither halts nor makes progress
+            if !is_halted && lockup::is_locked_up(&mut core)? {
+                core.halt(TIMEOUT)?;
+                end.locked_up = true;
+                break;
+            }
+
+            if is_halted && end.semihosting_exit.is_none() {
+                match semihosting.service(&mut core, &mut output)? {
+                    Some(Action::Resume) => {
+                        HaltReason::clear(&mut core)?;
+                        core.run()?;
+                        was_halted = false;
+                        continue;
+                    }
+                    Some(Action::Exit(code)) => end.semihosting_exit = Some(code),
+                    None => {}
+                }
+            }
+
+            if is_halted && was_halted {
+                break;
+            }
+            was_halted = is_halted;
+        }
+
+        let mut sess = sess.lock().unwrap();
+        let mut core = sess.core(0)?;
+
+        end.interrupted = opts.abort.load(Ordering::Relaxed);
+        // read before halting the core ourselves
+        let halt_reason = end.halt_reason(&mut core);
+        if end.interrupted || end.timed_out {
+            // Ctrl-C was pressed or the time is up; stop the microcontroller.
+            core.halt(TIMEOUT)?;
+        }
+
+        let (stack_canary, stack_usage) =
+            check_canary(canary.as_ref(), &mut core, opts.stack_usage_threshold)?;
+
+        let pc = core.read_core_reg(PC)?;
+
+        let debug_frame = elf
+            .debug_frame
+            .ok_or_else(|| anyhow!("`.debug_frame` section not found"))?;
+
+        // NOTE the PC of a locked up core may lie outside of the firmware, in which case the
+        // backtrace is incomplete
+        let backtrace = backtrace::backtrace(
+            &mut core,
+            pc,
+            debug_frame,
+            &elf.elf,
+            vector_table,
+            sp_ram_region.as_ref(),
+            &elf.live_functions,
+            &current_dir,
+            opts.backtrace_locals,
+        )?;
+
+        let fault = if end.locked_up
+            || matches!(
+                backtrace.top_exception,
+                Some(TopException::HardFault { .. })
+            ) {
+            match FaultStatus::read(&mut core, backtrace.interrupted_pc()) {
+                Ok(fault) => Some(fault),
+                Err(e) => {
+                    log::warn!("failed to read the fault status registers: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let registers = if opts.show_registers || end.locked_up {
+            Some(CoreRegisters::read(
+                &mut core,
+                backtrace.exception_frame.as_ref(),
+            )?)
+        } else {
+            None
+        };
+
+        if let Some(path) = &opts.coredump {
+            if let Some(TopException::HardFault { .. }) = backtrace.top_exception {
+                let ranges = ram_regions
+                    .iter()
+                    .map(|region| region.range.clone())
+                    .collect::<Vec<_>>();
+                let mut dump = CoreDump::capture(&mut core, &ranges)?;
+                dump.firmware = Some(opts.elf.canonicalize()?);
+                dump.chip = Some(opts.chip.clone());
+                dump.canary = canary.as_ref().map(Canary::region);
+                dump.write(path)?;
+                log::info!("wrote core dump to {}", path.display());
+            }
+        }
+
+        restore_vector_catch.restore(&mut core)?;
+        core.reset_and_halt(TIMEOUT)?;
+
+        let exit_reason =
+            end.exit_reason(backtrace.top_exception.as_ref(), halt_reason, opts.on_reset);
+
+        Ok(RunOutcome {
+            exit_reason,
+            logs,
+            output,
+            channel_output,
+            backtrace,
+            stack_canary,
+            stack_usage,
+            fault,
+            registers,
+            halt_reason,
+        })
+    }
+}
+
+/// Log frames and text decoded from an RTT capture
+#[derive(Debug)]
+pub struct Replay {
+    /// defmt log frames, in the order they were received
+    pub logs: Vec<LogFrame>,
+    /// Output of the RTT channels that don't carry defmt data, in the order it was received
+    pub output: Vec<u8>,
+    /// Output of each RTT channel that doesn't carry defmt data, keyed by channel name
+    pub channel_output: BTreeMap<String, Vec<u8>>,
+}
+
+/// Decodes an RTT capture of a device running the firmware at `elf_path`
+///
+/// The capture goes through the same decoding as the RTT data of a live run: in the `Human`
+/// format, defmt frames are forwarded to the `log` logger and text is written to stdout.
+pub fn replay(
+    elf_path: &Path,
+    capture: &Capture,
+    format: OutputFormat,
+) -> Result<Replay, anyhow::Error> {
+    let bytes = fs::read(elf_path)?;
+
+    let identity = ElfIdentity::new(elf_path, &bytes);
+    if !identity.same_contents(&capture.elf) {
+        log::warn!(
+            "the capture was recorded with a different firmware; output may be garbled\n  \
+            recorded with: {}\n  decoding with: {}",
+            capture.elf,
+            identity
+        );
+    }
+
+    let elf = ProcessedElf::parse(&bytes, &[])?;
+
+    let use_defmt = capture.chunks.iter().any(|chunk| chunk.channel == "defmt");
+    if use_defmt && elf.defmt_table.is_none() {
+        bail!("\"defmt\" RTT channel is in use, but the firmware binary contains no defmt data");
+    }
+
+    let current_dir = std::env::current_dir()?;
+    let mut decoder = if use_defmt {
+        Decoder::new(&elf, &current_dir, format)
+    } else {
+        None
+    };
+
+    // like in a live run, text channels are prefixed with their name when there are several
+    let text_channels = capture
+        .chunks
+        .iter()
+        .map(|chunk| chunk.channel.as_str())
+        .filter(|&name| name != "defmt")
+        .collect::<BTreeSet<_>>();
+    let prefix = text_channels.len() > 1 && format == OutputFormat::Human;
+    let mut rtt_outputs = text_channels
+        .into_iter()
+        .map(|name| {
+            let prefix = if prefix {
+                Some(format!("[{}] ", name))
+            } else {
+                None
+            };
+            (name, rtt::Output::stdout(prefix))
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    let mut logs = vec![];
+    let mut output = vec![];
+    let mut channel_output = BTreeMap::new();
+    for chunk in &capture.chunks {
+        match (&mut decoder, rtt_outputs.get_mut(chunk.channel.as_str())) {
+            (Some(decoder), None) => decoder.received(&chunk.data, &mut logs)?,
+            (_, Some(rtt_output)) => {
+                print_output(rtt_output, &chunk.channel, &chunk.data, format)?;
+                output.extend_from_slice(&chunk.data);
+                channel_output
+                    .entry(chunk.channel.clone())
+                    .or_insert_with(Vec::new)
+                    .extend_from_slice(&chunk.data);
+            }
+            (None, None) => {}
+        }
+    }
+
+    Ok(Replay {
+        logs,
+        output,
+        channel_output,
+    })
+}
+
+/// Analyzes a core dump of a device running the firmware at `elf_path`, as if the device had
+/// just halted at the end of a run
+///
+/// The backtrace, the stack overflow check and the stack canary check are the same ones done at
+/// the end of [`Runner::run`]; the canary is only checked when the dump records where it was
+/// painted. The RAM layout of the chip named in the dump is used when known;
+/// otherwise the memory regions of the dump stand in for the chip's RAM regions.
+///
+/// With `backtrace_locals` the backtrace includes the arguments and local variables of each frame.
+/// With `show_registers` the outcome includes the registers at the time of the dump.
+pub fn post_mortem(
+    elf_path: &Path,
+    dump: &CoreDump,
+    backtrace_locals: bool,
+    show_registers: bool,
+) -> Result<RunOutcome, anyhow::Error> {
+    let bytes = fs::read(elf_path)?;
+
+    let ram_regions = match dump
+        .chip
+        .as_deref()
+        .map(probe_rs::config::get_target_by_name)
+    {
+        Some(Ok(target)) => target
+            .memory_map
+            .iter()
+            .filter_map(|region| match region {
+                MemoryRegion::Ram(ram) => Some(ram.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>(),
+        chip => {
+            if let Some(Err(e)) = chip {
+                log::warn!(
+                    "unknown chip; using the memory regions of the core dump as RAM: {}",
+                    e
+                );
+            }
+            dump.memory
+                .iter()
+                .map(|(start, data)| RamRegion {
+                    range: *start..*start + data.len() as u32,
+                    is_boot_memory: false,
+                })
+                .collect()
+        }
+    };
+
+    let elf = ProcessedElf::parse(&bytes, &ram_regions)?;
+    let sp_ram_region = elf
+        .stack_ram_region
+        .as_ref()
+        .map(|region| region.range.clone());
+
+    let mut core = dump.target();
+
+    let heap = elf.heap_region(None);
+    // core dumps written by other tools or by older versions of `probe-run` don't record where the
+    // canary was
+    let canary = match &dump.canary {
+        Some(region) => Canary::at(region.clone(), &elf, heap.as_ref()),
+        None => {
+            log::debug!("the core dump doesn't locate the stack canary; skipping the canary check");
+            None
+        }
+    };
+    let (stack_canary, stack_usage) =
+        check_canary(canary.as_ref(), &mut core, DEFAULT_STACK_USAGE_THRESHOLD)?;
+
+    // NOTE `TargetAccess` is not imported in this module because its methods clash with those
+    // of `MemoryInterface`
+    let pc = target::TargetAccess::read_core_reg(&mut core, PC)?;
+
+    let debug_frame = elf
+        .debug_frame
+        .ok_or_else(|| anyhow!("`.debug_frame` section not found"))?;
+
+    let current_dir = std::env::current_dir()?;
+    let backtrace = backtrace::backtrace(
+        &mut core,
+        pc,
+        debug_frame,
+        &elf.elf,
+        &elf.vector_table,
+        sp_ram_region.as_ref(),
+        &elf.live_functions,
+        &current_dir,
+        backtrace_locals,
+    )?;
+
+    let exit_reason = if let Some(TopException::HardFault {
+        exception,
+        stack_overflow,
+    }) = backtrace.top_exception
+    {
+        ExitReason::HardFault {
+            exception,
+            stack_overflow,
+        }
+    } else {
+        ExitReason::Halted
+    };
+
+    // NOTE core dumps usually don't include the System Control Block, which holds these
+    let fault = if let ExitReason::HardFault { .. } = exit_reason {
+        match FaultStatus::read(&mut core, backtrace.interrupted_pc()) {
+            Ok(fault) => Some(fault),
+            Err(e) => {
+                log::debug!(
+                    "the core dump doesn't contain the fault status registers: {}",
+                    e
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let registers = if show_registers {
+        Some(CoreRegisters::read(
+            &mut core,
+            backtrace.exception_frame.as_ref(),
+        )?)
+    } else {
+        None
+    };
+
+    Ok(RunOutcome {
+        exit_reason,
+        logs: vec![],
+        output: vec![],
+        channel_output: BTreeMap::new(),
+        backtrace,
+        stack_canary,
+        stack_usage,
+        fault,
+        registers,
+        halt_reason: None,
+    })
+}
+
+/// An RTT up channel and how its data is processed
+struct Channel<'a> {
+    up_channel: UpChannel,
+    /// See [`rtt::channel_name`]
+    name: String,
+    /// Set if the channel carries defmt data
+    decoder: Option<Decoder<'a>>,
+    /// Set if the channel carries text
+    output: Option<rtt::Output>,
+}
+
+/// Restores the vector catches of a run when dropped, so that the run can't end with them enabled
+///
+/// Left enabled, the catches would halt the firmware on every reset and fault under the next
+/// debugger that attaches to the device.
+struct RestoreVectorCatch {
+    sess: Arc<Mutex<Session>>,
+    /// `None` once restored
+    vector_catch: Option<VectorCatch>,
+}
+
+impl RestoreVectorCatch {
+    /// Restores the vector catches now, reporting any error
+    fn restore(&mut self, core: &mut Core) -> Result<(), anyhow::Error> {
+        match self.vector_catch.take() {
+            Some(vector_catch) => vector_catch.restore(core),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for RestoreVectorCatch {
+    fn drop(&mut self) {
+        let vector_catch = match self.vector_catch.take() {
+            Some(vector_catch) => vector_catch,
+            None => return,
+        };
+        // the lock is poisoned if the run panicked while holding it; leave the device alone then
+        let mut sess = match self.sess.lock() {
+            Ok(sess) => sess,
+            Err(_) => return,
+        };
+
+        let result = sess
+            .core(0)
+            .map_err(anyhow::Error::from)
+            .and_then(|mut core| vector_catch.restore(&mut core));
+        if let Err(e) = result {
+            log::warn!("failed to restore the vector catches: {}", e);
+        }
+    }
+}
+
+/// Runs the device to `main`, by when the firmware has set up its RTT control block at
+/// `rtt_addr`, and makes the channels block when full so that no output is lost
+fn run_to_main_and_block_rtt(
+    core: &mut Core,
+    rtt_addr: u32,
+    main: u32,
+) -> Result<(), anyhow::Error> {
+    core.set_hw_breakpoint(main)?;
+    core.run()?;
+    core.wait_for_core_halted(Duration::from_secs(5))?;
+    const OFFSET: u32 = 44;
+    const FLAG: u32 = 2; // BLOCK_IF_FULL
+    core.write_word_32(rtt_addr + OFFSET, FLAG)?;
+    core.clear_hw_breakpoint(main)?;
+    Ok(())
+}
+
+/// Reports that the device reset in the middle of the run, repeating the last lines it printed
+fn report_reset(
+    logs: &[LogFrame],
+    output: &[u8],
+    policy: ResetPolicy,
+    json: bool,
+) -> Result<(), anyhow::Error> {
+    if json {
+        return Event::TargetReset {
+            policy: policy.to_string(),
+        }
+        .emit();
+    }
+
+    log::error!("target reset unexpectedly");
+    let lines = if logs.is_empty() {
+        let text = String::from_utf8_lossy(output);
+        let lines = text.lines().map(str::to_string).collect::<Vec<_>>();
+        lines[lines.len().saturating_sub(LAST_LINES_ON_RESET)..].to_vec()
+    } else {
+        logs[logs.len().saturating_sub(LAST_LINES_ON_RESET)..]
+            .iter()
+            .map(|frame| frame.message.clone())
+            .collect()
+    };
+    if !lines.is_empty() {
+        log::error!("last output before the reset:");
+        for line in lines {
+            log::error!("  {}", line);
+        }
+    }
+    if policy == ResetPolicy::Reattach {
+        log::info!("re-attaching to RTT");
+    }
+
+    Ok(())
+}
+
+/// Swaps the channels of the run for those of the RTT control block that the firmware set up
+/// again after a reset; channels that are gone are no longer read
+fn reattach_channels(
+    channels: &mut Vec<Channel>,
+    input: &mut Option<rtt::Input>,
+    rtt: rtt::Channels,
+    down_channel_name: &str,
+) {
+    let mut up_channels = rtt.up;
+    for mut channel in mem::take(channels) {
+        match up_channels
+            .iter()
+            .position(|up_channel| rtt::channel_name(up_channel) == channel.name)
+        {
+            Some(index) => {
+                channel.up_channel = up_channels.remove(index);
+                if let Some(decoder) = &mut channel.decoder {
+                    decoder.reset();
+                }
+                channels.push(channel);
+            }
+            None => log::warn!("RTT channel `{}` not found after the reset", channel.name),
+        }
+    }
+
+    if let Some(stdin) = input {
+        let down_channel = rtt.down.into_iter().find(|channel| {
+            channel.name() == Some(down_channel_name)
+                || channel.number().to_string() == down_channel_name
+        });
+        match down_channel {
+            Some(channel) => stdin.reattach(channel),
+            None => {
+                log::warn!(
+                    "RTT down channel `{}` not found after the reset; stdin will not be forwarded",
+                    down_channel_name
+                );
+                *input = None;
+            }
+        }
+    }
+}
+
+/// Prints the output of an RTT channel that doesn't carry defmt data
+fn print_output(
+    output: &mut rtt::Output,
+    channel: &str,
+    bytes: &[u8],
+    format: OutputFormat,
+) -> Result<(), anyhow::Error> {
+    match format {
+        OutputFormat::Json if output.is_stdout() => Event::output(channel, bytes).emit(),
+        _ => output.write(bytes),
+    }
+}
+
+/// Checks the stack canary and reports the stack usage, if it was measured
+///
+/// A warning is logged when the stack usage exceeds `threshold` percent of the available space.
+fn check_canary(
+    canary: Option<&Canary>,
+    core: &mut impl target::TargetAccess,
+    threshold: u8,
+) -> Result<(Option<CanaryState>, Option<StackUsage>), anyhow::Error> {
+    let canary = if let Some(canary) = canary {
+        canary
+    } else {
+        return Ok((None, None));
+    };
+
+    let (state, usage) = canary.check(core)?;
+    if let Some(usage) = usage {
+        log::info!(
+            "peak stack usage: {} of {} bytes; {} bytes ({:.1}%) of headroom left",
+            usage.peak,
+            usage.available,
+            usage.headroom(),
+            100.0 - usage.percent_used(),
+        );
+        if usage.percent_used() > f64::from(threshold) {
+            log::warn!(
+                "the program used {:.1}% of the available stack space, more than the {}% threshold",
+                usage.percent_used(),
+                threshold,
+            );
+        }
+    }
+
+    if let CanaryState::Touched {
+        min_stack_usage,
+        heap_collision,
+    } = state
+    {
+        if heap_collision {
+            log::error!("the stack has collided with the heap; heap data is likely corrupted");
+        }
+        log::warn!(
+            "program has used at least {} bytes of stack space, data segments \
+            may be corrupted due to stack overflow",
+            min_stack_usage,
+        );
+    } else {
+        log::debug!("stack canary intact");
+    }
+    Ok((Some(state), usage))
+}
+
+fn probes_filter(probes: &[DebugProbeInfo], selector: &DebugProbeSelector) -> Vec<DebugProbeInfo> {
+    probes
+        .iter()
+        .filter(|&p| {
+            p.vendor_id == selector.vendor_id
+                && p.product_id == selector.product_id
+                && (selector.serial_number == None || p.serial_number == selector.serial_number)
+        })
+        .map(|p| p.clone())
+        .collect()
+}
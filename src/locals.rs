@@ -0,0 +1,564 @@
+//! Arguments and local variables of backtrace frames, read with `--backtrace-locals`
+//!
+//! The variables of a function are the parameter and variable DIEs nested in its `.debug_info`
+//! entry, including those of the lexical blocks that contain the PC. Their locations are DWARF
+//! expressions, evaluated against the registers of the unwound frame and the target's memory.
+
+use anyhow::{anyhow, bail};
+use gimli::{
+    AttributeValue, DebuggingInformationEntry, Dwarf, EndianRcSlice, EntriesTreeNode,
+    EvaluationResult, Expression, Location, Piece, Reader as _, RunTimeEndian, Unit, UnitOffset,
+    Value,
+};
+
+use crate::backtrace::Variable;
+
+type R = EndianRcSlice<RunTimeEndian>;
+
+/// Structs nested deeper than this, and references to them, are not expanded
+const MAX_DEPTH: usize = 2;
+
+/// Size of a pointer on the target, in bytes
+const POINTER_SIZE: usize = 4;
+
+/// Registers and memory of an unwound frame
+pub(crate) trait FrameState {
+    /// Value of register `reg`, using the DWARF register numbering
+    fn register(&mut self, reg: u16) -> Result<u32, anyhow::Error>;
+
+    fn read_memory(&mut self, address: u32, data: &mut [u8]) -> Result<(), anyhow::Error>;
+}
+
+/// Reads the variables of one function of a frame
+pub(crate) struct Frame<'a, S: FrameState> {
+    pub(crate) dwarf: &'a Dwarf<R>,
+    pub(crate) unit: &'a Unit<R>,
+    /// Address used to look up location lists and lexical blocks
+    pub(crate) pc: u32,
+    /// Canonical frame address of the frame
+    pub(crate) cfa: u32,
+    pub(crate) state: &'a mut S,
+}
+
+impl<'a, S: FrameState> Frame<'a, S> {
+    /// The arguments and local variables of `function`, a subprogram or inlined subroutine DIE
+    ///
+    /// `subprogram` is the DIE of the physical function, which defines the frame base.
+    pub(crate) fn variables(
+        &mut self,
+        function: UnitOffset,
+        subprogram: UnitOffset,
+    ) -> Result<Vec<Variable>, anyhow::Error> {
+        let frame_base = match self.frame_base(subprogram) {
+            Ok(frame_base) => frame_base,
+            Err(e) => {
+                log::debug!("failed to compute the frame base: {:#}", e);
+                None
+            }
+        };
+
+        let mut tree = self.unit.entries_tree(Some(function))?;
+        let mut variables = vec![];
+        self.collect(tree.root()?, frame_base, &mut variables)?;
+        Ok(variables)
+    }
+
+    fn frame_base(&mut self, subprogram: UnitOffset) -> Result<Option<u32>, anyhow::Error> {
+        let entry = self.unit.entry(subprogram)?;
+        let expr = match entry.attr_value(gimli::DW_AT_frame_base)? {
+            Some(AttributeValue::Exprloc(expr)) => expr,
+            _ => return Ok(None),
+        };
+
+        let pieces = self.evaluate(expr, None)?;
+        match pieces.first().map(|piece| &piece.location) {
+            Some(Location::Register { register }) => Ok(Some(self.state.register(register.0)?)),
+            Some(Location::Address { address }) => Ok(Some(*address as u32)),
+            _ => bail!("unsupported frame base"),
+        }
+    }
+
+    fn collect(
+        &mut self,
+        node: EntriesTreeNode<R>,
+        frame_base: Option<u32>,
+        variables: &mut Vec<Variable>,
+    ) -> Result<(), anyhow::Error> {
+        let mut children = node.children();
+        while let Some(child) = children.next()? {
+            match child.entry().tag() {
+                gimli::DW_TAG_formal_parameter | gimli::DW_TAG_variable => {
+                    if let Some(variable) = self.variable(child.entry(), frame_base)? {
+                        variables.push(variable);
+                    }
+                }
+
+                gimli::DW_TAG_lexical_block if self.contains_pc(child.entry())? => {
+                    self.collect(child, frame_base, variables)?;
+                }
+
+                // nested inlined subroutines are frames of their own
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn contains_pc(&self, entry: &DebuggingInformationEntry<R>) -> Result<bool, anyhow::Error> {
+        let pc = u64::from(self.pc);
+        let mut ranges = self.dwarf.die_ranges(self.unit, entry)?;
+        let mut has_ranges = false;
+        while let Some(range) = ranges.next()? {
+            if range.begin <= pc && pc < range.end {
+                return Ok(true);
+            }
+            has_ranges = true;
+        }
+
+        // a block without addresses applies to the whole function
+        Ok(!has_ranges)
+    }
+
+    fn variable(
+        &mut self,
+        entry: &DebuggingInformationEntry<R>,
+        frame_base: Option<u32>,
+    ) -> Result<Option<Variable>, anyhow::Error> {
+        let name = match self.attr(entry, gimli::DW_AT_name)? {
+            Some(name) => self
+                .dwarf
+                .attr_string(self.unit, name)?
+                .to_string_lossy()?
+                .into_owned(),
+            // e.g. compiler generated temporaries
+            None => return Ok(None),
+        };
+        let value = match self.location(entry)? {
+            Some(expr) => {
+                let ty = match self.attr(entry, gimli::DW_AT_type)? {
+                    Some(AttributeValue::UnitRef(ty)) => Some(ty),
+                    _ => None,
+                };
+
+                match self.read(expr, ty, frame_base) {
+                    Ok(value) => value,
+                    Err(e) => format!("<{:#}>", e),
+                }
+            }
+            None => "<optimized out>".to_string(),
+        };
+
+        Ok(Some(Variable { name, value }))
+    }
+
+    /// The value of attribute `name`, looked up in the abstract origin of inlined DIEs if needed
+    fn attr(
+        &self,
+        entry: &DebuggingInformationEntry<R>,
+        name: gimli::DwAt,
+    ) -> Result<Option<AttributeValue<R>>, anyhow::Error> {
+        if let Some(value) = entry.attr_value(name)? {
+            return Ok(Some(value));
+        }
+
+        match entry.attr_value(gimli::DW_AT_abstract_origin)? {
+            Some(AttributeValue::UnitRef(origin)) => {
+                Ok(self.unit.entry(origin)?.attr_value(name)?)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The location expression of a variable that applies at the PC
+    fn location(
+        &self,
+        entry: &DebuggingInformationEntry<R>,
+    ) -> Result<Option<Expression<R>>, anyhow::Error> {
+        let value = match entry.attr_value(gimli::DW_AT_location)? {
+            Some(AttributeValue::Exprloc(expr)) => return Ok(Some(expr)),
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        let offset = match self.dwarf.attr_locations_offset(self.unit, value)? {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+
+        let pc = u64::from(self.pc);
+        let mut locations = self.dwarf.locations(self.unit, offset)?;
+        while let Some(location) = locations.next()? {
+            if location.range.begin <= pc && pc < location.range.end {
+                return Ok(Some(location.data));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Reads and formats the value of type `ty` found at the location `expr`
+    fn read(
+        &mut self,
+        expr: Expression<R>,
+        ty: Option<UnitOffset>,
+        frame_base: Option<u32>,
+    ) -> Result<String, anyhow::Error> {
+        let ty = ty.ok_or_else(|| anyhow!("unknown type"))?;
+        let size = self
+            .value_size(ty, 0)?
+            .ok_or_else(|| anyhow!("unknown size"))?;
+
+        let pieces = self.evaluate(expr, frame_base)?;
+        let bytes = self.piece_bytes(&pieces, size)?;
+        self.format(ty, &bytes, 0)
+    }
+
+    fn evaluate(
+        &mut self,
+        expr: Expression<R>,
+        frame_base: Option<u32>,
+    ) -> Result<Vec<Piece<R>>, anyhow::Error> {
+        let mut eval = expr.evaluation(self.unit.encoding());
+
+        let mut result = eval.evaluate()?;
+        loop {
+            result = match result {
+                EvaluationResult::Complete => break,
+
+                EvaluationResult::RequiresMemory { address, size, .. } => {
+                    let mut bytes = [0; 8];
+                    let size = usize::from(size).min(bytes.len());
+                    self.state.read_memory(address as u32, &mut bytes[..size])?;
+                    eval.resume_with_memory(Value::Generic(u64::from_le_bytes(bytes)))?
+                }
+
+                EvaluationResult::RequiresRegister { register, .. } => {
+                    let value = self.state.register(register.0)?;
+                    eval.resume_with_register(Value::Generic(value.into()))?
+                }
+
+                EvaluationResult::RequiresFrameBase => {
+                    let frame_base = frame_base.ok_or_else(|| anyhow!("unknown frame base"))?;
+                    eval.resume_with_frame_base(frame_base.into())?
+                }
+
+                EvaluationResult::RequiresCallFrameCfa => {
+                    eval.resume_with_call_frame_cfa(self.cfa.into())?
+                }
+
+                // statically linked binary -- there are no relative addresses
+                EvaluationResult::RequiresRelocatedAddress(address) => {
+                    eval.resume_with_relocated_address(address)?
+                }
+
+                other => bail!("unsupported DWARF expression operation ({:?})", other),
+            };
+        }
+
+        Ok(eval.result())
+    }
+
+    /// Gathers the bytes of a value of `size` bytes from its pieces
+    fn piece_bytes(&mut self, pieces: &[Piece<R>], size: usize) -> Result<Vec<u8>, anyhow::Error> {
+        let mut bytes = vec![];
+        for piece in pieces {
+            let len = piece
+                .size_in_bits
+                .map(|bits| (bits / 8) as usize)
+                .unwrap_or(size);
+
+            let mut piece_bytes = match &piece.location {
+                Location::Empty => bail!("optimized out"),
+                Location::Register { register } => {
+                    self.state.register(register.0)?.to_le_bytes().to_vec()
+                }
+                Location::Address { address } => {
+                    let mut data = vec![0; len];
+                    if len != 0 {
+                        self.state.read_memory(*address as u32, &mut data)?;
+                    }
+                    data
+                }
+                Location::Value { value } => value.to_u64(u64::MAX)?.to_le_bytes().to_vec(),
+                Location::Bytes { value } => value.to_slice()?.into_owned(),
+                Location::ImplicitPointer { .. } => bail!("implicit pointer"),
+            };
+            piece_bytes.resize(len, 0);
+            bytes.extend(piece_bytes);
+        }
+
+        bytes.resize(size, 0);
+        Ok(bytes)
+    }
+
+    /// Follows typedefs and qualifiers; returns the offset and DIE of the underlying type
+    fn peel(
+        &self,
+        mut ty: UnitOffset,
+    ) -> Result<(UnitOffset, DebuggingInformationEntry<'a, 'a, R>), anyhow::Error> {
+        let unit = self.unit;
+        loop {
+            let entry = unit.entry(ty)?;
+            match entry.tag() {
+                gimli::DW_TAG_typedef
+                | gimli::DW_TAG_const_type
+                | gimli::DW_TAG_volatile_type
+                | gimli::DW_TAG_restrict_type
+                | gimli::DW_TAG_atomic_type => match entry.attr_value(gimli::DW_AT_type)? {
+                    Some(AttributeValue::UnitRef(inner)) => ty = inner,
+                    _ => return Ok((ty, entry)),
+                },
+                _ => return Ok((ty, entry)),
+            }
+        }
+    }
+
+    fn type_size(&self, ty: UnitOffset) -> Result<Option<usize>, anyhow::Error> {
+        let (_, entry) = self.peel(ty)?;
+        if let Some(size) = entry
+            .attr_value(gimli::DW_AT_byte_size)?
+            .and_then(|size| size.udata_value())
+        {
+            return Ok(Some(size as usize));
+        }
+
+        Ok(match entry.tag() {
+            gimli::DW_TAG_pointer_type
+            | gimli::DW_TAG_reference_type
+            | gimli::DW_TAG_rvalue_reference_type => Some(POINTER_SIZE),
+            _ => None,
+        })
+    }
+
+    /// Number of leading bytes of a value of type `ty` that formatting it at `depth` looks at
+    ///
+    /// Arrays and structs that are elided as `[..]` or `Name { .. }` need none of their bytes, so
+    /// e.g. a large buffer isn't read from the target only to be skipped.
+    fn value_size(&self, ty: UnitOffset, depth: usize) -> Result<Option<usize>, anyhow::Error> {
+        let (ty, entry) = self.peel(ty)?;
+        match entry.tag() {
+            gimli::DW_TAG_array_type => Ok(Some(0)),
+
+            gimli::DW_TAG_structure_type | gimli::DW_TAG_class_type | gimli::DW_TAG_union_type => {
+                if depth >= MAX_DEPTH {
+                    return Ok(Some(0));
+                }
+
+                let mut size = 0;
+                let mut tree = self.unit.entries_tree(Some(ty))?;
+                let root = tree.root()?;
+                let mut children = root.children();
+                while let Some(child) = children.next()? {
+                    let member = child.entry();
+                    match member.tag() {
+                        gimli::DW_TAG_member => {}
+                        gimli::DW_TAG_variant_part => return Ok(Some(0)),
+                        _ => continue,
+                    }
+
+                    let offset = member
+                        .attr_value(gimli::DW_AT_data_member_location)?
+                        .and_then(|offset| offset.udata_value())
+                        .unwrap_or(0) as usize;
+                    if let Some(AttributeValue::UnitRef(field_ty)) =
+                        member.attr_value(gimli::DW_AT_type)?
+                    {
+                        let field_size = self.value_size(field_ty, depth + 1)?.unwrap_or(0);
+                        size = size.max(offset + field_size);
+                    }
+                }
+                Ok(Some(size))
+            }
+
+            _ => self.type_size(ty),
+        }
+    }
+
+    fn type_name(&self, entry: &DebuggingInformationEntry<R>) -> Result<String, anyhow::Error> {
+        Ok(match entry.attr_value(gimli::DW_AT_name)? {
+            Some(name) => self
+                .dwarf
+                .attr_string(self.unit, name)?
+                .to_string_lossy()?
+                .into_owned(),
+            None => String::new(),
+        })
+    }
+
+    /// Formats `bytes` as a value of type `ty`
+    fn format(
+        &mut self,
+        ty: UnitOffset,
+        bytes: &[u8],
+        depth: usize,
+    ) -> Result<String, anyhow::Error> {
+        let (ty, entry) = self.peel(ty)?;
+        match entry.tag() {
+            gimli::DW_TAG_base_type => format_base(&entry, bytes),
+
+            gimli::DW_TAG_pointer_type
+            | gimli::DW_TAG_reference_type
+            | gimli::DW_TAG_rvalue_reference_type => {
+                let address = u32::from_le_bytes(word(bytes));
+                let pointee = match entry.attr_value(gimli::DW_AT_type)? {
+                    Some(AttributeValue::UnitRef(pointee)) => Some(pointee),
+                    _ => None,
+                };
+
+                // Rust references are pointer types named `&T` or `&mut T`
+                let is_reference = entry.tag() != gimli::DW_TAG_pointer_type
+                    || self.type_name(&entry)?.starts_with('&');
+                if let (true, Some(pointee)) = (is_reference && depth < MAX_DEPTH, pointee) {
+                    if let Some(size) = self.value_size(pointee, depth + 1)? {
+                        let mut data = vec![0; size];
+                        if size == 0 || self.state.read_memory(address, &mut data).is_ok() {
+                            return Ok(format!("&{}", self.format(pointee, &data, depth + 1)?));
+                        }
+                    }
+                }
+
+                Ok(format!("0x{:08x}", address))
+            }
+
+            gimli::DW_TAG_structure_type | gimli::DW_TAG_class_type | gimli::DW_TAG_union_type => {
+                self.format_struct(ty, &entry, bytes, depth)
+            }
+
+            gimli::DW_TAG_enumeration_type => {
+                let value = u64::from_le_bytes(long(bytes));
+                let mut tree = self.unit.entries_tree(Some(ty))?;
+                let root = tree.root()?;
+                let mut children = root.children();
+                while let Some(child) = children.next()? {
+                    let enumerator = child.entry();
+                    let matches = enumerator
+                        .attr_value(gimli::DW_AT_const_value)?
+                        .map(|constant| {
+                            constant.udata_value() == Some(value)
+                                || constant.sdata_value() == Some(value as i64)
+                        })
+                        .unwrap_or(false);
+                    if matches {
+                        return self.type_name(enumerator);
+                    }
+                }
+                Ok(value.to_string())
+            }
+
+            gimli::DW_TAG_array_type => Ok("[..]".to_string()),
+
+            _ => bail!("unsupported type"),
+        }
+    }
+
+    /// Formats a struct like `Debug` does: `Name { field: value, .. }`
+    fn format_struct(
+        &mut self,
+        ty: UnitOffset,
+        entry: &DebuggingInformationEntry<R>,
+        bytes: &[u8],
+        depth: usize,
+    ) -> Result<String, anyhow::Error> {
+        let name = self.type_name(entry)?;
+        if depth >= MAX_DEPTH {
+            return Ok(format!("{} {{ .. }}", name));
+        }
+
+        let mut fields = vec![];
+        let mut tree = self.unit.entries_tree(Some(ty))?;
+        let root = tree.root()?;
+        let mut children = root.children();
+        while let Some(child) = children.next()? {
+            let member = child.entry();
+            match member.tag() {
+                gimli::DW_TAG_member => {}
+                // e.g. the variants of Rust enums; not supported
+                gimli::DW_TAG_variant_part => return Ok(format!("{} {{ .. }}", name)),
+                _ => continue,
+            }
+
+            let field_name = self.type_name(member)?;
+            let offset = member
+                .attr_value(gimli::DW_AT_data_member_location)?
+                .and_then(|offset| offset.udata_value())
+                .unwrap_or(0) as usize;
+            let field_ty = match member.attr_value(gimli::DW_AT_type)? {
+                Some(AttributeValue::UnitRef(field_ty)) => field_ty,
+                _ => continue,
+            };
+            let size = self.value_size(field_ty, depth + 1)?.unwrap_or(0);
+
+            let value = match bytes.get(offset..offset + size) {
+                Some(field_bytes) => self
+                    .format(field_ty, field_bytes, depth + 1)
+                    .unwrap_or_else(|e| format!("<{:#}>", e)),
+                None => "?".to_string(),
+            };
+            fields.push(format!("{}: {}", field_name, value));
+        }
+
+        Ok(if fields.is_empty() {
+            name
+        } else {
+            format!("{} {{ {} }}", name, fields.join(", "))
+        })
+    }
+}
+
+fn format_base(
+    entry: &DebuggingInformationEntry<R>,
+    bytes: &[u8],
+) -> Result<String, anyhow::Error> {
+    if bytes.is_empty() {
+        // e.g. `()`
+        return Ok("()".to_string());
+    }
+
+    let encoding = match entry.attr_value(gimli::DW_AT_encoding)? {
+        Some(AttributeValue::Encoding(encoding)) => encoding,
+        _ => bail!("unknown encoding"),
+    };
+
+    let unsigned = u64::from_le_bytes(long(bytes));
+    Ok(match encoding {
+        gimli::DW_ATE_boolean => (unsigned != 0).to_string(),
+
+        gimli::DW_ATE_signed | gimli::DW_ATE_signed_char => {
+            // sign-extend
+            let shift = 64 - 8 * bytes.len().min(8) as u32;
+            (((unsigned << shift) as i64) >> shift).to_string()
+        }
+
+        gimli::DW_ATE_unsigned | gimli::DW_ATE_unsigned_char => unsigned.to_string(),
+
+        gimli::DW_ATE_float if bytes.len() == 4 => {
+            f32::from_bits(u32::from_le_bytes(word(bytes))).to_string()
+        }
+        gimli::DW_ATE_float if bytes.len() == 8 => f64::from_bits(unsigned).to_string(),
+
+        // Rust's `char`
+        gimli::DW_ATE_UTF => match std::char::from_u32(unsigned as u32) {
+            Some(c) => format!("{:?}", c),
+            None => format!("0x{:x}", unsigned),
+        },
+
+        _ => bail!("unsupported encoding {}", encoding),
+    })
+}
+
+/// The first 4 bytes of `bytes`, zero padded
+fn word(bytes: &[u8]) -> [u8; 4] {
+    let mut word = [0; 4];
+    let len = bytes.len().min(word.len());
+    word[..len].copy_from_slice(&bytes[..len]);
+    word
+}
+
+/// The first 8 bytes of `bytes`, zero padded
+fn long(bytes: &[u8]) -> [u8; 8] {
+    let mut long = [0; 8];
+    let len = bytes.len().min(long.len());
+    long[..len].copy_from_slice(&bytes[..len]);
+    long
+}
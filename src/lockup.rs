@@ -0,0 +1,22 @@
+//! Detecting a core that locked up
+//!
+//! A core locks up when it can't handle a fault, e.g. one raised by the HardFault handler itself.
+//! A locked up core neither halts nor makes progress; only a reset or a halt request gets it out
+//! of that state.
+
+use crate::target::TargetAccess;
+
+/// Debug Halting Control and Status Register
+const DHCSR: u32 = 0xE000_EDF0;
+
+// DHCSR bits
+/// The core is halted in Debug state
+const S_HALT: u32 = 1 << 17;
+/// The core is locked up by an unrecoverable exception
+const S_LOCKUP: u32 = 1 << 19;
+
+/// Whether `core` is locked up; a halted core is not
+pub fn is_locked_up(core: &mut impl TargetAccess) -> Result<bool, anyhow::Error> {
+    let dhcsr = core.read_word_32(DHCSR)?;
+    Ok(dhcsr & S_LOCKUP != 0 && dhcsr & S_HALT == 0)
+}
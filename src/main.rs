@@ -1,44 +1,21 @@
-use core::{
-    cmp,
-    convert::TryInto,
-    mem,
-    sync::atomic::{AtomicBool, Ordering},
+use std::{env, ffi::OsStr, path::PathBuf, process};
+
+use anyhow::anyhow;
+use probe_rs::{DebugProbeInfo, Probe};
+use probe_run::{
+    backtrace,
+    capture::Capture,
+    config::{
+        self, BacktracePolicy, ChannelRoute, HeapSymbols, Layer, Options, OutputFormat,
+        ResetPolicy, Settings, Source, StdinMode, Timeout,
+    },
+    coredump::CoreDump,
+    event::Event,
+    exception::Exception,
+    ExitReason, RunOutcome, Runner,
 };
-use std::{
-    borrow::Cow,
-    collections::{btree_map, BTreeMap, HashSet},
-    fs,
-    io::{self, Write as _},
-    path::{Path, PathBuf},
-    process,
-    sync::{Arc, Mutex},
-    time::Duration,
-};
-
-use addr2line::fallible_iterator::FallibleIterator as _;
-use anyhow::{anyhow, bail, Context};
-use arrayref::array_ref;
-use colored::Colorize as _;
-use gimli::{
-    read::{CfaRule, DebugFrame, UnwindSection},
-    BaseAddresses, EndianSlice, LittleEndian, RegisterRule, UninitializedUnwindContext,
-};
-use object::{
-    read::{File as ElfFile, Object as _, ObjectSection as _},
-    ObjectSegment, SymbolSection,
-};
-use probe_rs::config::{MemoryRegion, RamRegion};
-use probe_rs::{
-    flashing::{self, Format},
-    Core, CoreRegisterAddress, DebugProbeInfo, DebugProbeSelector, MemoryInterface, Probe, Session,
-};
-use probe_rs_rtt::{Rtt, ScanRegion, UpChannel};
 use structopt::StructOpt;
 
-const TIMEOUT: Duration = Duration::from_secs(1);
-const STACK_CANARY: u8 = 0xAA;
-const THUMB_BIT: u32 = 1;
-
 fn main() -> Result<(), anyhow::Error> {
     notmain().map(|code| process::exit(code))
 }
@@ -75,7 +52,7 @@ struct Opts {
     defmt: bool,
 
     /// The chip to program.
-    #[structopt(long, required_unless_one(&["list-chips", "list-probes", "version"]), env = "PROBE_RUN_CHIP")]
+    #[structopt(long, env = "PROBE_RUN_CHIP")]
     chip: Option<String>,
 
     /// The probe to use (eg. VID:PID or VID:PID:Serial).
@@ -91,775 +68,397 @@ struct Opts {
     elf: Option<PathBuf>,
 
     /// Skip writing the application binary to flash.
-    #[structopt(long, conflicts_with = "defmt")]
+    #[structopt(long, conflicts_with = "defmt", overrides_with = "flash")]
     no_flash: bool,
 
+    /// Write the application binary to flash, even if a configuration file sets `no-flash`.
+    #[structopt(long, overrides_with = "no_flash")]
+    flash: bool,
+
     /// Connect to device when NRST is pressed.
-    #[structopt(long)]
+    #[structopt(long, overrides_with = "no_connect_under_reset")]
     connect_under_reset: bool,
 
-    /// Enable more verbose logging.
-    #[structopt(short, long)]
-    verbose: bool,
-
-    /// Prints version information
-    #[structopt(short = "V", long)]
-    version: bool,
-}
-
-fn notmain() -> Result<i32, anyhow::Error> {
-    let opts: Opts = Opts::from_args();
-    defmt_logger::init(opts.verbose);
+    /// Don't connect under reset, even if a configuration file sets `connect-under-reset`.
+    #[structopt(long, overrides_with = "connect_under_reset")]
+    no_connect_under_reset: bool,
 
-    if opts.version {
-        return print_version();
-    }
+    /// Write an ELF core dump to this file if the firmware ends in a HardFault.
+    #[structopt(long, parse(from_os_str))]
+    coredump: Option<PathBuf>,
 
-    if opts.list_probes {
-        return print_probes(Probe::list_all());
-    }
+    /// Show the arguments and local variables of each frame in the backtrace.
+    #[structopt(long)]
+    backtrace_locals: bool,
 
-    if opts.list_chips {
-        return print_chips();
-    }
+    /// Print the core registers and the exception frame at the time of halt.
+    #[structopt(long)]
+    show_registers: bool,
 
-    let elf_path = opts.elf.as_deref().unwrap();
-    let chip = opts.chip.as_deref().unwrap();
-    let bytes = fs::read(elf_path)?;
-    let elf = ElfFile::parse(&bytes)?;
-
-    let target = probe_rs::config::get_target_by_name(chip)?;
-
-    let mut ram_region = None;
-    for region in &target.memory_map {
-        match region {
-            MemoryRegion::Ram(ram) => {
-                if let Some(old) = &ram_region {
-                    log::debug!("multiple RAM regions found ({:?} and {:?}), stack canary will not be available", old, ram);
-                } else {
-                    ram_region = Some(ram.clone());
-                }
-            }
-            _ => {}
-        }
-    }
+    /// Record the raw bytes of the RTT channel to this file, for `probe-run decode`.
+    #[structopt(long, parse(from_os_str))]
+    rtt_capture: Option<PathBuf>,
 
-    if let Some(ram) = &ram_region {
-        log::debug!(
-            "RAM region: 0x{:08X}-0x{:08X}",
-            ram.range.start,
-            ram.range.end - 1
-        );
-    }
+    /// Send the output of an RTT up channel, given by name or number, to a file (`-` for stdout).
+    #[structopt(long = "rtt-channel", name = "CHANNEL=FILE", number_of_values = 1)]
+    rtt_channels: Vec<ChannelRoute>,
 
-    // NOTE we want to raise the linking error before calling `defmt_elf2table::parse`
-    let text = elf
-        .section_by_name(".text")
-        .map(|section| section.index())
-        .ok_or_else(|| {
-            anyhow!(
-                "`.text` section is missing, please make sure that the linker script was passed \
-                to the linker (check `.cargo/config.toml` and the `RUSTFLAGS` variable)"
-            )
-        })?;
-
-    let (mut table, locs) = {
-        let table = defmt_elf2table::parse(&bytes)?;
-
-        let locs = if let Some(table) = table.as_ref() {
-            let locs = defmt_elf2table::get_locations(&bytes, table)?;
-
-            if !table.is_empty() && locs.is_empty() {
-                log::warn!("insufficient DWARF info; compile your program with `debug = 2` to enable location info");
-                None
-            } else {
-                if table.indices().all(|idx| locs.contains_key(&(idx as u64))) {
-                    Some(locs)
-                } else {
-                    log::warn!(
-                        "(BUG) location info is incomplete; it will be omitted from the output"
-                    );
-                    None
-                }
-            }
-        } else {
-            None
-        };
+    /// How to forward stdin to the device: off, line or raw.
+    ///
+    /// On platforms other than unix, a read of stdin that's still blocked when the run ends isn't
+    /// waited for; the reader thread is left to finish with the process.
+    #[structopt(long, default_value = "off", possible_values(&["line", "raw", "off"]))]
+    stdin: StdinMode,
 
-        (table, locs)
-    };
+    /// The RTT down channel, given by name or number, that receives stdin.
+    #[structopt(long)]
+    rtt_down_channel: Option<String>,
 
-    // sections used in cortex-m-rt
-    // NOTE we won't load `.uninit` so it is not included here
-    // NOTE we don't load `.bss` because the app (cortex-m-rt) will zero it
-    let candidates = [".vector_table", ".text", ".rodata", ".data"];
-
-    let mut highest_ram_addr_in_use = 0;
-    let mut debug_frame = None;
-    let mut sections = vec![];
-    let mut vector_table = None;
-    for sect in elf.sections() {
-        // If this section resides in RAM, track the highest RAM address in use.
-        if let Some(ram) = &ram_region {
-            if sect.size() != 0 {
-                let last_addr = sect.address() + sect.size() - 1;
-                let last_addr = last_addr.try_into()?;
-                if ram.range.contains(&last_addr) {
-                    log::debug!(
-                        "section `{}` is in RAM at 0x{:08X}-0x{:08X}",
-                        sect.name().unwrap_or("<unknown>"),
-                        sect.address(),
-                        last_addr,
-                    );
-                    highest_ram_addr_in_use = highest_ram_addr_in_use.max(last_addr);
-                }
-            }
-        }
+    /// Directory in which the firmware can open files through semihosting.
+    #[structopt(long, parse(from_os_str))]
+    semihosting_root: Option<PathBuf>,
 
-        if let Ok(name) = sect.name() {
-            if name == ".debug_frame" {
-                debug_frame = Some(sect.data()?);
-                continue;
-            }
+    /// When to print the stack backtrace: auto, always or never.
+    #[structopt(long, possible_values(&["auto", "always", "never"]))]
+    backtrace: Option<BacktracePolicy>,
 
-            let size = sect.size();
-            // skip empty sections
-            if candidates.contains(&name) && size != 0 {
-                let start = sect.address();
-                if size % 4 != 0 || start % 4 != 0 {
-                    // we could support unaligned sections but let's not do that now
-                    bail!("section `{}` is not 4-byte aligned", name);
-                }
-
-                let start = start.try_into()?;
-                let data = sect
-                    .data()?
-                    .chunks_exact(4)
-                    .map(|chunk| u32::from_le_bytes(*array_ref!(chunk, 0, 4)))
-                    .collect::<Vec<_>>();
-
-                if name == ".vector_table" {
-                    vector_table = Some(VectorTable {
-                        location: start,
-                        // Initial stack pointer
-                        initial_sp: data[0],
-                        reset: data[1],
-                        hard_fault: data[3],
-                    });
-                }
-
-                sections.push(Section { start, data });
-            }
-        }
-    }
+    /// Output format: human or json.
+    #[structopt(long, possible_values(&["human", "json"]))]
+    format: Option<OutputFormat>,
 
-    let live_functions = elf
-        .symbol_map()
-        .symbols()
-        .iter()
-        .filter_map(|sym| {
-            if sym.section() == SymbolSection::Section(text) {
-                sym.name()
-            } else {
-                None
-            }
-        })
-        .collect::<HashSet<_>>();
-
-    let (rtt_addr, uses_heap, main) = get_rtt_heap_main_from(&elf)?;
-
-    let vector_table = vector_table.ok_or_else(|| anyhow!("`.vector_table` section is missing"))?;
-    log::debug!("vector table: {:x?}", vector_table);
-    let sp_ram_region = target
-        .memory_map
-        .iter()
-        .filter_map(|region| match region {
-            MemoryRegion::Ram(region) => {
-                // NOTE stack is full descending; meaning the stack pointer can be `ORIGIN(RAM) +
-                // LENGTH(RAM)`
-                let range = region.range.start..=region.range.end;
-                if range.contains(&vector_table.initial_sp) {
-                    Some(region)
-                } else {
-                    None
-                }
-            }
-            _ => None,
-        })
-        .next()
-        .cloned();
-
-    let probes = Probe::list_all();
-    let probes = if let Some(probe_opt) = opts.probe.as_deref() {
-        let selector = probe_opt.try_into()?;
-        probes_filter(&probes, &selector)
-    } else {
-        probes
-    };
-    if probes.is_empty() {
-        bail!("no probe was found")
-    }
-    log::debug!("found {} probes", probes.len());
-    if probes.len() > 1 {
-        let _ = print_probes(probes);
-        bail!("more than one probe found; use --probe to specify which one to use");
-    }
-    let mut probe = probes[0].open()?;
-    log::debug!("opened probe");
+    /// Halt the device and exit with code 124 if it runs longer than this (e.g. 90s or 5m).
+    #[structopt(long)]
+    timeout: Option<Timeout>,
 
-    if let Some(speed) = opts.speed {
-        probe.set_speed(speed)?;
-    }
+    /// Paint all of the free stack space to measure the peak stack usage.
+    #[structopt(long, overrides_with = "no_measure_stack")]
+    measure_stack: bool,
 
-    let mut sess = if opts.connect_under_reset {
-        probe.attach_under_reset(target)?
-    } else {
-        probe.attach(target)?
-    };
-    log::debug!("started session");
+    /// Don't measure the stack usage, even if a configuration file sets `measure-stack`.
+    #[structopt(long, overrides_with = "measure_stack")]
+    no_measure_stack: bool,
 
-    if opts.no_flash {
-        log::info!("skipped flashing");
-    } else {
-        // program lives in Flash
-        let size = program_size_of(&elf);
-        log::info!("flashing program ({:.02} KiB)", size as f64 / 1024 as f64);
-        flashing::download_file(&mut sess, elf_path, Format::Elf)?;
-        log::info!("success!");
-    }
+    /// Warn when the measured stack usage exceeds this percentage of the stack (default: 90).
+    #[structopt(long, name = "PERCENT")]
+    stack_usage_threshold: Option<u8>,
 
-    let mut canary = None;
-    {
-        let mut core = sess.core(0)?;
-        core.reset_and_halt(TIMEOUT)?;
-
-        // Decide if and where to place the stack canary.
-        if let Some(ram) = &ram_region {
-            // Initial SP must be past canary location.
-            let initial_sp_makes_sense = ram.range.contains(&(vector_table.initial_sp - 1))
-                && highest_ram_addr_in_use < vector_table.initial_sp;
-            if highest_ram_addr_in_use != 0 && !uses_heap && initial_sp_makes_sense {
-                let stack_available = vector_table.initial_sp - highest_ram_addr_in_use - 1;
-
-                // We consider >90% stack usage a potential stack overflow, but don't go beyond 1 kb
-                // since filling a lot of RAM is slow (and 1 kb should be "good enough" for what
-                // we're doing).
-                let canary_size = cmp::min(stack_available / 10, 1024);
-
-                log::debug!(
-                    "{} bytes of stack available (0x{:08X}-0x{:08X}), using {} byte canary to detect overflows",
-                    stack_available,
-                    highest_ram_addr_in_use + 1,
-                    vector_table.initial_sp,
-                    canary_size,
-                );
-
-                // Canary starts right after `highest_ram_addr_in_use`.
-                let canary_addr = highest_ram_addr_in_use + 1;
-                canary = Some((canary_addr, canary_size));
-                let data = vec![STACK_CANARY; canary_size as usize];
-                core.write_8(canary_addr, &data)?;
-            }
-        }
-
-        log::debug!("starting device");
-        if core.get_available_breakpoint_units()? == 0 {
-            if rtt_addr.is_some() {
-                bail!("RTT not supported on device without HW breakpoints");
-            } else {
-                log::warn!("device doesn't support HW breakpoints; HardFault will NOT make `probe-run` exit with an error code");
-            }
-        }
+    /// Symbols at the start and end of the heap, for placing the stack canary above the heap.
+    #[structopt(long, name = "START,END")]
+    heap_symbols: Option<HeapSymbols>,
 
-        if let Some(rtt) = rtt_addr {
-            core.set_hw_breakpoint(main)?;
-            core.run()?;
-            core.wait_for_core_halted(Duration::from_secs(5))?;
-            const OFFSET: u32 = 44;
-            const FLAG: u32 = 2; // BLOCK_IF_FULL
-            core.write_word_32(rtt + OFFSET, FLAG)?;
-            core.clear_hw_breakpoint(main)?;
-        }
+    /// Also exit on MemManage, BusFault, UsageFault and SecureFault, not only on HardFault.
+    #[structopt(long, overrides_with = "no_catch_faults")]
+    catch_faults: bool,
 
-        core.set_hw_breakpoint(vector_table.hard_fault & !THUMB_BIT)?;
-        core.run()?;
-    }
+    /// Only exit on HardFault, even if a configuration file sets `catch-faults`.
+    #[structopt(long, overrides_with = "catch_faults")]
+    no_catch_faults: bool,
 
-    let exit = Arc::new(AtomicBool::new(false));
-    let sig_id = signal_hook::flag::register(signal_hook::SIGINT, exit.clone())?;
+    /// What to do when the device resets during the run: fail, reattach or stop.
+    #[structopt(long, possible_values(&["fail", "reattach", "stop"]))]
+    on_reset: Option<ResetPolicy>,
 
-    let sess = Arc::new(Mutex::new(sess));
-    let mut logging_channel = setup_logging_channel(rtt_addr, sess.clone())?;
+    /// Print the resolved configuration, and where each value came from, and exit.
+    #[structopt(long)]
+    print_config: bool,
 
-    // `defmt-rtt` names the channel "defmt", so enable defmt decoding in that case.
-    let use_defmt = logging_channel
-        .as_ref()
-        .map_or(false, |ch| ch.name() == Some("defmt"));
+    /// Enable more verbose logging.
+    #[structopt(short, long)]
+    verbose: bool,
 
-    if use_defmt && opts.no_flash {
-        bail!(
-            "attempted to use `--no-flash` and `defmt` logging -- this combination is not allowed. Remove the `--no-flash` flag"
-        );
-    }
+    /// Prints version information
+    #[structopt(short = "V", long)]
+    version: bool,
+}
 
-    if use_defmt && table.is_none() {
-        bail!("\"defmt\" RTT channel is in use, but the firmware binary contains no defmt data");
-    }
+/// Analyzes a core dump written by `probe-run --coredump`, without a probe.
+#[derive(StructOpt)]
+#[structopt(name = "probe-run post-mortem")]
+struct PostMortemOpts {
+    /// Path to the ELF firmware file that was running on the device.
+    #[structopt(long, parse(from_os_str))]
+    elf: Option<PathBuf>,
 
-    if !use_defmt {
-        table = None;
-    }
+    /// Path to the core dump.
+    #[structopt(long, parse(from_os_str))]
+    dump: PathBuf,
 
-    // Print a separator before the device messages start.
-    eprintln!("{}", "─".repeat(80).dimmed());
-
-    // wait for breakpoint
-    let stdout = io::stdout();
-    let mut stdout = stdout.lock();
-    let mut read_buf = [0; 1024];
-    let mut frames = vec![];
-    let mut was_halted = false;
-    let current_dir = std::env::current_dir()?;
-    // TODO strip prefix from crates-io paths (?)
-    while !exit.load(Ordering::Relaxed) {
-        if let Some(logging_channel) = &mut logging_channel {
-            let num_bytes_read = match logging_channel.read(&mut read_buf) {
-                Ok(n) => n,
-                Err(e) => {
-                    eprintln!("RTT error: {}", e);
-                    break;
-                }
-            };
-
-            if num_bytes_read != 0 {
-                if let Some(table) = table.as_ref() {
-                    frames.extend_from_slice(&read_buf[..num_bytes_read]);
-
-                    loop {
-                        match defmt_decoder::decode(&frames, table) {
-                            Ok((frame, consumed)) => {
-                                // NOTE(`[]` indexing) all indices in `table` have already been
-                                // verified to exist in the `locs` map
-                                let loc = locs.as_ref().map(|locs| &locs[&frame.index()]);
-
-                                let (mut file, mut line, mut mod_path) = (None, None, None);
-                                if let Some(loc) = loc {
-                                    let relpath =
-                                        if let Ok(relpath) = loc.file.strip_prefix(&current_dir) {
-                                            relpath
-                                        } else {
-                                            // not relative; use full path
-                                            &loc.file
-                                        };
-                                    file = Some(relpath.display().to_string());
-                                    line = Some(loc.line as u32);
-                                    mod_path = Some(loc.module.clone());
-                                }
-
-                                // Forward the defmt frame to our logger.
-                                defmt_logger::log_defmt(
-                                    &frame,
-                                    file.as_deref(),
-                                    line,
-                                    mod_path.as_ref().map(|s| &**s),
-                                );
-
-                                let num_frames = frames.len();
-                                frames.rotate_left(consumed);
-                                frames.truncate(num_frames - consumed);
-                            }
-                            Err(defmt_decoder::DecodeError::UnexpectedEof) => break,
-                            Err(defmt_decoder::DecodeError::Malformed) => {
-                                log::error!("failed to decode defmt data: {:x?}", frames);
-                                Err(defmt_decoder::DecodeError::Malformed)?;
-                            }
-                        }
-                    }
-                } else {
-                    stdout.write_all(&read_buf[..num_bytes_read])?;
-                    stdout.flush()?;
-                }
-            }
-        }
+    /// Output format: human or json.
+    #[structopt(long, possible_values(&["human", "json"]))]
+    format: Option<OutputFormat>,
 
-        let mut sess = sess.lock().unwrap();
-        let mut core = sess.core(0)?;
-        let is_halted = core.core_halted()?;
+    /// Show the arguments and local variables of each frame in the backtrace.
+    #[structopt(long)]
+    backtrace_locals: bool,
 
-        if is_halted && was_halted {
-            break;
-        }
-        was_halted = is_halted;
-    }
-    drop(stdout);
+    /// Print the core registers and the exception frame at the time of the dump.
+    #[structopt(long)]
+    show_registers: bool,
 
-    // Restore default Ctrl+C behavior.
-    signal_hook::unregister(sig_id);
-    signal_hook::cleanup::cleanup_signal(signal_hook::SIGINT)?;
+    /// Enable more verbose logging.
+    #[structopt(short, long)]
+    verbose: bool,
+}
 
-    let mut sess = sess.lock().unwrap();
-    let mut core = sess.core(0)?;
+/// Decodes an RTT capture written by `probe-run --rtt-capture`, without a probe.
+#[derive(StructOpt)]
+#[structopt(name = "probe-run decode")]
+struct DecodeOpts {
+    /// Path to the ELF firmware file that was running on the device.
+    #[structopt(long, parse(from_os_str))]
+    elf: Option<PathBuf>,
 
-    if exit.load(Ordering::Relaxed) {
-        // Ctrl-C was pressed; stop the microcontroller.
-        core.halt(TIMEOUT)?;
-    }
+    /// Path to the capture file.
+    #[structopt(name = "CAPTURE", parse(from_os_str))]
+    capture: PathBuf,
 
-    if let Some((addr, len)) = canary {
-        let mut buf = vec![0; len as usize];
-        core.read_8(addr as u32, &mut buf)?;
+    /// Output format: human or json.
+    #[structopt(long, possible_values(&["human", "json"]))]
+    format: Option<OutputFormat>,
 
-        if let Some(pos) = buf.iter().position(|b| *b != STACK_CANARY) {
-            let touched_addr = addr + pos as u32;
-            log::debug!("canary was touched at 0x{:08X}", touched_addr);
+    /// Enable more verbose logging.
+    #[structopt(short, long)]
+    verbose: bool,
+}
 
-            let min_stack_usage = vector_table.initial_sp - touched_addr;
-            log::warn!(
-                "program has used at least {} bytes of stack space, data segments \
-                may be corrupted due to stack overflow",
-                min_stack_usage,
-            );
-        } else {
-            log::debug!("stack canary intact");
+fn notmain() -> Result<i32, anyhow::Error> {
+    // `probe-run <ELF>` must keep working as a Cargo runner so modes other than running a
+    // firmware are selected by a leading keyword rather than by clap subcommands
+    match env::args_os().nth(1).as_deref() {
+        Some(mode) if mode == OsStr::new("post-mortem") => {
+            return post_mortem(PostMortemOpts::from_iter(env::args_os().skip(1)));
+        }
+        Some(mode) if mode == OsStr::new("decode") => {
+            return decode(DecodeOpts::from_iter(env::args_os().skip(1)));
         }
+        _ => {}
     }
 
-    let pc = core.read_core_reg(PC)?;
+    let matches = Opts::clap().get_matches();
+    let opts = Opts::from_clap(&matches);
+    defmt_logger::init(opts.verbose);
 
-    let debug_frame = debug_frame.ok_or_else(|| anyhow!("`.debug_frame` section not found"))?;
+    if opts.version {
+        return print_version();
+    }
 
-    // print backtrace
-    let top_exception = backtrace(
-        &mut core,
-        pc,
-        debug_frame,
-        &elf,
-        &vector_table,
-        &sp_ram_region,
-        &live_functions,
-        &current_dir,
-    )?;
+    if opts.list_probes {
+        print_probes(&Probe::list_all());
+        return Ok(0);
+    }
 
-    core.reset_and_halt(TIMEOUT)?;
+    if opts.list_chips {
+        return print_chips();
+    }
 
-    Ok(
-        if let Some(TopException::HardFault { stack_overflow }) = top_exception {
-            if stack_overflow {
-                log::error!("the program has overflowed its stack");
-            }
+    let elf = opts.elf.clone().unwrap();
 
-            SIGABRT
+    // values of `--chip` and `--probe` may come from the command line or the environment
+    let from_env = |name, value: &Option<String>| {
+        if matches.occurrences_of(name) == 0 {
+            value.clone()
+        } else {
+            None
+        }
+    };
+    let from_cli = |name, value: &Option<String>| {
+        if matches.occurrences_of(name) != 0 {
+            value.clone()
         } else {
-            0
+            None
+        }
+    };
+
+    let mut layers = vec![
+        Layer {
+            source: Source::CommandLine,
+            options: Options {
+                chip: from_cli("chip", &opts.chip),
+                probe: from_cli("probe", &opts.probe),
+                speed: opts.speed,
+                connect_under_reset: flag(opts.connect_under_reset, opts.no_connect_under_reset),
+                no_flash: flag(opts.no_flash, opts.flash),
+                backtrace: opts.backtrace,
+                format: opts.format,
+                timeout: opts.timeout,
+                measure_stack: flag(opts.measure_stack, opts.no_measure_stack),
+                stack_usage_threshold: opts.stack_usage_threshold,
+                heap_symbols: opts.heap_symbols.clone(),
+                catch_faults: flag(opts.catch_faults, opts.no_catch_faults),
+                on_reset: opts.on_reset,
+            },
         },
-    )
-}
+        Layer {
+            source: Source::Env,
+            options: Options {
+                chip: from_env("chip", &opts.chip),
+                probe: from_env("probe", &opts.probe),
+                ..Options::default()
+            },
+        },
+    ];
+    layers.extend(config::discover(&elf)?);
+    let settings = Settings::resolve(&layers);
 
-fn program_size_of(file: &ElfFile) -> u64 {
-    // `segments` iterates only over *loadable* segments, which are the segments that will be loaded to Flash by probe-rs
-    file.segments().map(|segment| segment.size()).sum()
-}
+    if opts.print_config {
+        settings.print();
+        return Ok(0);
+    }
 
-const SIGABRT: i32 = 134;
+    let mut config = settings.run_config(elf)?;
+    config.coredump = opts.coredump;
+    config.backtrace_locals = opts.backtrace_locals;
+    config.show_registers = opts.show_registers;
+    config.rtt_capture = opts.rtt_capture;
+    config.rtt_channels = opts.rtt_channels;
+    config.stdin = opts.stdin;
+    config.rtt_down_channel = opts.rtt_down_channel;
+    config.semihosting_root = opts.semihosting_root;
+
+    // Ctrl-C halts the device and ends the run
+    let sig_id = signal_hook::flag::register(signal_hook::SIGINT, config.abort.clone())?;
+    let outcome = Runner::new(config).run();
+    // restore the default Ctrl-C behavior
+    signal_hook::unregister(sig_id);
+    signal_hook::cleanup::cleanup_signal(signal_hook::SIGINT)?;
+    let outcome = outcome?;
 
-#[derive(Debug, PartialEq)]
-enum TopException {
-    HardFault { stack_overflow: bool },
-    Other,
+    report(&outcome, settings.backtrace.value, settings.format.value)
 }
 
-fn setup_logging_channel(
-    rtt_addr: Option<u32>,
-    sess: Arc<Mutex<Session>>,
-) -> Result<Option<UpChannel>, anyhow::Error> {
-    if let Some(rtt_addr_res) = rtt_addr {
-        const NUM_RETRIES: usize = 10; // picked at random, increase if necessary
-        let mut rtt_res: Result<Rtt, probe_rs_rtt::Error> =
-            Err(probe_rs_rtt::Error::ControlBlockNotFound);
-
-        for try_index in 0..=NUM_RETRIES {
-            rtt_res = Rtt::attach_region(sess.clone(), &ScanRegion::Exact(rtt_addr_res));
-            match rtt_res {
-                Ok(_) => {
-                    log::debug!("Successfully attached RTT");
-                    break;
-                }
-                Err(probe_rs_rtt::Error::ControlBlockNotFound) => {
-                    if try_index < NUM_RETRIES {
-                        log::trace!("Could not attach because the target's RTT control block isn't initialized (yet). retrying");
-                    } else {
-                        log::error!("Max number of RTT attach retries exceeded.");
-                        return Err(anyhow!(probe_rs_rtt::Error::ControlBlockNotFound));
-                    }
-                }
-                Err(e) => {
-                    return Err(anyhow!(e));
-                }
-            }
-        }
-
-        let channel = rtt_res
-            .expect("unreachable") // this block is only executed when rtt was successfully attached before
-            .up_channels()
-            .take(0)
-            .ok_or_else(|| anyhow!("RTT up channel 0 not found"))?;
-        Ok(Some(channel))
+/// The value of a flag that `off` negates; `None`, leaving it to the configuration files, if
+/// neither was passed
+fn flag(on: bool, off: bool) -> Option<bool> {
+    if on {
+        Some(true)
+    } else if off {
+        Some(false)
     } else {
-        eprintln!("RTT logs not available; blocking until the device halts..");
-        Ok(None)
+        None
     }
 }
 
-fn gimli2probe(reg: &gimli::Register) -> CoreRegisterAddress {
-    CoreRegisterAddress(reg.0)
-}
+fn post_mortem(opts: PostMortemOpts) -> Result<i32, anyhow::Error> {
+    defmt_logger::init(opts.verbose);
+
+    let dump = CoreDump::read(&opts.dump)?;
+    // the dump records the path of the firmware that was running
+    let elf = opts
+        .elf
+        .or_else(|| dump.firmware.clone())
+        .ok_or_else(|| anyhow!("the core dump doesn't name the firmware; use the `--elf` flag"))?;
 
-struct Registers<'c, 'probe> {
-    cache: BTreeMap<u16, u32>,
-    core: &'c mut Core<'probe>,
+    let outcome = probe_run::post_mortem(&elf, &dump, opts.backtrace_locals, opts.show_registers)?;
+
+    report(
+        &outcome,
+        BacktracePolicy::Always,
+        opts.format.unwrap_or_default(),
+    )
 }
 
-impl<'c, 'probe> Registers<'c, 'probe> {
-    fn new(lr: u32, sp: u32, core: &'c mut Core<'probe>) -> Self {
-        let mut cache = BTreeMap::new();
-        cache.insert(LR.0, lr);
-        cache.insert(SP.0, sp);
-        Self { cache, core }
-    }
+fn decode(opts: DecodeOpts) -> Result<i32, anyhow::Error> {
+    defmt_logger::init(opts.verbose);
 
-    fn get(&mut self, reg: CoreRegisterAddress) -> Result<u32, anyhow::Error> {
-        Ok(match self.cache.entry(reg.0) {
-            btree_map::Entry::Occupied(entry) => *entry.get(),
-            btree_map::Entry::Vacant(entry) => *entry.insert(self.core.read_core_reg(reg)?),
-        })
-    }
+    let capture = Capture::read(&opts.capture)?;
+    // the capture records the path of the firmware that was running
+    let elf = opts.elf.unwrap_or_else(|| capture.elf.path.clone());
 
-    fn insert(&mut self, reg: CoreRegisterAddress, val: u32) {
-        self.cache.insert(reg.0, val);
-    }
+    probe_run::replay(&elf, &capture, opts.format.unwrap_or_default())?;
 
-    fn update_cfa(
-        &mut self,
-        rule: &CfaRule<EndianSlice<LittleEndian>>,
-    ) -> Result</* cfa_changed: */ bool, anyhow::Error> {
-        match rule {
-            CfaRule::RegisterAndOffset { register, offset } => {
-                let cfa = (i64::from(self.get(gimli2probe(register))?) + offset) as u32;
-                let old_cfa = self.cache.get(&SP.0);
-                let changed = old_cfa != Some(&cfa);
-                if changed {
-                    log::debug!("update_cfa: CFA changed {:8x?} -> {:8x}", old_cfa, cfa);
-                }
-                self.cache.insert(SP.0, cfa);
-                Ok(changed)
-            }
+    Ok(0)
+}
 
-            // NOTE not encountered in practice so far
-            CfaRule::Expression(_) => todo!("CfaRule::Expression"),
+/// Prints the backtrace and diagnostics of `outcome`; returns the exit code
+fn report(
+    outcome: &RunOutcome,
+    backtrace_policy: BacktracePolicy,
+    format: OutputFormat,
+) -> Result<i32, anyhow::Error> {
+    let print_backtrace = match backtrace_policy {
+        BacktracePolicy::Always => true,
+        BacktracePolicy::Never => false,
+        BacktracePolicy::Auto => {
+            outcome.exit_reason == ExitReason::Interrupted || outcome.exit_code() != 0
         }
-    }
+    };
 
-    fn update(
-        &mut self,
-        reg: &gimli::Register,
-        rule: &RegisterRule<EndianSlice<LittleEndian>>,
-    ) -> Result<(), anyhow::Error> {
-        match rule {
-            RegisterRule::Undefined => unreachable!(),
-
-            RegisterRule::Offset(offset) => {
-                let cfa = self.get(SP)?;
-                let addr = (i64::from(cfa) + offset) as u32;
-                self.cache.insert(reg.0, self.core.read_word_32(addr)?);
+    if format == OutputFormat::Json {
+        if let Some(state) = outcome.stack_canary {
+            Event::stack_canary(state).emit()?;
+        }
+        if let Some(usage) = outcome.stack_usage {
+            Event::stack_usage(usage).emit()?;
+        }
+        if print_backtrace {
+            for event in Event::backtrace(&outcome.backtrace) {
+                event.emit()?;
             }
-
-            _ => unimplemented!(),
         }
+        if let Some(fault) = &outcome.fault {
+            Event::fault(fault).emit()?;
+        }
+        if let Some(registers) = &outcome.registers {
+            Event::registers(registers).emit()?;
+        }
+        Event::exit(outcome).emit()?;
+        return Ok(outcome.exit_code());
+    }
 
-        Ok(())
+    if print_backtrace {
+        backtrace::print(&outcome.backtrace);
     }
-}
 
-fn backtrace(
-    core: &mut Core<'_>,
-    mut pc: u32,
-    debug_frame: &[u8],
-    elf: &ElfFile,
-    vector_table: &VectorTable,
-    sp_ram_region: &Option<RamRegion>,
-    live_functions: &HashSet<&str>,
-    current_dir: &Path,
-) -> Result<Option<TopException>, anyhow::Error> {
-    let mut debug_frame = DebugFrame::new(debug_frame, LittleEndian);
-    // 32-bit ARM -- this defaults to the host's address size which is likely going to be 8
-    debug_frame.set_address_size(mem::size_of::<u32>() as u8);
-
-    let sp = core.read_core_reg(SP)?;
-    let lr = core.read_core_reg(LR)?;
-
-    // statically linked binary -- there are no relative addresses
-    let bases = &BaseAddresses::default();
-    let ctx = &mut UninitializedUnwindContext::new();
-
-    let addr2line = addr2line::Context::new(elf)?;
-    let mut top_exception = None;
-    let mut frame_index = 0;
-    let mut registers = Registers::new(lr, sp, core);
-    let symtab = elf.symbol_map();
-    println!("stack backtrace:");
-    loop {
-        let frames = addr2line.find_frames(pc as u64)?.collect::<Vec<_>>()?;
-        // when the input of `find_frames` is the PC of a subroutine that has no debug information
-        // (e.g. external assembly), it will either return an empty `FrameIter` OR the frames that
-        // correspond to a subroutine GC-ed by the linker, instead of an `Err`or.
-        // To detect the second failure mode we check that the last frame (the non-inline one) is
-        // actually "live" (exists in the final binary).
-        // When there's no debuginfo we fallback to a symtab lookup to at least provide the name of
-        // the function that contains the PC.
-        let subroutine = frames.last();
-        let has_valid_debuginfo = if let Some(function) =
-            subroutine.and_then(|subroutine| subroutine.function.as_ref())
-        {
-            live_functions.contains(&*function.raw_name()?)
-        } else {
-            false
-        };
-
-        if has_valid_debuginfo {
-            for frame in &frames {
-                let name = frame
-                    .function
-                    .as_ref()
-                    .map(|function| function.demangle())
-                    .transpose()?
-                    .unwrap_or(Cow::Borrowed("???"));
-
-                println!("{:>4}: {}", frame_index, name);
-                frame_index += 1;
-
-                if let Some((file, line)) = frame
-                    .location
-                    .as_ref()
-                    .and_then(|loc| loc.file.and_then(|file| loc.line.map(|line| (file, line))))
-                {
-                    let file = Path::new(file);
-                    let relpath = if let Ok(relpath) = file.strip_prefix(&current_dir) {
-                        relpath
-                    } else {
-                        // not within current directory; use full path
-                        file
-                    };
-                    println!("        at {}:{}", relpath.display(), line);
-                }
-            }
-        } else {
-            // .symtab fallback
-            // the .symtab appears to use address ranges that have their thumb bits set (e.g.
-            // `0x101..0x200`). Passing the `pc` with the thumb bit cleared (e.g. `0x100`) to the
-            // lookup function sometimes returns the *previous* symbol. Work around the issue by
-            // setting `pc`'s thumb bit before looking it up
-            let address = (pc | THUMB_BIT) as u64;
-            let name = symtab
-                .get(address)
-                .and_then(|symbol| symbol.name())
-                .unwrap_or("???");
-            println!("{:>4}: {}", frame_index, name);
-            frame_index += 1;
+    if let Some(fault) = &outcome.fault {
+        for cause in fault.causes() {
+            log::error!("{}", cause);
         }
-
-        // on hard fault exception entry we hit the breakpoint before the subroutine prelude (`push
-        // lr`) is executed so special handling is required
-        // also note that hard fault will always be the first frame we unwind
-        if top_exception.is_none() {
-            top_exception = Some(if pc & !THUMB_BIT == vector_table.hard_fault & !THUMB_BIT {
-                // HardFaultTrampoline
-
-                let stack_overflow = if let Some(sp_ram_region) = sp_ram_region {
-                    // NOTE stack is full descending; meaning the stack pointer can be `ORIGIN(RAM) +
-                    // LENGTH(RAM)`
-                    let range = sp_ram_region.range.start..=sp_ram_region.range.end;
-                    !range.contains(&sp)
-                } else {
-                    log::warn!(
-                        "no RAM region appears to contain the stack; cannot determine if this was a stack overflow"
-                    );
-
-                    false
-                };
-
-                TopException::HardFault { stack_overflow }
+        if let Some(pc) = fault.pc {
+            if fault.is_imprecise() {
+                log::error!("the faulting instruction precedes 0x{:08x}", pc);
             } else {
-                TopException::Other
-            });
+                log::error!("the faulting instruction is at 0x{:08x}", pc);
+            }
         }
+        log::info!("fault status registers: {}", fault);
+    }
 
-        let uwt_row = debug_frame.unwind_info_for_address(bases, ctx, pc.into(), DebugFrame::cie_from_offset).with_context(|| {
-            "debug information is missing. Likely fixes:
-1. compile the Rust code with `debug = 1` or higher. This is configured in the `profile.*` section of Cargo.toml
-2. use a recent version of the `cortex-m` crates (e.g. cortex-m 0.6.3 or newer). Check versions in Cargo.lock
-3. if linking to C code, compile the C code with the `-g` flag"
-        })?;
-
-        let cfa_changed = registers.update_cfa(uwt_row.cfa())?;
-
-        for (reg, rule) in uwt_row.registers() {
-            registers.update(reg, rule)?;
-        }
+    if let Some(registers) = &outcome.registers {
+        print!("{}", registers);
+    }
 
-        let lr = registers.get(LR)?;
-        log::debug!("lr=0x{:08x} pc=0x{:08x}", lr, pc);
-        if lr == LR_END {
-            break;
+    if let ExitReason::HardFault {
+        exception,
+        stack_overflow,
+    } = outcome.exit_reason
+    {
+        if exception != Exception::HARD_FAULT {
+            log::error!("the program ended in the {} handler", exception);
         }
-
-        // If the frame didn't move, and the program counter didn't change, bail out (otherwise we
-        // might print the same frame over and over).
-        // Since we strip the thumb bit from `pc`, ignore it in this comparison.
-        if !cfa_changed && lr & !THUMB_BIT == pc & !THUMB_BIT {
-            println!("error: the stack appears to be corrupted beyond this point");
-            return Ok(top_exception);
+        if stack_overflow {
+            log::error!("the program has overflowed its stack");
         }
+    }
 
-        if lr > 0xffff_ffe0 {
-            let fpu = match lr {
-                0xFFFFFFF1 | 0xFFFFFFF9 | 0xFFFFFFFD => false,
-                0xFFFFFFE1 | 0xFFFFFFE9 | 0xFFFFFFED => true,
-                _ => bail!("LR contains invalid EXC_RETURN value 0x{:08X}", lr),
-            };
-
-            println!("      <exception entry>");
-
-            let sp = registers.get(SP)?;
-            let stacked = Stacked::read(registers.core, sp, fpu)?;
+    if outcome.exit_reason == ExitReason::TimedOut {
+        log::error!("the program did not halt before the timeout expired");
+    }
 
-            registers.insert(LR, stacked.lr);
-            // adjust the stack pointer for stacked registers
-            registers.insert(SP, sp + stacked.size());
-            pc = stacked.pc;
-        } else {
-            if lr & 1 == 0 {
-                bail!("bug? LR ({:#010x}) didn't have the Thumb bit set", lr)
-            }
-            pc = lr & !THUMB_BIT;
-        }
+    if let ExitReason::UnexpectedHalt { reason } = outcome.exit_reason {
+        log::error!("the core halted unexpectedly: {}", reason);
+    } else if let Some(reason) = outcome.halt_reason {
+        log::debug!("the core halted: {}", reason);
     }
 
-    Ok(top_exception)
-}
+    if outcome.exit_reason == ExitReason::Lockup {
+        log::error!("the core locked up: it faulted while handling a HardFault or an NMI");
+    }
 
-fn probes_filter(probes: &[DebugProbeInfo], selector: &DebugProbeSelector) -> Vec<DebugProbeInfo> {
-    probes
-        .iter()
-        .filter(|&p| {
-            p.vendor_id == selector.vendor_id
-                && p.product_id == selector.product_id
-                && (selector.serial_number == None || p.serial_number == selector.serial_number)
-        })
-        .map(|p| p.clone())
-        .collect()
+    Ok(outcome.exit_code())
 }
 
-fn print_probes(probes: Vec<DebugProbeInfo>) -> Result<i32, anyhow::Error> {
+/// Prints a numbered list of `probes`
+fn print_probes(probes: &[DebugProbeInfo]) {
     if !probes.is_empty() {
         println!("The following devices were found:");
         probes
@@ -869,8 +468,6 @@ fn print_probes(probes: Vec<DebugProbeInfo>) -> Result<i32, anyhow::Error> {
     } else {
         println!("No devices were found.");
     }
-
-    Ok(0)
 }
 
 fn print_chips() -> Result<i32, anyhow::Error> {
@@ -885,157 +482,3 @@ fn print_chips() -> Result<i32, anyhow::Error> {
 
     Ok(0)
 }
-
-#[derive(Debug)]
-struct StackedFpuRegs {
-    s0: f32,
-    s1: f32,
-    s2: f32,
-    s3: f32,
-    s4: f32,
-    s5: f32,
-    s6: f32,
-    s7: f32,
-    s8: f32,
-    s9: f32,
-    s10: f32,
-    s11: f32,
-    s12: f32,
-    s13: f32,
-    s14: f32,
-    s15: f32,
-    fpscr: u32,
-}
-
-/// Registers stacked on exception entry.
-#[derive(Debug)]
-struct Stacked {
-    r0: u32,
-    r1: u32,
-    r2: u32,
-    r3: u32,
-    r12: u32,
-    lr: u32,
-    pc: u32,
-    xpsr: u32,
-    fpu_regs: Option<StackedFpuRegs>,
-}
-
-impl Stacked {
-    /// Number of 32-bit words stacked in a basic frame.
-    const WORDS_BASIC: usize = 8;
-
-    /// Number of 32-bit words stacked in an extended frame.
-    const WORDS_EXTENDED: usize = Self::WORDS_BASIC + 17; // 16 FPU regs + 1 status word
-
-    fn read(core: &mut Core<'_>, sp: u32, fpu: bool) -> Result<Self, anyhow::Error> {
-        let mut storage = [0; Self::WORDS_EXTENDED];
-        let registers: &mut [_] = if fpu {
-            &mut storage
-        } else {
-            &mut storage[..Self::WORDS_BASIC]
-        };
-        core.read_32(sp, registers)?;
-
-        Ok(Stacked {
-            r0: registers[0],
-            r1: registers[1],
-            r2: registers[2],
-            r3: registers[3],
-            r12: registers[4],
-            lr: registers[5],
-            pc: registers[6],
-            xpsr: registers[7],
-            fpu_regs: if fpu {
-                Some(StackedFpuRegs {
-                    s0: f32::from_bits(registers[8]),
-                    s1: f32::from_bits(registers[9]),
-                    s2: f32::from_bits(registers[10]),
-                    s3: f32::from_bits(registers[11]),
-                    s4: f32::from_bits(registers[12]),
-                    s5: f32::from_bits(registers[13]),
-                    s6: f32::from_bits(registers[14]),
-                    s7: f32::from_bits(registers[15]),
-                    s8: f32::from_bits(registers[16]),
-                    s9: f32::from_bits(registers[17]),
-                    s10: f32::from_bits(registers[18]),
-                    s11: f32::from_bits(registers[19]),
-                    s12: f32::from_bits(registers[20]),
-                    s13: f32::from_bits(registers[21]),
-                    s14: f32::from_bits(registers[22]),
-                    s15: f32::from_bits(registers[23]),
-                    fpscr: registers[24],
-                })
-            } else {
-                None
-            },
-        })
-    }
-
-    /// Returns the in-memory size of these stacked registers, in Bytes.
-    fn size(&self) -> u32 {
-        let num_words = if self.fpu_regs.is_none() {
-            Self::WORDS_BASIC
-        } else {
-            Self::WORDS_EXTENDED
-        };
-
-        num_words as u32 * 4
-    }
-}
-
-fn get_rtt_heap_main_from(
-    elf: &ElfFile,
-) -> Result<(Option<u32>, bool /* uses heap */, u32), anyhow::Error> {
-    let mut rtt = None;
-    let mut uses_heap = false;
-    let mut main = None;
-
-    for (_, symbol) in elf.symbols() {
-        let name = match symbol.name() {
-            Some(name) => name,
-            None => continue,
-        };
-
-        match name {
-            "main" => main = Some(symbol.address() as u32 & !THUMB_BIT),
-            "_SEGGER_RTT" => rtt = Some(symbol.address() as u32),
-            "__rust_alloc" | "__rg_alloc" | "__rdl_alloc" | "malloc" if !uses_heap => {
-                log::debug!("symbol `{}` indicates heap is in use", name);
-                uses_heap = true;
-            }
-            _ => {}
-        }
-    }
-
-    Ok((
-        rtt,
-        uses_heap,
-        main.ok_or_else(|| anyhow!("`main` symbol not found"))?,
-    ))
-}
-
-const LR: CoreRegisterAddress = CoreRegisterAddress(14);
-const PC: CoreRegisterAddress = CoreRegisterAddress(15);
-const SP: CoreRegisterAddress = CoreRegisterAddress(13);
-
-const LR_END: u32 = 0xFFFF_FFFF;
-
-/// ELF section to be loaded onto the target
-#[derive(Debug)]
-struct Section {
-    start: u32,
-    data: Vec<u32>,
-}
-
-/// The contents of the vector table
-#[derive(Debug)]
-struct VectorTable {
-    location: u32,
-    // entry 0
-    initial_sp: u32,
-    // entry 1: Reset handler
-    reset: u32,
-    // entry 3: HardFault handler
-    hard_fault: u32,
-}
@@ -0,0 +1,171 @@
+//! The registers of the halted core, printed with `--show-registers`
+
+use std::fmt;
+
+use probe_rs::CoreRegisterAddress;
+use serde::Serialize;
+
+use crate::{exception::Exception, stacked::Stacked, target::TargetAccess, LR, PC, SP, XPSR};
+
+const MSP: CoreRegisterAddress = CoreRegisterAddress(17);
+const PSP: CoreRegisterAddress = CoreRegisterAddress(18);
+/// `CoreRegisterAddress` of CONTROL, FAULTMASK, BASEPRI and PRIMASK, packed in one word from the
+/// most to the least significant byte
+const SPECIAL: CoreRegisterAddress = CoreRegisterAddress(20);
+
+/// The core registers at the time of halt and the registers stacked on entry to the exception the
+/// core halted in
+#[derive(Clone, Debug, Serialize)]
+pub struct CoreRegisters {
+    pub r4: u32,
+    pub r5: u32,
+    pub r6: u32,
+    pub r7: u32,
+    pub r8: u32,
+    pub r9: u32,
+    pub r10: u32,
+    pub r11: u32,
+    pub sp: u32,
+    pub lr: u32,
+    pub pc: u32,
+    pub xpsr: u32,
+    pub msp: u32,
+    pub psp: u32,
+    pub control: u8,
+    pub faultmask: u8,
+    pub basepri: u8,
+    pub primask: u8,
+    /// Registers stacked on entry to the innermost exception; `None` if the core halted in
+    /// thread mode
+    pub stacked: Option<Stacked>,
+}
+
+impl CoreRegisters {
+    /// Reads the registers of the halted `core`
+    ///
+    /// `stacked` is the innermost exception frame found while unwinding the stack.
+    pub fn read(
+        core: &mut impl TargetAccess,
+        stacked: Option<&Stacked>,
+    ) -> Result<Self, anyhow::Error> {
+        let mut r4_r11 = [0; 8];
+        for (index, value) in r4_r11.iter_mut().enumerate() {
+            *value = core.read_core_reg(CoreRegisterAddress(4 + index as u16))?;
+        }
+        let [r4, r5, r6, r7, r8, r9, r10, r11] = r4_r11;
+
+        let special = core.read_core_reg(SPECIAL)?.to_le_bytes();
+
+        Ok(Self {
+            r4,
+            r5,
+            r6,
+            r7,
+            r8,
+            r9,
+            r10,
+            r11,
+            sp: core.read_core_reg(SP)?,
+            lr: core.read_core_reg(LR)?,
+            pc: core.read_core_reg(PC)?,
+            xpsr: core.read_core_reg(XPSR)?,
+            msp: core.read_core_reg(MSP)?,
+            psp: core.read_core_reg(PSP)?,
+            control: special[3],
+            faultmask: special[2],
+            basepri: special[1],
+            primask: special[0],
+            stacked: stacked.cloned(),
+        })
+    }
+
+    /// The exception the core halted in
+    pub fn exception(&self) -> Exception {
+        Exception::from_xpsr(self.xpsr)
+    }
+}
+
+/// Formats the registers as a table, four per line
+impl fmt::Display for CoreRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "registers ({}):", self.exception())?;
+        write_table(
+            f,
+            &[
+                ("r4", self.r4),
+                ("r5", self.r5),
+                ("r6", self.r6),
+                ("r7", self.r7),
+                ("r8", self.r8),
+                ("r9", self.r9),
+                ("r10", self.r10),
+                ("r11", self.r11),
+                ("sp", self.sp),
+                ("lr", self.lr),
+                ("pc", self.pc),
+                ("xpsr", self.xpsr),
+                ("msp", self.msp),
+                ("psp", self.psp),
+                ("control", self.control.into()),
+                ("faultmask", self.faultmask.into()),
+                ("basepri", self.basepri.into()),
+                ("primask", self.primask.into()),
+            ],
+        )?;
+
+        if let Some(stacked) = &self.stacked {
+            writeln!(
+                f,
+                "exception frame (interrupted {}):",
+                Exception::from_xpsr(stacked.xpsr)
+            )?;
+            write_table(
+                f,
+                &[
+                    ("r0", stacked.r0),
+                    ("r1", stacked.r1),
+                    ("r2", stacked.r2),
+                    ("r3", stacked.r3),
+                    ("r12", stacked.r12),
+                    ("lr", stacked.lr),
+                    ("pc", stacked.pc),
+                    ("xpsr", stacked.xpsr),
+                ],
+            )?;
+
+            if let Some(fpu) = &stacked.fpu_regs {
+                let s = [
+                    fpu.s0, fpu.s1, fpu.s2, fpu.s3, fpu.s4, fpu.s5, fpu.s6, fpu.s7, fpu.s8, fpu.s9,
+                    fpu.s10, fpu.s11, fpu.s12, fpu.s13, fpu.s14, fpu.s15,
+                ];
+                for (index, chunk) in s.chunks(4).enumerate() {
+                    write!(f, " ")?;
+                    for (offset, value) in chunk.iter().enumerate() {
+                        write!(
+                            f,
+                            " {:>9} = {:>10}",
+                            format!("s{}", 4 * index + offset),
+                            value
+                        )?;
+                    }
+                    writeln!(f)?;
+                }
+                writeln!(f, "  {:>9} = 0x{:08x}", "fpscr", fpu.fpscr)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn write_table(f: &mut fmt::Formatter<'_>, registers: &[(&str, u32)]) -> fmt::Result {
+    for chunk in registers.chunks(4) {
+        write!(f, " ")?;
+        for (name, value) in chunk {
+            write!(f, " {:>9} = 0x{:08x}", name, value)?;
+        }
+        writeln!(f)?;
+    }
+
+    Ok(())
+}
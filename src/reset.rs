@@ -0,0 +1,150 @@
+//! Detecting a reset of the device in the middle of a run
+//!
+//! With the core reset vector catch the core halts at the start of the reset handler. Without it
+//! the RTT control block gives the reset away: the firmware initializes it again while starting
+//! over. Its ID may be copied back from `.data` before `probe-run` gets to see it missing, so the
+//! offsets of the up channels are watched as well.
+
+use anyhow::anyhow;
+
+use crate::{target::TargetAccess, PC, THUMB_BIT};
+
+/// The ID at the start of an RTT control block
+const RTT_ID: &[u8] = b"SEGGER RTT";
+/// Size of the control block header: the ID and the number of up and down channels
+const HEADER_SIZE: usize = 24;
+/// Size of the descriptor of a channel
+const DESCRIPTOR_SIZE: usize = 24;
+/// More up channels than this means the control block holds garbage, e.g. right after a reset
+const MAX_UP_CHANNELS: u32 = 32;
+
+/// Whether the PC of the halted `core` is at the start of the reset handler at `reset`
+pub fn at_reset_handler(core: &mut impl TargetAccess, reset: u32) -> Result<bool, anyhow::Error> {
+    Ok(core.read_core_reg(PC)? & !THUMB_BIT == reset & !THUMB_BIT)
+}
+
+/// The state of the ring buffer of an up channel
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct UpBuffer {
+    size: u32,
+    /// Advanced by the firmware as it writes
+    write: u32,
+    /// Advanced by `probe-run` as it reads
+    read: u32,
+}
+
+impl UpBuffer {
+    /// Number of bytes written but not read yet
+    fn available(&self) -> u32 {
+        (self.write + self.size - self.read) % self.size
+    }
+}
+
+/// Watches the RTT control block of the running firmware for signs of a reset
+#[derive(Debug)]
+pub struct RttWatch {
+    rtt_addr: u32,
+    /// Up channels at the last check, indexed by channel number
+    up: Vec<UpBuffer>,
+    /// Bytes `probe-run` read from each up channel since the last check
+    consumed: Vec<u32>,
+}
+
+impl RttWatch {
+    /// Starts watching the control block at `rtt_addr`
+    pub fn new(core: &mut impl TargetAccess, rtt_addr: u32) -> Result<Self, anyhow::Error> {
+        let up = read_up_buffers(core, rtt_addr)?
+            .ok_or_else(|| anyhow!("no RTT control block at 0x{:08x}", rtt_addr))?;
+
+        Ok(Self {
+            rtt_addr,
+            consumed: vec![0; up.len()],
+            up,
+        })
+    }
+
+    /// Records that `len` bytes were read from up channel `number`
+    pub fn consumed(&mut self, number: usize, len: usize) {
+        if let Some(consumed) = self.consumed.get_mut(number) {
+            *consumed += len as u32;
+        }
+    }
+
+    /// Whether the firmware initialized the control block again since the last check
+    ///
+    /// That's the case when its ID is missing, when a read offset changed by more than what was
+    /// read or when a write offset went backwards.
+    pub fn check(&mut self, core: &mut impl TargetAccess) -> Result<bool, anyhow::Error> {
+        let up = match read_up_buffers(core, self.rtt_addr)? {
+            Some(up) if up.len() == self.up.len() => up,
+            _ => return Ok(true),
+        };
+
+        for ((old, new), consumed) in self.up.iter().zip(&up).zip(&self.consumed) {
+            if old.size != new.size {
+                return Ok(true);
+            }
+            // the channel has no buffer
+            if new.size == 0 {
+                continue;
+            }
+            if new.write >= new.size || new.read >= new.size {
+                return Ok(true);
+            }
+
+            // only `probe-run` moves the read offset
+            if new.read != (old.read + consumed) % old.size {
+                log::debug!("RTT read offset moved from {} to {}", old.read, new.read);
+                return Ok(true);
+            }
+
+            // the firmware can't write more than the free space: going backwards would take more
+            let written = (new.write + old.size - old.write) % old.size;
+            let free = old.size - 1 - old.available() + consumed;
+            if written > free {
+                log::debug!(
+                    "RTT write offset went back from {} to {}",
+                    old.write,
+                    new.write
+                );
+                return Ok(true);
+            }
+        }
+
+        self.up = up;
+        self.consumed.iter_mut().for_each(|consumed| *consumed = 0);
+        Ok(false)
+    }
+}
+
+/// Reads the state of the up channels of the control block at `rtt_addr`; `None` if its ID is
+/// missing or its number of up channels is out of range
+fn read_up_buffers(
+    core: &mut impl TargetAccess,
+    rtt_addr: u32,
+) -> Result<Option<Vec<UpBuffer>>, anyhow::Error> {
+    let mut header = [0; HEADER_SIZE];
+    core.read_8(rtt_addr, &mut header)?;
+    if !header.starts_with(RTT_ID) {
+        return Ok(None);
+    }
+
+    let max_up = u32::from_le_bytes([header[16], header[17], header[18], header[19]]);
+    if max_up > MAX_UP_CHANNELS {
+        log::debug!("RTT control block has {} up channels", max_up);
+        return Ok(None);
+    }
+    let mut descriptors = vec![0; max_up as usize * DESCRIPTOR_SIZE / 4];
+    core.read_32(rtt_addr + HEADER_SIZE as u32, &mut descriptors)?;
+    // name, buffer, size, write offset, read offset, flags
+    Ok(Some(
+        descriptors
+            .chunks_exact(DESCRIPTOR_SIZE / 4)
+            .map(|descriptor| UpBuffer {
+                size: descriptor[2],
+                write: descriptor[3],
+                read: descriptor[4],
+            })
+            .collect(),
+    ))
+}
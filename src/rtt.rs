@@ -0,0 +1,287 @@
+use std::{
+    fs::File,
+    io,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context as _};
+use probe_rs::Session;
+use probe_rs_rtt::{DownChannel, Rtt, ScanRegion, UpChannel};
+
+use crate::{config::StdinMode, stdin::Splitter};
+
+/// The channels of an RTT control block
+#[derive(Default)]
+pub(crate) struct Channels {
+    pub(crate) up: Vec<UpChannel>,
+    pub(crate) down: Vec<DownChannel>,
+}
+
+/// Attaches to the RTT control block at `rtt_addr` and returns all of its channels
+pub(crate) fn setup_channels(
+    rtt_addr: Option<u32>,
+    sess: Arc<Mutex<Session>>,
+) -> Result<Channels, anyhow::Error> {
+    if let Some(rtt_addr_res) = rtt_addr {
+        const NUM_RETRIES: usize = 10; // picked at random, increase if necessary
+        let mut rtt_res: Result<Rtt, probe_rs_rtt::Error> =
+            Err(probe_rs_rtt::Error::ControlBlockNotFound);
+
+        for try_index in 0..=NUM_RETRIES {
+            rtt_res = Rtt::attach_region(sess.clone(), &ScanRegion::Exact(rtt_addr_res));
+            match rtt_res {
+                Ok(_) => {
+                    log::debug!("Successfully attached RTT");
+                    break;
+                }
+                Err(probe_rs_rtt::Error::ControlBlockNotFound) => {
+                    if try_index < NUM_RETRIES {
+                        log::trace!("Could not attach because the target's RTT control block isn't initialized (yet). retrying");
+                    } else {
+                        log::error!("Max number of RTT attach retries exceeded.");
+                        return Err(anyhow!(probe_rs_rtt::Error::ControlBlockNotFound));
+                    }
+                }
+                Err(e) => {
+                    return Err(anyhow!(e));
+                }
+            }
+        }
+
+        let mut rtt = rtt_res.expect("unreachable"); // this block is only executed when rtt was successfully attached before
+        let up = rtt.up_channels().drain().collect::<Vec<_>>();
+        if up.is_empty() {
+            return Err(anyhow!("RTT up channel 0 not found"));
+        }
+        let down = rtt.down_channels().drain().collect();
+        Ok(Channels { up, down })
+    } else {
+        eprintln!("RTT logs not available; blocking until the device halts..");
+        Ok(Channels::default())
+    }
+}
+
+/// The name of `channel` or, if it has none, its number
+pub(crate) fn channel_name(channel: &UpChannel) -> String {
+    channel
+        .name()
+        .map(str::to_string)
+        .unwrap_or_else(|| channel.number().to_string())
+}
+
+/// How long the stdin reader waits for input before checking whether it should stop
+const STDIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Forwards the host's stdin to a down channel
+pub(crate) struct Input {
+    channel: DownChannel,
+    receiver: mpsc::Receiver<Vec<u8>>,
+    /// Bytes that didn't fit in the channel's buffer yet
+    pending: Vec<u8>,
+    /// Tells the reader thread to stop
+    stop: Arc<AtomicBool>,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl Input {
+    /// Starts reading stdin in the background; the reader stops when the `Input` is dropped
+    pub(crate) fn spawn(channel: DownChannel, mode: StdinMode) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let reader = thread::spawn({
+            let stop = stop.clone();
+            move || read_stdin(mode, &sender, &stop)
+        });
+
+        Self {
+            channel,
+            receiver,
+            pending: vec![],
+            stop,
+            reader: Some(reader),
+        }
+    }
+
+    /// Forwards the input to `channel` from now on, e.g. after the device reset
+    pub(crate) fn reattach(&mut self, channel: DownChannel) {
+        self.channel = channel;
+    }
+
+    /// Writes as much of the input received so far as fits in the down channel
+    pub(crate) fn forward(&mut self) -> Result<(), anyhow::Error> {
+        while let Ok(data) = self.receiver.try_recv() {
+            self.pending.extend_from_slice(&data);
+        }
+
+        if !self.pending.is_empty() {
+            let num_bytes_written = self.channel.write(&self.pending)?;
+            self.pending.drain(..num_bytes_written);
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Input {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+
+        // NOTE without `poll` a blocking read can't be interrupted; elsewhere the thread is left
+        // to exit after its pending read
+        if cfg!(unix) {
+            if let Some(reader) = self.reader.take() {
+                let _ = reader.join();
+            }
+        }
+    }
+}
+
+/// Sends stdin to `sender`, a line or a read at a time depending on `mode`, until stdin is closed
+/// or `stop` is set
+fn read_stdin(mode: StdinMode, sender: &mpsc::Sender<Vec<u8>>, stop: &AtomicBool) {
+    if mode == StdinMode::Off {
+        return;
+    }
+
+    let mut splitter = Splitter::new(mode);
+    let mut buf = [0; 1024];
+    while !stop.load(Ordering::Relaxed) {
+        match stdin_ready(STDIN_POLL_INTERVAL) {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(_) => break,
+        }
+
+        let n = match read_stdin_once(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        let data = splitter.received(&buf[..n]);
+        if !data.is_empty() && sender.send(data).is_err() {
+            return;
+        }
+    }
+
+    // the last line may not be terminated
+    let line = splitter.finish();
+    if !line.is_empty() {
+        let _ = sender.send(line);
+    }
+}
+
+/// Waits up to `timeout` for stdin to become readable
+#[cfg(unix)]
+fn stdin_ready(timeout: Duration) -> io::Result<bool> {
+    let mut fd = libc::pollfd {
+        fd: libc::STDIN_FILENO,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    match unsafe { libc::poll(&mut fd, 1, timeout.as_millis() as libc::c_int) } {
+        -1 => {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                Ok(false)
+            } else {
+                Err(error)
+            }
+        }
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+#[cfg(not(unix))]
+fn stdin_ready(_timeout: Duration) -> io::Result<bool> {
+    Ok(true)
+}
+
+/// Reads whatever stdin has available
+#[cfg(unix)]
+fn read_stdin_once(buf: &mut [u8]) -> io::Result<usize> {
+    // NOTE read the file descriptor directly: data left in the buffer of `io::Stdin` would not
+    // wake up `poll`
+    match unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr().cast(), buf.len()) } {
+        -1 => Err(io::Error::last_os_error()),
+        n => Ok(n as usize),
+    }
+}
+
+#[cfg(not(unix))]
+fn read_stdin_once(buf: &mut [u8]) -> io::Result<usize> {
+    use std::io::Read as _;
+
+    // only lock stdin for the duration of the read
+    io::stdin().lock().read(buf)
+}
+
+/// Destination of the text received on an up channel
+pub(crate) struct Output {
+    file: Option<File>,
+    /// Printed at the start of every line
+    prefix: Option<String>,
+    at_line_start: bool,
+}
+
+impl Output {
+    pub(crate) fn stdout(prefix: Option<String>) -> Self {
+        Self {
+            file: None,
+            prefix,
+            at_line_start: true,
+        }
+    }
+
+    pub(crate) fn file(path: &Path) -> Result<Self, anyhow::Error> {
+        let file =
+            File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+        Ok(Self {
+            file: Some(file),
+            prefix: None,
+            at_line_start: true,
+        })
+    }
+
+    pub(crate) fn is_stdout(&self) -> bool {
+        self.file.is_none()
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) -> Result<(), anyhow::Error> {
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        let sink: &mut dyn io::Write = match &mut self.file {
+            Some(file) => file,
+            None => &mut stdout,
+        };
+
+        if let Some(prefix) = &self.prefix {
+            let mut rest = bytes;
+            while !rest.is_empty() {
+                let len = rest
+                    .iter()
+                    .position(|b| *b == b'\n')
+                    .map_or(rest.len(), |newline| newline + 1);
+                let (line, tail) = rest.split_at(len);
+
+                if self.at_line_start {
+                    sink.write_all(prefix.as_bytes())?;
+                }
+                sink.write_all(line)?;
+                self.at_line_start = line.ends_with(b"\n");
+                rest = tail;
+            }
+        } else {
+            sink.write_all(bytes)?;
+        }
+        sink.flush()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,548 @@
+//! ARM semihosting
+//!
+//! The firmware makes a semihosting request by executing `BKPT 0xAB` with the operation number
+//! in R0 and a pointer to its parameters in R1. This halts the core; the request is serviced by
+//! reading and writing the target's memory, the result is written to R0 and the core is resumed
+//! after the breakpoint instruction.
+//!
+//! Besides the console (`:tt`), files can be opened, read and written when a root directory is
+//! configured. File names are resolved relative to that directory and may not escape it.
+
+use std::{
+    cmp,
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{self, Read as _, Seek as _, SeekFrom, Write as _},
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail};
+
+use probe_rs::CoreRegisterAddress;
+
+use crate::{config::OutputFormat, event::Event, target::TargetAccess, PC};
+
+/// Thumb encoding of `BKPT 0xAB`
+const BKPT_SEMIHOSTING: u16 = 0xBEAB;
+
+const R0: CoreRegisterAddress = CoreRegisterAddress(0);
+const R1: CoreRegisterAddress = CoreRegisterAddress(1);
+
+const SYS_OPEN: u32 = 0x01;
+const SYS_CLOSE: u32 = 0x02;
+const SYS_WRITEC: u32 = 0x03;
+const SYS_WRITE0: u32 = 0x04;
+const SYS_WRITE: u32 = 0x05;
+const SYS_READ: u32 = 0x06;
+const SYS_ISTTY: u32 = 0x09;
+const SYS_SEEK: u32 = 0x0A;
+const SYS_FLEN: u32 = 0x0C;
+const SYS_ERRNO: u32 = 0x13;
+const SYS_EXIT: u32 = 0x18;
+const SYS_EXIT_EXTENDED: u32 = 0x20;
+
+/// Reason reported by `SYS_EXIT` when the program exits normally
+const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x20026;
+
+/// Returned in R0 by failed operations
+const FAILURE: u32 = u32::MAX;
+
+/// Strings longer than this are truncated; longer file names are rejected
+const MAX_STRING_LEN: u32 = 4096;
+
+/// Strings are read in blocks of this many bytes
+const STRING_BLOCK_SIZE: u32 = 64;
+
+/// `SYS_READ` and `SYS_WRITE` copy data between the target and the host in chunks of this size,
+/// whatever length the firmware asks for
+const CHUNK_SIZE: u32 = 4096;
+
+/// What to do with the core after a semihosting request has been serviced
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    /// Resume the core; R0 and PC have already been updated
+    Resume,
+    /// The program exited with this exit code
+    Exit(i32),
+}
+
+/// Reported by `SYS_ERRNO` when a file name escapes the root directory (`EACCES`)
+const EACCES: u32 = 13;
+/// Reported by `SYS_ERRNO` when a file name is longer than `MAX_STRING_LEN` (`ENAMETOOLONG`)
+const ENAMETOOLONG: u32 = 36;
+/// Reported by `SYS_ERRNO` when an operation fails for another reason (`EIO`)
+const EIO: u32 = 5;
+
+/// An open semihosting file handle
+#[derive(Debug)]
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+/// Services semihosting requests
+pub struct Semihosting {
+    format: OutputFormat,
+    /// Directory that files are opened in; without it only the console can be opened
+    root: Option<PathBuf>,
+    handles: BTreeMap<u32, Handle>,
+    next_handle: u32,
+    /// `errno` of the last failed operation, reported by `SYS_ERRNO`
+    errno: u32,
+}
+
+impl Semihosting {
+    pub fn new(format: OutputFormat) -> Self {
+        Self {
+            format,
+            root: None,
+            handles: BTreeMap::new(),
+            next_handle: 1,
+            errno: 0,
+        }
+    }
+
+    /// Lets the firmware open files in the `root` directory of the host
+    pub fn with_root(mut self, root: PathBuf) -> Self {
+        self.root = Some(root);
+        self
+    }
+
+    /// Services the semihosting request the halted `core` is making, if any
+    ///
+    /// Returns `None` if the core did not halt on a semihosting breakpoint. Text written to the
+    /// console is printed and appended to `output`.
+    pub fn service(
+        &mut self,
+        core: &mut impl TargetAccess,
+        output: &mut Vec<u8>,
+    ) -> Result<Option<Action>, anyhow::Error> {
+        let pc = core.read_core_reg(PC)?;
+        let mut instruction = [0; 2];
+        if core.read_8(pc, &mut instruction).is_err()
+            || u16::from_le_bytes(instruction) != BKPT_SEMIHOSTING
+        {
+            return Ok(None);
+        }
+
+        let operation = core.read_core_reg(R0)?;
+        let parameter = core.read_core_reg(R1)?;
+        log::trace!(
+            "semihosting operation 0x{:02x} with parameter 0x{:08x}",
+            operation,
+            parameter
+        );
+
+        let result = match operation {
+            SYS_OPEN => {
+                let params = read_words(core, parameter, 3)?;
+                if params[2] > MAX_STRING_LEN {
+                    log::error!("semihosting: file name of {} bytes is too long", params[2]);
+                    self.errno = ENAMETOOLONG;
+                    FAILURE
+                } else {
+                    let name = read_bytes(core, params[0], params[2])?;
+                    self.open(&name, params[1])
+                }
+            }
+
+            SYS_CLOSE => {
+                let handle = read_words(core, parameter, 1)?[0];
+                if self.handles.remove(&handle).is_some() {
+                    0
+                } else {
+                    FAILURE
+                }
+            }
+
+            SYS_WRITEC => {
+                let c = read_bytes(core, parameter, 1)?;
+                self.write(Console::Stdout, &c, output)?;
+                return self.resume(core, pc, None);
+            }
+
+            SYS_WRITE0 => {
+                let s = read_c_string(core, parameter)?;
+                self.write(Console::Stdout, &s, output)?;
+                return self.resume(core, pc, None);
+            }
+
+            SYS_WRITE => {
+                let params = read_words(core, parameter, 3)?;
+                let (handle, data, len) = (params[0], params[1], params[2]);
+                self.write_handle(core, handle, data, len, output)?
+            }
+
+            SYS_READ => {
+                let params = read_words(core, parameter, 3)?;
+                let (handle, buffer, len) = (params[0], params[1], params[2]);
+                self.read_handle(core, handle, buffer, len)?
+            }
+
+            SYS_ISTTY => {
+                let handle = read_words(core, parameter, 1)?[0];
+                match self.handles.get(&handle) {
+                    Some(Handle::File(_)) => 0,
+                    Some(_) => 1,
+                    None => FAILURE,
+                }
+            }
+
+            SYS_SEEK => {
+                let params = read_words(core, parameter, 2)?;
+                let (handle, position) = (params[0], params[1]);
+                match self.handles.get_mut(&handle) {
+                    Some(Handle::File(file)) => {
+                        match file.seek(SeekFrom::Start(u64::from(position))) {
+                            Ok(_) => 0,
+                            Err(e) => {
+                                self.errno = errno(&e);
+                                FAILURE
+                            }
+                        }
+                    }
+                    _ => FAILURE,
+                }
+            }
+
+            SYS_FLEN => {
+                let handle = read_words(core, parameter, 1)?[0];
+                match self.handles.get(&handle) {
+                    Some(Handle::File(file)) => match file.metadata() {
+                        Ok(metadata) => metadata.len() as u32,
+                        Err(e) => {
+                            self.errno = errno(&e);
+                            FAILURE
+                        }
+                    },
+                    _ => FAILURE,
+                }
+            }
+
+            SYS_ERRNO => self.errno,
+
+            SYS_EXIT => {
+                // on 32-bit targets the parameter is the reason itself
+                let code = if parameter == ADP_STOPPED_APPLICATION_EXIT {
+                    0
+                } else {
+                    1
+                };
+                return Ok(Some(Action::Exit(code)));
+            }
+
+            SYS_EXIT_EXTENDED => {
+                let params = read_words(core, parameter, 2)?;
+                let code = if params[0] == ADP_STOPPED_APPLICATION_EXIT {
+                    params[1] as i32
+                } else {
+                    1
+                };
+                return Ok(Some(Action::Exit(code)));
+            }
+
+            _ => {
+                log::warn!("semihosting operation 0x{:02x} is not supported", operation);
+                FAILURE
+            }
+        };
+
+        self.resume(core, pc, Some(result))
+    }
+
+    /// Opens the special file `:tt`, the console, or a file in the root directory
+    fn open(&mut self, name: &[u8], mode: u32) -> u32 {
+        // modes 0-3 are `r` variants, 4-7 are `w` variants and 8-11 are `a` variants
+        let handle = if name == b":tt" {
+            match mode {
+                0..=3 => Handle::Stdin,
+                4..=7 => Handle::Stdout,
+                _ => Handle::Stderr,
+            }
+        } else {
+            let name = String::from_utf8_lossy(name);
+            match self.open_file(&name, mode) {
+                Ok(file) => Handle::File(file),
+                Err(e) => {
+                    log::error!("semihosting: can't open `{}`: {}", name, e);
+                    self.errno = match e.downcast_ref::<io::Error>() {
+                        Some(e) => errno(e),
+                        None => EACCES,
+                    };
+                    return FAILURE;
+                }
+            }
+        };
+
+        let number = self.next_handle;
+        self.next_handle += 1;
+        self.handles.insert(number, handle);
+        number
+    }
+
+    fn open_file(&self, name: &str, mode: u32) -> Result<File, anyhow::Error> {
+        let root = self.root.as_ref().ok_or_else(|| {
+            anyhow!(
+                "only the console (`:tt`) can be opened; use `--semihosting-root` to allow files"
+            )
+        })?;
+        let path = resolve(root, name)?;
+
+        let mut options = OpenOptions::new();
+        match mode {
+            // r, rb
+            0 | 1 => options.read(true),
+            // r+, r+b
+            2 | 3 => options.read(true).write(true),
+            // w, wb
+            4 | 5 => options.write(true).create(true).truncate(true),
+            // w+, w+b
+            6 | 7 => options.read(true).write(true).create(true).truncate(true),
+            // a, ab
+            8 | 9 => options.append(true).create(true),
+            // a+, a+b
+            10 | 11 => options.read(true).append(true).create(true),
+            _ => bail!("invalid mode {}", mode),
+        };
+
+        let file = options.open(&path)?;
+        log::debug!("semihosting: opened {}", path.display());
+        Ok(file)
+    }
+
+    /// Writes the `len` bytes at `data` to `handle`; returns the number of bytes that were *not*
+    /// written
+    fn write_handle(
+        &mut self,
+        core: &mut impl TargetAccess,
+        handle: u32,
+        data: u32,
+        len: u32,
+        output: &mut Vec<u8>,
+    ) -> Result<u32, anyhow::Error> {
+        match self.handles.get(&handle) {
+            Some(Handle::Stdin) | None => return Ok(len),
+            _ => {}
+        }
+
+        let mut buffer = [0; CHUNK_SIZE as usize];
+        let mut written = 0;
+        while written < len {
+            let chunk = &mut buffer[..cmp::min(len - written, CHUNK_SIZE) as usize];
+            core.read_8(data + written, chunk)?;
+
+            match self.handles.get_mut(&handle) {
+                Some(Handle::Stdout) => self.write(Console::Stdout, chunk, output)?,
+                Some(Handle::Stderr) => self.write(Console::Stderr, chunk, output)?,
+                Some(Handle::File(file)) => {
+                    if let Err(e) = file.write_all(chunk) {
+                        log::warn!("semihosting: failed to write to handle {}: {}", handle, e);
+                        self.errno = errno(&e);
+                        break;
+                    }
+                }
+                _ => break,
+            }
+            written += chunk.len() as u32;
+        }
+
+        Ok(len - written)
+    }
+
+    /// Reads up to `len` bytes from `handle` into `buffer`; returns the number of bytes that were
+    /// *not* read, `len` meaning end of file
+    fn read_handle(
+        &mut self,
+        core: &mut impl TargetAccess,
+        handle: u32,
+        buffer: u32,
+        len: u32,
+    ) -> Result<u32, anyhow::Error> {
+        let file = match self.handles.get_mut(&handle) {
+            Some(Handle::File(file)) => file,
+            // reading from the console is not supported; report end of file
+            _ => return Ok(len),
+        };
+
+        let mut chunk = [0; CHUNK_SIZE as usize];
+        let mut read = 0;
+        while read < len {
+            let count = cmp::min(len - read, CHUNK_SIZE) as usize;
+            let n = match file.read(&mut chunk[..count]) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    log::warn!("semihosting: failed to read from handle {}: {}", handle, e);
+                    self.errno = errno(&e);
+                    return Ok(FAILURE);
+                }
+            };
+            core.write_8(buffer + read, &chunk[..n])?;
+            read += n as u32;
+        }
+
+        Ok(len - read)
+    }
+
+    fn write(
+        &mut self,
+        console: Console,
+        bytes: &[u8],
+        output: &mut Vec<u8>,
+    ) -> Result<(), anyhow::Error> {
+        match (console, self.format) {
+            (Console::Stdout, OutputFormat::Json) => Event::output("semihosting", bytes).emit()?,
+            (Console::Stdout, OutputFormat::Human) => {
+                let stdout = io::stdout();
+                let mut stdout = stdout.lock();
+                stdout.write_all(bytes)?;
+                stdout.flush()?;
+            }
+            (Console::Stderr, _) => {
+                let stderr = io::stderr();
+                let mut stderr = stderr.lock();
+                stderr.write_all(bytes)?;
+                stderr.flush()?;
+            }
+        }
+
+        if console == Console::Stdout {
+            output.extend_from_slice(bytes);
+        }
+        Ok(())
+    }
+
+    /// Stores `result` in R0 and moves the PC past the breakpoint instruction
+    fn resume(
+        &mut self,
+        core: &mut impl TargetAccess,
+        pc: u32,
+        result: Option<u32>,
+    ) -> Result<Option<Action>, anyhow::Error> {
+        if let Some(result) = result {
+            core.write_core_reg(R0, result)?;
+        }
+        core.write_core_reg(PC, pc + 2)?;
+        Ok(Some(Action::Resume))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Console {
+    Stdout,
+    Stderr,
+}
+
+/// Resolves the file `name` requested by the firmware to a path inside `root`
+///
+/// The name must be relative and may not leave `root` through `..` components or symlinks. The
+/// returned path is the canonical directory of the file joined with its name; a symlink in its
+/// place must point inside `root`, even when the file is opened for writing.
+fn resolve(root: &Path, name: &str) -> Result<PathBuf, anyhow::Error> {
+    let mut relative = PathBuf::new();
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::CurDir => {}
+            Component::ParentDir if relative.pop() => {}
+            _ => bail!("the path escapes the semihosting root {}", root.display()),
+        }
+    }
+    if relative.as_os_str().is_empty() {
+        bail!("not a file name");
+    }
+
+    let path = root.join(&relative);
+    let escapes = || anyhow!("the path escapes the semihosting root {}", root.display());
+
+    // symlinks inside the root could still point outside of it
+    let root = root.canonicalize()?;
+    let file_name = path.file_name().ok_or_else(|| anyhow!("not a file name"))?;
+    let parent = path.parent().unwrap_or(&root).canonicalize()?;
+    if !parent.starts_with(&root) {
+        return Err(escapes());
+    }
+    let path = parent.join(file_name);
+
+    // a symlink is followed when the file is opened, even one whose target doesn't exist yet
+    let is_symlink = path
+        .symlink_metadata()
+        .map(|metadata| metadata.file_type().is_symlink())
+        .unwrap_or(false);
+    if is_symlink {
+        match path.canonicalize() {
+            Ok(target) if target.starts_with(&root) => {}
+            _ => return Err(escapes()),
+        }
+    }
+
+    Ok(path)
+}
+
+/// The `errno` reported to the firmware for `error`
+fn errno(error: &io::Error) -> u32 {
+    error.raw_os_error().map(|code| code as u32).unwrap_or(EIO)
+}
+
+/// Reads the parameter block of an operation
+fn read_words(
+    core: &mut impl TargetAccess,
+    address: u32,
+    count: usize,
+) -> Result<Vec<u32>, anyhow::Error> {
+    let mut words = vec![0; count];
+    core.read_32(address, &mut words)?;
+    Ok(words)
+}
+
+fn read_bytes(
+    core: &mut impl TargetAccess,
+    address: u32,
+    len: u32,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let mut bytes = vec![0; len as usize];
+    core.read_8(address, &mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_c_string(core: &mut impl TargetAccess, address: u32) -> Result<Vec<u8>, anyhow::Error> {
+    let mut string = vec![];
+
+    // blocks are aligned to their size so that reading past the NUL byte stays within the memory
+    // region the string is in
+    let mut block = address & !(STRING_BLOCK_SIZE - 1);
+    let mut start = (address - block) as usize;
+    loop {
+        let mut words = [0; STRING_BLOCK_SIZE as usize / 4];
+        if core.read_32(block, &mut words).is_err() {
+            break;
+        }
+
+        let bytes = words
+            .iter()
+            .flat_map(|word| word.to_le_bytes().to_vec())
+            .collect::<Vec<_>>();
+        for &byte in &bytes[start..] {
+            if byte == 0 || string.len() == MAX_STRING_LEN as usize {
+                return Ok(string);
+            }
+            string.push(byte);
+        }
+        start = 0;
+        block += STRING_BLOCK_SIZE;
+    }
+
+    // the block could not be read as a whole; read the rest of the string a byte at a time
+    while string.len() < MAX_STRING_LEN as usize {
+        let mut byte = [0];
+        core.read_8(address + string.len() as u32, &mut byte)?;
+        if byte[0] == 0 {
+            break;
+        }
+        string.push(byte[0]);
+    }
+    Ok(string)
+}
@@ -0,0 +1,44 @@
+//! Splitting the host's stdin into the chunks forwarded to an RTT down channel
+//!
+//! The reading itself happens on a background thread; see `rtt::Input`.
+
+use std::mem;
+
+use crate::config::StdinMode;
+
+/// Decides which of the bytes read from stdin are sent to the device, and when
+#[derive(Debug)]
+pub struct Splitter {
+    mode: StdinMode,
+    /// Bytes of a line whose newline hasn't been read yet
+    line: Vec<u8>,
+}
+
+impl Splitter {
+    pub fn new(mode: StdinMode) -> Self {
+        Self { mode, line: vec![] }
+    }
+
+    /// The bytes to send after `read` came in; empty if there's nothing to send yet
+    ///
+    /// In `Line` mode everything up to the last newline is sent; in `Raw` mode everything is sent
+    /// right away; in `Off` mode nothing is sent.
+    pub fn received(&mut self, read: &[u8]) -> Vec<u8> {
+        match self.mode {
+            StdinMode::Line => {
+                self.line.extend_from_slice(read);
+                match self.line.iter().rposition(|&byte| byte == b'\n') {
+                    Some(end) => self.line.drain(..=end).collect(),
+                    None => vec![],
+                }
+            }
+            StdinMode::Raw => read.to_vec(),
+            StdinMode::Off => vec![],
+        }
+    }
+
+    /// The bytes left to send once stdin is closed, i.e. a last line without a newline
+    pub fn finish(&mut self) -> Vec<u8> {
+        mem::take(&mut self.line)
+    }
+}
@@ -0,0 +1,183 @@
+//! Access to the state of the target device
+//!
+//! The unwinder and the stack canary only need to read and write registers and memory. Doing so
+//! through the [`TargetAccess`] trait lets them work on a live [`Core`] as well as on an
+//! in-memory [`FakeTarget`].
+
+use std::collections::BTreeMap;
+
+use anyhow::anyhow;
+use probe_rs::{Core, CoreRegisterAddress, MemoryInterface};
+
+/// Register and memory access to a (possibly halted) Cortex-M core
+pub trait TargetAccess {
+    fn read_core_reg(&mut self, reg: CoreRegisterAddress) -> Result<u32, anyhow::Error>;
+
+    fn write_core_reg(&mut self, reg: CoreRegisterAddress, value: u32)
+        -> Result<(), anyhow::Error>;
+
+    fn read_8(&mut self, address: u32, data: &mut [u8]) -> Result<(), anyhow::Error>;
+
+    fn write_8(&mut self, address: u32, data: &[u8]) -> Result<(), anyhow::Error>;
+
+    fn core_halted(&mut self) -> Result<bool, anyhow::Error>;
+
+    fn read_32(&mut self, address: u32, data: &mut [u32]) -> Result<(), anyhow::Error> {
+        let mut bytes = vec![0; data.len() * 4];
+        self.read_8(address, &mut bytes)?;
+        for (word, chunk) in data.iter_mut().zip(bytes.chunks_exact(4)) {
+            *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        Ok(())
+    }
+
+    fn read_word_32(&mut self, address: u32) -> Result<u32, anyhow::Error> {
+        let mut word = [0];
+        self.read_32(address, &mut word)?;
+        Ok(word[0])
+    }
+
+    fn write_word_32(&mut self, address: u32, value: u32) -> Result<(), anyhow::Error> {
+        self.write_8(address, &value.to_le_bytes())
+    }
+}
+
+impl TargetAccess for Core<'_> {
+    fn read_core_reg(&mut self, reg: CoreRegisterAddress) -> Result<u32, anyhow::Error> {
+        Ok(Core::read_core_reg(self, reg)?)
+    }
+
+    fn write_core_reg(
+        &mut self,
+        reg: CoreRegisterAddress,
+        value: u32,
+    ) -> Result<(), anyhow::Error> {
+        Ok(Core::write_core_reg(self, reg, value)?)
+    }
+
+    fn read_8(&mut self, address: u32, data: &mut [u8]) -> Result<(), anyhow::Error> {
+        Ok(MemoryInterface::read_8(self, address, data)?)
+    }
+
+    fn write_8(&mut self, address: u32, data: &[u8]) -> Result<(), anyhow::Error> {
+        Ok(MemoryInterface::write_8(self, address, data)?)
+    }
+
+    fn core_halted(&mut self) -> Result<bool, anyhow::Error> {
+        Ok(Core::core_halted(self)?)
+    }
+
+    // use word-sized accesses; some memory, like peripheral registers, can't be read byte-wise
+    fn read_32(&mut self, address: u32, data: &mut [u32]) -> Result<(), anyhow::Error> {
+        Ok(MemoryInterface::read_32(self, address, data)?)
+    }
+
+    fn read_word_32(&mut self, address: u32) -> Result<u32, anyhow::Error> {
+        Ok(MemoryInterface::read_word_32(self, address)?)
+    }
+
+    fn write_word_32(&mut self, address: u32, value: u32) -> Result<(), anyhow::Error> {
+        Ok(MemoryInterface::write_word_32(self, address, value)?)
+    }
+}
+
+/// An in-memory stand-in for a halted core
+///
+/// Its state is a set of core registers plus a number of memory regions. Accessing a register
+/// that was not set or memory outside the regions is an error, like it would be on hardware.
+#[derive(Clone, Debug)]
+pub struct FakeTarget {
+    registers: BTreeMap<u16, u32>,
+    regions: Vec<(u32, Vec<u8>)>,
+    halted: bool,
+}
+
+impl FakeTarget {
+    /// Creates a halted target with no registers and no memory
+    pub fn new() -> Self {
+        Self {
+            registers: BTreeMap::new(),
+            regions: vec![],
+            halted: true,
+        }
+    }
+
+    /// Sets the value of core register `reg`
+    pub fn with_register(mut self, reg: CoreRegisterAddress, value: u32) -> Self {
+        self.registers.insert(reg.0, value);
+        self
+    }
+
+    /// Adds a memory region starting at `address` and filled with `data`
+    pub fn with_memory(mut self, address: u32, data: Vec<u8>) -> Self {
+        self.regions.push((address, data));
+        self
+    }
+
+    /// Adds a memory region starting at `address` and filled with the little endian `words`
+    pub fn with_words(self, address: u32, words: &[u32]) -> Self {
+        let data = words.iter().flat_map(|word| word.to_le_bytes().to_vec());
+        self.with_memory(address, data.collect())
+    }
+
+    pub fn set_halted(&mut self, halted: bool) {
+        self.halted = halted;
+    }
+
+    /// Returns the memory region that fully contains `address..address + len`
+    fn region_mut(&mut self, address: u32, len: usize) -> Result<&mut [u8], anyhow::Error> {
+        let end = u64::from(address) + len as u64;
+        self.regions
+            .iter_mut()
+            .find(|(start, data)| address >= *start && end <= u64::from(*start) + data.len() as u64)
+            .map(|(start, data)| {
+                let offset = (address - *start) as usize;
+                &mut data[offset..offset + len]
+            })
+            .ok_or_else(|| {
+                anyhow!(
+                    "memory at 0x{:08X}..0x{:08X} is not available",
+                    address,
+                    end
+                )
+            })
+    }
+}
+
+impl Default for FakeTarget {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TargetAccess for FakeTarget {
+    fn read_core_reg(&mut self, reg: CoreRegisterAddress) -> Result<u32, anyhow::Error> {
+        self.registers
+            .get(&reg.0)
+            .copied()
+            .ok_or_else(|| anyhow!("register {} is not available", reg.0))
+    }
+
+    fn write_core_reg(
+        &mut self,
+        reg: CoreRegisterAddress,
+        value: u32,
+    ) -> Result<(), anyhow::Error> {
+        self.registers.insert(reg.0, value);
+        Ok(())
+    }
+
+    fn read_8(&mut self, address: u32, data: &mut [u8]) -> Result<(), anyhow::Error> {
+        data.copy_from_slice(self.region_mut(address, data.len())?);
+        Ok(())
+    }
+
+    fn write_8(&mut self, address: u32, data: &[u8]) -> Result<(), anyhow::Error> {
+        self.region_mut(address, data.len())?.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn core_halted(&mut self) -> Result<bool, anyhow::Error> {
+        Ok(self.halted)
+    }
+}
@@ -0,0 +1,108 @@
+//! Halting on exception entry with the vector catch bits of the Debug Exception and Monitor
+//! Control Register
+//!
+//! A vector catch halts the core on entry to the exception's handler, like a breakpoint on the
+//! handler would, but doesn't take up one of the few hardware breakpoints. Catch bits that the
+//! core doesn't implement read as zero; faults without a working catch fall back to breakpoints.
+
+use crate::{exception::Exception, target::TargetAccess};
+
+/// Debug Exception and Monitor Control Register
+const DEMCR: u32 = 0xE000_EDFC;
+
+// DEMCR bits
+/// Core reset
+const VC_CORERESET: u32 = 1 << 0;
+/// MemManage exception
+const VC_MMERR: u32 = 1 << 4;
+/// UsageFault caused by an access to a disabled or absent coprocessor
+const VC_NOCPERR: u32 = 1 << 5;
+/// UsageFault caused by a checking error, e.g. an unaligned access
+const VC_CHKERR: u32 = 1 << 6;
+/// UsageFault caused by a state information error, e.g. an undefined instruction
+const VC_STATERR: u32 = 1 << 7;
+/// BusFault exception
+const VC_BUSERR: u32 = 1 << 8;
+/// HardFault exception
+const VC_HARDERR: u32 = 1 << 10;
+/// SecureFault exception (ARMv8-M)
+const VC_SFERR: u32 = 1 << 11;
+
+/// All the catch bits this module may set
+const VC_MASK: u32 = VC_CORERESET
+    | VC_MMERR
+    | VC_NOCPERR
+    | VC_CHKERR
+    | VC_STATERR
+    | VC_BUSERR
+    | VC_HARDERR
+    | VC_SFERR;
+
+/// The DEMCR bits that catch `fault`; a UsageFault takes three of them
+fn catch_bits(fault: Exception) -> u32 {
+    match fault {
+        Exception::HARD_FAULT => VC_HARDERR,
+        Exception::MEM_MANAGE => VC_MMERR,
+        Exception::BUS_FAULT => VC_BUSERR,
+        Exception::USAGE_FAULT => VC_NOCPERR | VC_CHKERR | VC_STATERR,
+        Exception::SECURE_FAULT => VC_SFERR,
+        _ => 0,
+    }
+}
+
+/// The vector catches set up for a run
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VectorCatch {
+    /// The DEMCR value before the catches were enabled
+    original: u32,
+    /// The catch bits that the core accepted
+    enabled: u32,
+}
+
+impl VectorCatch {
+    /// Enables the vector catches of `faults` and, with `reset`, of core resets
+    pub fn enable(
+        core: &mut impl TargetAccess,
+        faults: &[Exception],
+        reset: bool,
+    ) -> Result<Self, anyhow::Error> {
+        let mut requested = faults
+            .iter()
+            .fold(0, |bits, &fault| bits | catch_bits(fault));
+        if reset {
+            requested |= VC_CORERESET;
+        }
+
+        let original = core.read_word_32(DEMCR)?;
+        core.write_word_32(DEMCR, original | requested)?;
+        // unimplemented catch bits are RAZ/WI
+        let enabled = core.read_word_32(DEMCR)? & requested;
+        log::debug!(
+            "vector catch: requested 0x{:04x}, enabled 0x{:04x}",
+            requested,
+            enabled
+        );
+
+        Ok(Self { original, enabled })
+    }
+
+    /// Whether the core halts on entry to the handler of `fault`
+    pub fn catches(&self, fault: Exception) -> bool {
+        let bits = catch_bits(fault);
+        bits != 0 && self.enabled & bits == bits
+    }
+
+    /// Whether the core halts at the reset handler when it resets
+    pub fn catches_reset(&self) -> bool {
+        self.enabled & VC_CORERESET != 0
+    }
+
+    /// Restores the catch bits to their value before `enable`
+    ///
+    /// DEMCR is only reset on power-up; left enabled, the catches would keep halting the firmware
+    /// on faults after `probe-run` exits.
+    pub fn restore(&self, core: &mut impl TargetAccess) -> Result<(), anyhow::Error> {
+        let demcr = core.read_word_32(DEMCR)?;
+        core.write_word_32(DEMCR, demcr & !VC_MASK | self.original & VC_MASK)
+    }
+}
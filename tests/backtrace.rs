@@ -0,0 +1,431 @@
+//! Replays recorded HardFault scenarios through the unwinder
+//!
+//! The firmware is `fixtures/hard-fault.elf`; see `fixtures/hard-fault.s` for its source. Its
+//! call chain is `Reset` -> `main` -> `foo` and `foo` executes an undefined instruction.
+//!
+//! `fixtures/cfi-rules.elf` exercises the less common CFI register rules; see
+//! `fixtures/cfi-rules.s`.
+//!
+//! `fixtures/locals.elf` has hand-written debug information for the locals of its `main`; see
+//! `fixtures/locals.s`.
+
+use std::{fs, path::Path};
+
+use probe_rs::CoreRegisterAddress;
+use probe_run::{
+    backtrace::{self, Backtrace, ExceptionEntry, Frame, TopException},
+    elf::{ProcessedElf, VectorTable},
+    exception::Exception,
+    target::{FakeTarget, TargetAccess as _},
+    LR, PC, SP, XPSR,
+};
+
+const RAM: std::ops::Range<u32> = 0x2000_0000..0x2000_1000;
+
+// addresses in `hard-fault.elf`
+const RESET_AFTER_CALL: u32 = 0x16;
+const MAIN_AFTER_CALL: u32 = 0x1e;
+const FOO_UDF: u32 = 0x22;
+const HARD_FAULT: u32 = 0x26;
+
+const EXC_RETURN_THREAD_MSP: u32 = 0xFFFF_FFF9;
+const LR_END: u32 = 0xFFFF_FFFF;
+const THUMB_BIT: u32 = 1;
+
+/// Contents of the stack, from its top (highest address) to the frame of `foo`; every function
+/// starts with `push {r7, lr}`
+fn call_frames() -> [u32; 6] {
+    [
+        LR_END,                       // Reset's LR
+        0,                            // Reset's R7
+        RESET_AFTER_CALL | THUMB_BIT, // main's LR
+        0,                            // main's R7
+        MAIN_AFTER_CALL | THUMB_BIT,  // foo's LR
+        0,                            // foo's R7
+    ]
+}
+
+/// Basic exception frame pushed by the hardware on entry to the HardFault handler
+fn exception_frame() -> [u32; 8] {
+    [
+        0,                           // r0
+        1,                           // r1
+        2,                           // r2
+        3,                           // r3
+        12,                          // r12
+        MAIN_AFTER_CALL | THUMB_BIT, // lr
+        FOO_UDF,                     // pc
+        0x0100_0003,                 // xPSR: Thumb state; IPSR = HardFault
+    ]
+}
+
+/// Lays out `words` so that the last one ends at `top`; returns the address of the first word
+fn stack(top: u32, words: &[u32]) -> (u32, Vec<u32>) {
+    let mut stack = words.to_vec();
+    stack.reverse();
+    (top - 4 * words.len() as u32, stack)
+}
+
+fn unwind(target: &mut FakeTarget, sp_ram_region: Option<&std::ops::Range<u32>>) -> Backtrace {
+    unwind_with(target, sp_ram_region, |_| {})
+}
+
+/// Like `unwind` but lets `patch` modify the firmware's vector table first
+fn unwind_with(
+    target: &mut FakeTarget,
+    sp_ram_region: Option<&std::ops::Range<u32>>,
+    patch: impl FnOnce(&mut VectorTable),
+) -> Backtrace {
+    unwind_firmware("hard-fault.elf", target, sp_ram_region, patch, false)
+}
+
+/// Unwinds the stack of `target`, which runs the firmware `fixture`; reads the locals of the
+/// frames if `locals` is set
+fn unwind_firmware(
+    fixture: &str,
+    target: &mut FakeTarget,
+    sp_ram_region: Option<&std::ops::Range<u32>>,
+    patch: impl FnOnce(&mut VectorTable),
+    locals: bool,
+) -> Backtrace {
+    let bytes = fs::read(Path::new("tests/fixtures").join(fixture)).unwrap();
+    let mut elf = ProcessedElf::parse(&bytes, &[]).unwrap();
+    patch(&mut elf.vector_table);
+    let pc = target.read_core_reg(PC).unwrap();
+
+    backtrace::backtrace(
+        target,
+        pc,
+        elf.debug_frame.unwrap(),
+        &elf.elf,
+        &elf.vector_table,
+        sp_ram_region,
+        &elf.live_functions,
+        Path::new("/"),
+        locals,
+    )
+    .unwrap()
+}
+
+fn names(backtrace: &Backtrace) -> Vec<&str> {
+    backtrace
+        .frames
+        .iter()
+        .map(|frame| match frame {
+            Frame::Subroutine(subroutine) => &*subroutine.name,
+            Frame::Exception(_) => "<exception entry>",
+        })
+        .collect()
+}
+
+/// Stack with the frames of `Reset`, `main` and `foo` followed by an exception frame, starting
+/// at `top`; returns the target halted at the HardFault handler
+fn hard_fault_at(top: u32) -> FakeTarget {
+    let mut words = call_frames().to_vec();
+    // the exception frame is pushed in reverse order
+    words.extend(exception_frame().iter().rev());
+    let (sp, memory) = stack(top, &words);
+
+    FakeTarget::new()
+        .with_register(PC, HARD_FAULT)
+        .with_register(LR, EXC_RETURN_THREAD_MSP)
+        .with_register(SP, sp)
+        // IPSR = HardFault
+        .with_register(XPSR, 0x0100_0003)
+        .with_words(sp, &memory)
+}
+
+#[test]
+fn hard_fault() {
+    let mut target = hard_fault_at(RAM.end);
+
+    let backtrace = unwind(&mut target, Some(&RAM));
+
+    assert_eq!(
+        names(&backtrace),
+        ["HardFault", "<exception entry>", "foo", "main", "Reset"]
+    );
+    assert_eq!(
+        backtrace.top_exception,
+        Some(TopException::HardFault {
+            exception: Exception(3),
+            stack_overflow: false
+        })
+    );
+    assert!(!backtrace.corrupted);
+    assert_eq!(backtrace.interrupted_pc(), Some(FOO_UDF));
+    assert_eq!(
+        backtrace
+            .exception_frame
+            .as_ref()
+            .map(|stacked| stacked.r12),
+        Some(12)
+    );
+    assert_eq!(
+        backtrace.to_string(),
+        "stack backtrace:
+   0: HardFault
+      <exception entry: HardFault>
+   1: foo
+   2: main
+   3: Reset
+"
+    );
+}
+
+#[test]
+fn hard_fault_caused_by_stack_overflow() {
+    // the stack pointer has left RAM
+    let mut target = hard_fault_at(RAM.start);
+
+    let backtrace = unwind(&mut target, Some(&RAM));
+
+    assert_eq!(
+        backtrace.top_exception,
+        Some(TopException::HardFault {
+            exception: Exception(3),
+            stack_overflow: true
+        })
+    );
+}
+
+#[test]
+fn handler_shared_by_several_faults() {
+    // `HardFault` also handles BusFault, like `cortex-m-rt`'s `DefaultHandler`
+    let mut target = hard_fault_at(RAM.end).with_register(XPSR, 0x0100_0005);
+
+    let backtrace = unwind_with(&mut target, Some(&RAM), |vector_table| {
+        vector_table.vectors.resize(6, 0);
+        vector_table.vectors[5] = HARD_FAULT | THUMB_BIT;
+    });
+
+    assert_eq!(
+        backtrace.top_exception,
+        Some(TopException::HardFault {
+            exception: Exception(5),
+            stack_overflow: false
+        })
+    );
+    assert!(backtrace
+        .to_string()
+        .contains("<exception entry: BusFault>"));
+}
+
+#[test]
+fn halted_outside_of_exception() {
+    // e.g. a `bkpt` instruction in `foo`
+    let (sp, memory) = stack(RAM.end, &call_frames());
+    let mut target = FakeTarget::new()
+        .with_register(PC, FOO_UDF)
+        .with_register(LR, MAIN_AFTER_CALL | THUMB_BIT)
+        .with_register(SP, sp)
+        .with_words(sp, &memory);
+
+    let backtrace = unwind(&mut target, Some(&RAM));
+
+    assert_eq!(names(&backtrace), ["foo", "main", "Reset"]);
+    assert_eq!(backtrace.top_exception, Some(TopException::Other));
+    assert_eq!(backtrace.interrupted_pc(), None);
+}
+
+#[test]
+fn corrupted_stack() {
+    // returning from `HardFault` would land in `HardFault` again, with the same stack pointer
+    let mut target = FakeTarget::new()
+        .with_register(PC, HARD_FAULT)
+        .with_register(LR, HARD_FAULT | THUMB_BIT)
+        .with_register(SP, RAM.end);
+
+    let backtrace = unwind(&mut target, Some(&RAM));
+
+    assert_eq!(names(&backtrace), ["HardFault"]);
+    assert!(backtrace.corrupted);
+    assert!(backtrace
+        .to_string()
+        .ends_with("error: the stack appears to be corrupted beyond this point\n"));
+}
+
+#[test]
+fn unreadable_stack() {
+    // only the frames of `foo` and `main` are readable; `Reset`'s is missing
+    let (sp, memory) = stack(RAM.end, &call_frames());
+    let mut target = FakeTarget::new()
+        .with_register(PC, FOO_UDF)
+        .with_register(LR, MAIN_AFTER_CALL | THUMB_BIT)
+        .with_register(SP, sp)
+        .with_words(sp, &memory[..4]);
+
+    let backtrace = unwind(&mut target, Some(&RAM));
+
+    assert_eq!(names(&backtrace), ["foo", "main", "Reset"]);
+    assert!(backtrace.incomplete.is_some());
+    assert!(backtrace
+        .to_string()
+        .contains("error: cannot unwind further: failed to recover register"));
+}
+
+#[test]
+fn locked_up_outside_of_the_firmware() {
+    // the PC of a core that locked up on exception entry or return
+    let mut target = hard_fault_at(RAM.end).with_register(PC, 0xEFFF_FFFE);
+
+    let backtrace = unwind(&mut target, Some(&RAM));
+
+    assert_eq!(names(&backtrace), ["???"]);
+    assert_eq!(backtrace.top_exception, Some(TopException::Other));
+    assert!(backtrace
+        .incomplete
+        .as_deref()
+        .unwrap()
+        .starts_with("debug information is missing"));
+}
+
+#[test]
+fn exception_entries() {
+    let entry = |exception, handler: Option<&str>| {
+        ExceptionEntry {
+            exception,
+            handler: handler.map(str::to_owned),
+        }
+        .to_string()
+    };
+
+    assert_eq!(entry(None, None), "<exception entry>");
+    assert_eq!(
+        entry(Some(Exception(15)), None),
+        "<exception entry: SysTick>"
+    );
+    assert_eq!(
+        entry(Some(Exception(39)), Some("USART1")),
+        "<exception entry: IRQ 23 (USART1)>"
+    );
+}
+
+// addresses in `cfi-rules.elf`
+const CFI_RESET_AFTER_CALL: u32 = 0x14;
+const CFI_MAIN_AFTER_CALL: u32 = 0x20;
+const WITH_SAME_VALUE_AFTER_CALL: u32 = 0x28;
+const WITH_VAL_EXPRESSION_AFTER_CALL: u32 = 0x32;
+const WITH_REGISTER_BKPT: u32 = 0x36;
+
+const R4: CoreRegisterAddress = CoreRegisterAddress(4);
+const R7: CoreRegisterAddress = CoreRegisterAddress(7);
+const R12: CoreRegisterAddress = CoreRegisterAddress(12);
+
+/// `cfi-rules.elf` halted on the breakpoint in `with_register`
+fn halted_in_with_register() -> FakeTarget {
+    // `main` sets up its frame pointer at the top of the stack
+    let main_frame_pointer = RAM.end - 8;
+    let (sp, memory) = stack(
+        RAM.end,
+        &[
+            CFI_RESET_AFTER_CALL | THUMB_BIT,       // main's LR
+            0,                                      // main's R7
+            0xAAAA_AAAA,                            // main's locals
+            0xAAAA_AAAA,                            //
+            0xAAAA_AAAA,                            //
+            0xAAAA_AAAA,                            //
+            CFI_MAIN_AFTER_CALL | THUMB_BIT,        // with_same_value's LR
+            4,                                      // with_same_value's R4
+            WITH_SAME_VALUE_AFTER_CALL | THUMB_BIT, // with_val_expression's LR
+            main_frame_pointer,                     // with_val_expression's R7
+        ],
+    );
+
+    FakeTarget::new()
+        .with_register(PC, WITH_REGISTER_BKPT)
+        // not the return address; `with_register` keeps that in R12
+        .with_register(LR, 0)
+        .with_register(SP, sp)
+        .with_register(R4, 0)
+        // overwritten by `with_val_expression`
+        .with_register(R7, 0)
+        .with_register(R12, WITH_VAL_EXPRESSION_AFTER_CALL | THUMB_BIT)
+        .with_words(sp, &memory)
+}
+
+fn pcs(backtrace: &Backtrace) -> Vec<u32> {
+    backtrace
+        .frames
+        .iter()
+        .filter_map(|frame| match frame {
+            Frame::Subroutine(subroutine) => Some(subroutine.pc),
+            Frame::Exception(_) => None,
+        })
+        .collect()
+}
+
+#[test]
+fn cfi_register_rules() {
+    let mut target = halted_in_with_register();
+
+    let backtrace = unwind_firmware("cfi-rules.elf", &mut target, Some(&RAM), |_| {}, false);
+
+    // every frame was recovered and `Reset`'s undefined return address ended the unwinding
+    assert_eq!(
+        names(&backtrace),
+        [
+            "with_register",
+            "with_val_expression",
+            "with_same_value",
+            "main",
+            "Reset"
+        ]
+    );
+    assert_eq!(
+        pcs(&backtrace),
+        [
+            WITH_REGISTER_BKPT,
+            WITH_VAL_EXPRESSION_AFTER_CALL,
+            WITH_SAME_VALUE_AFTER_CALL,
+            CFI_MAIN_AFTER_CALL,
+            CFI_RESET_AFTER_CALL
+        ]
+    );
+    assert!(!backtrace.corrupted);
+    assert_eq!(backtrace.incomplete, None);
+}
+
+// addresses in `locals.elf`
+const LOCALS_RESET_AFTER_CALL: u32 = 0x14;
+const LOCALS_MAIN_BKPT: u32 = 0x1a;
+
+#[test]
+fn locals_are_read_only_as_far_as_they_are_formatted() {
+    let (sp, memory) = stack(
+        RAM.end,
+        &[
+            LOCALS_RESET_AFTER_CALL | THUMB_BIT, // main's LR
+            0,                                   // main's R7
+            0,                                   // padding
+            7,                                   // count
+        ],
+    );
+    // `packet` and `buffer` are 64 KiB each but only `packet.len` is mapped
+    let mut target = FakeTarget::new()
+        .with_register(PC, LOCALS_MAIN_BKPT)
+        .with_register(LR, 0)
+        .with_register(SP, sp)
+        .with_words(sp, &memory)
+        .with_words(RAM.start, &[5]);
+
+    let backtrace = unwind_firmware("locals.elf", &mut target, Some(&RAM), |_| {}, true);
+
+    assert_eq!(names(&backtrace), ["main", "Reset"]);
+    let locals = match &backtrace.frames[0] {
+        Frame::Subroutine(main) => main
+            .locals
+            .iter()
+            .map(|local| (&*local.name, &*local.value))
+            .collect::<Vec<_>>(),
+        Frame::Exception(_) => panic!("expected the frame of `main`"),
+    };
+    assert_eq!(
+        locals,
+        [
+            ("packet", "Packet { len: 5, data: [..] }"),
+            ("buffer", "[..]"),
+            ("count", "7"),
+        ]
+    );
+}
@@ -0,0 +1,111 @@
+//! Records RTT captures and decodes them again
+
+use std::{env, fs, path::Path, process};
+
+use probe_run::{
+    capture::{Capture, CaptureWriter, ElfIdentity},
+    config::OutputFormat,
+};
+
+const ELF: &str = "tests/fixtures/hard-fault.elf";
+
+/// Records `chunks` of data, given with the name of the channel they were read from
+fn record(name: &str, chunks: &[(&str, &[u8])]) -> Capture {
+    let path = env::temp_dir().join(format!("probe-run-{}-{}.bin", name, process::id()));
+    let elf = ElfIdentity::new(Path::new(ELF), &fs::read(ELF).unwrap());
+
+    let mut writer = CaptureWriter::create(&path, &elf).unwrap();
+    for (channel, data) in chunks {
+        writer.write_chunk(channel, data).unwrap();
+    }
+    drop(writer);
+
+    let capture = Capture::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    capture
+}
+
+#[test]
+fn round_trip() {
+    let capture = record(
+        "round-trip",
+        &[("defmt", b"Hello"), ("1", b""), ("defmt", b", world!")],
+    );
+
+    assert!(capture.elf.path.ends_with(ELF));
+    let chunks = capture
+        .chunks
+        .iter()
+        .map(|chunk| (&*chunk.channel, &*chunk.data))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        chunks,
+        [("defmt", &b"Hello"[..]), ("1", b""), ("defmt", b", world!")]
+    );
+    assert!(capture.chunks[0].timestamp <= capture.chunks[2].timestamp);
+}
+
+#[test]
+fn truncated_chunk_is_ignored() {
+    let elf = ElfIdentity::new(Path::new(ELF), b"");
+    let path = env::temp_dir().join(format!("probe-run-truncated-{}.bin", process::id()));
+    let mut writer = CaptureWriter::create(&path, &elf).unwrap();
+    writer.write_chunk("0", b"complete").unwrap();
+    writer.write_chunk("0", b"incomplete").unwrap();
+    drop(writer);
+    let mut bytes = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    bytes.truncate(bytes.len() - 2);
+
+    let capture = Capture::parse(&bytes).unwrap();
+
+    assert_eq!(capture.chunks.len(), 1);
+    assert_eq!(capture.chunks[0].data, b"complete");
+}
+
+#[test]
+fn replay_text_channel() {
+    let capture = record(
+        "text",
+        &[("Terminal", b"Hello"), ("Terminal", b", world!\n")],
+    );
+
+    let replay = probe_run::replay(Path::new(ELF), &capture, OutputFormat::Human).unwrap();
+
+    assert!(replay.logs.is_empty());
+    assert_eq!(replay.output, b"Hello, world!\n");
+}
+
+#[test]
+fn replay_text_channels() {
+    let capture = record(
+        "text-channels",
+        &[
+            ("Terminal", b"Hello"),
+            ("1", b"ping\n"),
+            ("Terminal", b", world!\n"),
+        ],
+    );
+
+    let replay = probe_run::replay(Path::new(ELF), &capture, OutputFormat::Human).unwrap();
+
+    assert_eq!(replay.output, b"Helloping\n, world!\n");
+    let channel_output = replay
+        .channel_output
+        .iter()
+        .map(|(name, output)| (name.as_str(), output.as_slice()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        channel_output,
+        [("1", &b"ping\n"[..]), ("Terminal", b"Hello, world!\n")]
+    );
+}
+
+#[test]
+fn unsupported_version() {
+    let mut bytes = b"PRRTTCAP".to_vec();
+    bytes.extend_from_slice(&2u32.to_le_bytes());
+
+    let error = Capture::parse(&bytes).unwrap_err();
+    assert_eq!(error.to_string(), "unsupported capture file version 2");
+}
@@ -0,0 +1,212 @@
+//! Checks how options are read from configuration files and merged across sources
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    time::Duration,
+};
+
+use probe_run::config::{
+    self, BacktracePolicy, Layer, Options, OutputFormat, ResetPolicy, Settings, Source,
+};
+
+fn options(toml: &str) -> Result<Options, String> {
+    toml::from_str(toml).map_err(|e| e.to_string())
+}
+
+fn layer(source: Source, toml: &str) -> Layer {
+    Layer {
+        source,
+        options: options(toml).unwrap(),
+    }
+}
+
+#[test]
+fn every_option() {
+    let options = options(
+        r#"
+chip = "nRF52840_xxAA"
+probe = "1366:1015"
+speed = 4000
+connect-under-reset = true
+no-flash = true
+backtrace = "always"
+format = "json"
+timeout = "5m"
+measure-stack = true
+stack-usage-threshold = 90
+catch-faults = true
+on-reset = "reattach"
+heap-symbols = "__sheap,__eheap"
+"#,
+    )
+    .unwrap();
+
+    assert_eq!(options.chip.as_deref(), Some("nRF52840_xxAA"));
+    assert_eq!(options.probe.as_deref(), Some("1366:1015"));
+    assert_eq!(options.speed, Some(4000));
+    assert_eq!(options.connect_under_reset, Some(true));
+    assert_eq!(options.no_flash, Some(true));
+    assert_eq!(options.backtrace, Some(BacktracePolicy::Always));
+    assert_eq!(options.format, Some(OutputFormat::Json));
+    assert_eq!(
+        options.timeout.map(|timeout| timeout.0),
+        Some(Duration::from_secs(5 * 60))
+    );
+    assert_eq!(options.measure_stack, Some(true));
+    assert_eq!(options.stack_usage_threshold, Some(90));
+    assert_eq!(options.catch_faults, Some(true));
+    assert_eq!(options.on_reset, Some(ResetPolicy::Reattach));
+    assert_eq!(
+        options.heap_symbols.map(|symbols| symbols.to_string()),
+        Some("__sheap,__eheap".to_string())
+    );
+}
+
+#[test]
+fn invalid_options() {
+    // typos are reported rather than ignored
+    assert!(options("chipp = \"nRF52840_xxAA\"")
+        .unwrap_err()
+        .contains("chipp"));
+    assert!(options("timeout = \"5d\"")
+        .unwrap_err()
+        .contains("unknown unit"));
+    assert!(options(&format!("timeout = \"{}h\"", u64::MAX))
+        .unwrap_err()
+        .contains("too long"));
+    assert!(options("stack-usage-threshold = 300").is_err());
+    assert!(options("backtrace = \"sometimes\"").is_err());
+}
+
+#[test]
+fn precedence() {
+    let config_file = PathBuf::from("/firmware/probe-run.toml");
+    let manifest = PathBuf::from("/firmware/Cargo.toml");
+    let layers = [
+        layer(Source::CommandLine, "timeout = \"10s\""),
+        layer(Source::Env, "chip = \"STM32F401RETx\""),
+        layer(
+            Source::ConfigFile(config_file.clone()),
+            "chip = \"nRF52840_xxAA\"\ntimeout = \"1m\"\nmeasure-stack = true",
+        ),
+        layer(
+            Source::CargoManifest(manifest.clone()),
+            "chip = \"nRF52832_xxAA\"\nmeasure-stack = false\nspeed = 1000",
+        ),
+    ];
+
+    let settings = Settings::resolve(&layers);
+
+    assert_eq!(settings.chip.value.as_deref(), Some("STM32F401RETx"));
+    assert_eq!(settings.chip.source, Source::Env);
+    assert_eq!(
+        settings.timeout.value.map(|timeout| timeout.0),
+        Some(Duration::from_secs(10))
+    );
+    assert_eq!(settings.timeout.source, Source::CommandLine);
+    assert!(settings.measure_stack.value);
+    assert_eq!(
+        settings.measure_stack.source,
+        Source::ConfigFile(config_file)
+    );
+    assert_eq!(settings.speed.value, Some(1000));
+    assert_eq!(settings.speed.source, Source::CargoManifest(manifest));
+    // set nowhere
+    assert!(!settings.catch_faults.value);
+    assert_eq!(settings.catch_faults.source, Source::Default);
+    assert_eq!(settings.probe.value, None);
+    assert_eq!(settings.probe.source, Source::Default);
+}
+
+#[test]
+fn disabled_by_a_higher_layer() {
+    // e.g. `--no-measure-stack` overriding a configuration file
+    let layers = [
+        layer(Source::CommandLine, "measure-stack = false"),
+        layer(
+            Source::ConfigFile(PathBuf::from("probe-run.toml")),
+            "measure-stack = true",
+        ),
+    ];
+
+    let settings = Settings::resolve(&layers);
+
+    assert!(!settings.measure_stack.value);
+    assert_eq!(settings.measure_stack.source, Source::CommandLine);
+}
+
+#[test]
+fn print_is_aligned() {
+    let layers = [layer(
+        Source::ConfigFile(PathBuf::from("probe-run.toml")),
+        "chip = \"nRF52840_xxAA\"",
+    )];
+
+    let printed = Settings::resolve(&layers).to_string();
+
+    let lines = printed.lines().collect::<Vec<_>>();
+    assert!(lines
+        .iter()
+        .any(|line| line.starts_with("stack-usage-threshold = ")));
+    for line in &lines {
+        assert_eq!(
+            line.find(" = "),
+            Some("stack-usage-threshold".len()),
+            "{}",
+            line
+        );
+    }
+    let sources = lines
+        .iter()
+        .map(|line| line.find(" (").unwrap())
+        .collect::<Vec<_>>();
+    assert!(sources.iter().all(|&column| column == sources[0]));
+}
+
+/// An empty directory for `discover` to search
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("probe-run-config-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir.canonicalize().unwrap()
+}
+
+fn write(path: &Path, contents: &str) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+}
+
+#[test]
+fn discover() {
+    // a package whose configuration file sits in an ancestor of the firmware's directory
+    let package = temp_dir("discover");
+    let manifest = package.join("Cargo.toml");
+    write(
+        &manifest,
+        "[package]\nname = \"app\"\n\n[package.metadata.probe-run]\nchip = \"nRF52832_xxAA\"\n",
+    );
+    let config_file = package.join("target").join(config::CONFIG_FILE_NAME);
+    write(&config_file, "chip = \"nRF52840_xxAA\"\n");
+    let elf = package.join("target/thumbv7em-none-eabihf/debug/app");
+    write(&elf, "");
+    // `cargo run` points this at the package being run
+    env::set_var("CARGO_MANIFEST_DIR", &package);
+
+    let layers = config::discover(&elf).unwrap();
+
+    let sources = layers
+        .iter()
+        .map(|layer| layer.source.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        sources,
+        [
+            Source::ConfigFile(config_file),
+            Source::CargoManifest(manifest)
+        ]
+    );
+    assert_eq!(layers[0].options.chip.as_deref(), Some("nRF52840_xxAA"));
+    assert_eq!(layers[1].options.chip.as_deref(), Some("nRF52832_xxAA"));
+}
@@ -0,0 +1,198 @@
+//! Checks the layout of the ELF core dumps
+
+use object::read::{File as ElfFile, Object as _, ObjectSegment as _};
+use probe_rs::CoreRegisterAddress;
+use probe_run::{
+    backtrace::{Frame, TopException},
+    coredump::CoreDump,
+    exception::Exception,
+    target::FakeTarget,
+    CanaryState, ExitReason, LR, PC, SP,
+};
+
+const RAM: std::ops::Range<u32> = 0x2000_0000..0x2000_0100;
+const CPACR: u32 = 0xE000_ED88;
+
+fn target() -> FakeTarget {
+    let mut target = FakeTarget::new()
+        .with_words(CPACR, &[0])
+        .with_memory(RAM.start, (0..RAM.len()).map(|i| i as u8).collect());
+    for reg in (0..=18).chain(Some(20)) {
+        target = target.with_register(CoreRegisterAddress(reg), u32::from(reg));
+    }
+    target
+}
+
+#[test]
+fn ram_regions_become_load_segments() {
+    let dump = CoreDump::capture(&mut target(), &[RAM]).unwrap();
+    let bytes = dump.to_elf();
+
+    let elf = ElfFile::parse(&bytes).unwrap();
+    let segments = elf.segments().collect::<Vec<_>>();
+    // the notes are not a loadable segment
+    assert_eq!(segments.len(), 1);
+    assert_eq!(segments[0].address(), u64::from(RAM.start));
+    assert_eq!(segments[0].data().unwrap(), &*dump.memory[0].1);
+}
+
+#[test]
+fn fpu_registers_are_skipped_when_the_fpu_is_disabled() {
+    let dump = CoreDump::capture(&mut target(), &[RAM]).unwrap();
+
+    assert_eq!(dump.registers.len(), 20);
+    assert_eq!(dump.registers[&15], 15);
+}
+
+#[test]
+fn unreadable_ram_is_left_out() {
+    let dump = CoreDump::capture(&mut target(), &[RAM, 0x1000_0000..0x1000_0100]).unwrap();
+
+    assert_eq!(dump.memory.len(), 1);
+}
+
+#[test]
+fn round_trip() {
+    let mut dump = CoreDump::capture(&mut target(), &[RAM]).unwrap();
+    dump.firmware = Some("/path/to/firmware".into());
+    dump.chip = Some("nRF52840_xxAA".to_string());
+    dump.canary = Some(0x2000_0040..0x2000_0080);
+
+    let parsed = CoreDump::parse(&dump.to_elf()).unwrap();
+
+    assert_eq!(parsed.registers, dump.registers);
+    assert_eq!(parsed.memory, dump.memory);
+    assert_eq!(parsed.firmware, dump.firmware);
+    assert_eq!(parsed.chip, dump.chip);
+    assert_eq!(parsed.canary, dump.canary);
+}
+
+// `hard-fault.elf` halted in `HardFault`, entered from `foo`; see `tests/backtrace.rs`
+const RESET_AFTER_CALL: u32 = 0x17;
+const MAIN_AFTER_CALL: u32 = 0x1f;
+const FOO_UDF: u32 = 0x22;
+const HARD_FAULT: u32 = 0x26;
+const EXC_RETURN_THREAD_MSP: u32 = 0xFFFF_FFF9;
+
+/// Dump of `hard-fault.elf` halted in `HardFault`; its 4 KiB of RAM are zeroed below the stack
+fn hard_fault_dump() -> CoreDump {
+    let stack: &[u32] = &[
+        // exception frame: r0-r3, r12, lr, pc, xPSR
+        0,
+        1,
+        2,
+        3,
+        12,
+        MAIN_AFTER_CALL,
+        FOO_UDF,
+        0x0100_0003,
+        // frames of `foo`, `main` and `Reset`: r7, lr
+        0,
+        MAIN_AFTER_CALL,
+        0,
+        RESET_AFTER_CALL,
+        0,
+        0xFFFF_FFFF,
+    ];
+    let sp = 0x2000_1000 - 4 * stack.len() as u32;
+    let mut memory = vec![0; 0x1000];
+    for (i, word) in stack.iter().enumerate() {
+        let offset = (sp - 0x2000_0000) as usize + 4 * i;
+        memory[offset..offset + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    CoreDump {
+        registers: [
+            (PC.0, HARD_FAULT),
+            (LR.0, EXC_RETURN_THREAD_MSP),
+            (SP.0, sp),
+        ]
+        .iter()
+        .copied()
+        .collect(),
+        memory: vec![(0x2000_0000, memory)],
+        firmware: None,
+        chip: None,
+        canary: None,
+    }
+}
+
+#[test]
+fn post_mortem_of_a_hard_fault() {
+    let dump = hard_fault_dump();
+
+    let outcome = probe_run::post_mortem(
+        "tests/fixtures/hard-fault.elf".as_ref(),
+        &dump,
+        false,
+        false,
+    )
+    .unwrap();
+
+    assert_eq!(
+        outcome.exit_reason,
+        ExitReason::HardFault {
+            exception: Exception(3),
+            stack_overflow: false
+        }
+    );
+    assert_eq!(
+        outcome.backtrace.top_exception,
+        Some(TopException::HardFault {
+            exception: Exception(3),
+            stack_overflow: false
+        })
+    );
+    let names = outcome
+        .backtrace
+        .frames
+        .iter()
+        .filter_map(|frame| match frame {
+            Frame::Subroutine(subroutine) => Some(&*subroutine.name),
+            Frame::Exception(_) => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["HardFault", "foo", "main", "Reset"]);
+}
+
+#[test]
+fn post_mortem_checks_the_recorded_canary() {
+    let mut dump = hard_fault_dump();
+    dump.canary = Some(0x2000_0000..0x2000_0100);
+    // the canary is intact but for its topmost byte
+    dump.memory[0].1[..0xff]
+        .iter_mut()
+        .for_each(|byte| *byte = 0xAA);
+
+    let outcome = probe_run::post_mortem(
+        "tests/fixtures/hard-fault.elf".as_ref(),
+        &dump,
+        false,
+        false,
+    )
+    .unwrap();
+
+    assert_eq!(
+        outcome.stack_canary,
+        Some(CanaryState::Touched {
+            min_stack_usage: 0x1000 - 0xff,
+            heap_collision: false
+        })
+    );
+}
+
+#[test]
+fn post_mortem_skips_the_canary_check_without_the_note() {
+    // e.g. a core file written by another tool, whose RAM happens not to hold the canary pattern
+    let dump = hard_fault_dump();
+    assert_eq!(dump.canary, None);
+
+    let outcome = probe_run::post_mortem(
+        "tests/fixtures/hard-fault.elf".as_ref(),
+        &dump,
+        false,
+        false,
+    )
+    .unwrap();
+
+    assert_eq!(outcome.stack_canary, None);
+}
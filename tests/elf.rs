@@ -0,0 +1,129 @@
+//! Extracts the memory layout of the firmware from its ELF file
+
+use std::fs;
+
+use probe_rs::config::RamRegion;
+use probe_run::{
+    config::HeapSymbols,
+    elf::{ProcessedElf, VectorTable},
+    exception::Exception,
+};
+
+fn ram(start: u32, end: u32) -> RamRegion {
+    RamRegion {
+        range: start..end,
+        is_boot_memory: false,
+    }
+}
+
+#[test]
+fn stack_region_is_the_one_containing_the_initial_sp() {
+    let bytes = fs::read("tests/fixtures/hard-fault.elf").unwrap();
+    let initial_sp = ProcessedElf::parse(&bytes, &[])
+        .unwrap()
+        .vector_table
+        .initial_sp;
+
+    // a region that ends where the stack's region starts, like DTCM followed by AXI SRAM
+    let other = ram(initial_sp - 0x2_0000, initial_sp - 0x1_0000);
+    let stack = ram(initial_sp - 0x1_0000, initial_sp);
+    let elf = ProcessedElf::parse(&bytes, &[other, stack.clone()]).unwrap();
+
+    assert_eq!(elf.stack_ram_region, Some(stack));
+}
+
+#[test]
+fn no_stack_region() {
+    let bytes = fs::read("tests/fixtures/hard-fault.elf").unwrap();
+    let elf = ProcessedElf::parse(&bytes, &[ram(0x6000_0000, 0x6001_0000)]).unwrap();
+
+    assert_eq!(elf.stack_ram_region, None);
+    assert_eq!(elf.highest_ram_addr_in_use, 0);
+}
+
+#[test]
+fn vector_table() {
+    let bytes = fs::read("tests/fixtures/hard-fault.elf").unwrap();
+    let vector_table = ProcessedElf::parse(&bytes, &[]).unwrap().vector_table;
+
+    assert_eq!(vector_table.vectors.len(), 4);
+    assert_eq!(
+        vector_table.handler(Exception(3)),
+        Some(vector_table.hard_fault)
+    );
+    // entry 0 is the initial stack pointer
+    assert_eq!(vector_table.handler(Exception(0)), None);
+    // past the end of the table
+    assert_eq!(vector_table.handler(Exception(16)), None);
+}
+
+/// A vector table where UsageFault shares its handler with MemManage, BusFault has a dedicated
+/// one and SecureFault is reserved
+fn armv7m_vector_table() -> VectorTable {
+    VectorTable {
+        location: 0,
+        initial_sp: 0x2000_1000,
+        reset: 0x101,
+        hard_fault: 0x201,
+        vectors: vec![0x2000_1000, 0x101, 0x301, 0x201, 0x401, 0x501, 0x401, 0],
+    }
+}
+
+#[test]
+fn fault_handlers() {
+    let vector_table = armv7m_vector_table();
+
+    assert_eq!(
+        vector_table.fault_handlers(&[Exception::HARD_FAULT]),
+        [(Exception::HARD_FAULT, 0x200)]
+    );
+    assert_eq!(
+        vector_table.fault_handlers(&Exception::CONFIGURABLE_FAULTS),
+        [
+            (Exception::MEM_MANAGE, 0x400),
+            (Exception::BUS_FAULT, 0x500)
+        ]
+    );
+}
+
+#[test]
+fn fault_at() {
+    let vector_table = armv7m_vector_table();
+
+    assert_eq!(vector_table.fault_at(0x200), Some(Exception::HARD_FAULT));
+    // the first fault using the handler
+    assert_eq!(vector_table.fault_at(0x400), Some(Exception::MEM_MANAGE));
+    assert_eq!(vector_table.fault_at(0x500), Some(Exception::BUS_FAULT));
+    assert_eq!(vector_table.fault_at(0x300), None);
+    // reserved entries are zero
+    assert_eq!(vector_table.fault_at(0), None);
+}
+
+/// `hard-fault.elf` with the heap symbols of a `cortex-m-rt` program using `alloc-cortex-m`,
+/// rebuilt with:
+///
+///     rust-lld -flavor gnu -N -T hard-fault.x --defsym=__sheap=0x20000100 \
+///         --defsym=__eheap=0x20000500 hard-fault.o -o heap-symbols.elf
+const HEAP_SYMBOLS_ELF: &str = "tests/fixtures/heap-symbols.elf";
+
+#[test]
+fn heap_symbols_of_cortex_m_rt_are_not_guessed() {
+    let bytes = fs::read(HEAP_SYMBOLS_ELF).unwrap();
+    let elf = ProcessedElf::parse(&bytes, &[]).unwrap();
+
+    assert_eq!(elf.heap_region(None), None);
+}
+
+#[test]
+fn heap_symbols_given_by_the_user() {
+    let bytes = fs::read(HEAP_SYMBOLS_ELF).unwrap();
+    let elf = ProcessedElf::parse(&bytes, &[]).unwrap();
+
+    let symbols = "__sheap,__eheap".parse::<HeapSymbols>().unwrap();
+    assert_eq!(
+        elf.heap_region(Some(&symbols)),
+        Some(0x2000_0100..0x2000_0500)
+    );
+    let missing = "__sheap,_heap_end".parse::<HeapSymbols>().unwrap();
+    assert_eq!(elf.heap_region(Some(&missing)), None);
+}
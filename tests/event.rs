@@ -0,0 +1,339 @@
+//! Checks the JSON encoding of `--format json` events
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use probe_run::{
+    backtrace::{Backtrace, ExceptionEntry, Frame, Location, Subroutine, TopException, Variable},
+    event::Event,
+    exception::Exception,
+    halt_reason::HaltReason,
+    CanaryState, ExitReason, LogFrame, RunOutcome, StackUsage,
+};
+use serde_json::{json, Value};
+
+fn to_json(event: &Event) -> Value {
+    serde_json::to_value(event).unwrap()
+}
+
+fn subroutine(name: &str, pc: u32, line: Option<u64>) -> Frame {
+    Frame::Subroutine(Subroutine {
+        name: name.to_string(),
+        pc,
+        location: line.map(|line| Location {
+            file: PathBuf::from("src/main.rs"),
+            line,
+        }),
+        is_inlined: false,
+        locals: vec![],
+    })
+}
+
+fn hard_fault() -> RunOutcome {
+    RunOutcome {
+        exit_reason: ExitReason::HardFault {
+            exception: Exception(3),
+            stack_overflow: false,
+        },
+        logs: vec![],
+        output: vec![],
+        channel_output: BTreeMap::new(),
+        backtrace: Backtrace {
+            frames: vec![
+                subroutine("HardFault", 0x26, None),
+                Frame::Exception(ExceptionEntry {
+                    exception: Some(Exception(3)),
+                    handler: None,
+                }),
+                subroutine("foo", 0x22, Some(12)),
+            ],
+            top_exception: Some(TopException::HardFault {
+                exception: Exception(3),
+                stack_overflow: false,
+            }),
+            corrupted: true,
+            incomplete: None,
+            exception_frame: None,
+        },
+        stack_canary: Some(CanaryState::Intact),
+        stack_usage: None,
+        fault: None,
+        registers: None,
+        halt_reason: None,
+    }
+}
+
+#[test]
+fn backtrace() {
+    let outcome = hard_fault();
+
+    let events = Event::backtrace(&outcome.backtrace)
+        .iter()
+        .map(to_json)
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        events,
+        [
+            json!({
+                "event": "backtrace_frame",
+                "index": 0,
+                "function": "HardFault",
+                "file": null,
+                "line": null,
+                "pc": 0x26,
+                "inlined": false,
+            }),
+            json!({ "event": "exception_entry", "exception": "HardFault" }),
+            json!({
+                "event": "backtrace_frame",
+                "index": 1,
+                "function": "foo",
+                "file": "src/main.rs",
+                "line": 12,
+                "pc": 0x22,
+                "inlined": false,
+            }),
+            json!({ "event": "backtrace_corrupted" }),
+        ]
+    );
+}
+
+#[test]
+fn backtrace_locals() {
+    let mut outcome = hard_fault();
+    if let Frame::Subroutine(foo) = &mut outcome.backtrace.frames[2] {
+        foo.locals = vec![
+            Variable {
+                name: "x".to_string(),
+                value: "42".to_string(),
+            },
+            Variable {
+                name: "p".to_string(),
+                value: "&Point { x: 1, y: 2 }".to_string(),
+            },
+        ];
+    }
+
+    let events = Event::backtrace(&outcome.backtrace);
+
+    assert_eq!(
+        to_json(&events[2]),
+        json!({
+            "event": "backtrace_frame",
+            "index": 1,
+            "function": "foo",
+            "file": "src/main.rs",
+            "line": 12,
+            "pc": 0x22,
+            "inlined": false,
+            "locals": [
+                { "name": "x", "value": "42" },
+                { "name": "p", "value": "&Point { x: 1, y: 2 }" },
+            ],
+        })
+    );
+    assert!(outcome.backtrace.to_string().contains(
+        "   1: foo
+        at src/main.rs:12
+          x = 42
+          p = &Point { x: 1, y: 2 }
+"
+    ));
+}
+
+#[test]
+fn exit() {
+    assert_eq!(
+        to_json(&Event::exit(&hard_fault())),
+        json!({
+            "event": "exit",
+            "reason": "hard_fault",
+            "exception": "HardFault",
+            "stack_overflow": false,
+            "code": 134,
+        })
+    );
+}
+
+#[test]
+fn exit_after_reset() {
+    let outcome = RunOutcome {
+        exit_reason: ExitReason::Reset,
+        ..hard_fault()
+    };
+
+    assert_eq!(
+        to_json(&Event::exit(&outcome)),
+        json!({
+            "event": "exit",
+            "reason": "reset",
+            "stack_overflow": false,
+            "code": 1,
+        })
+    );
+    assert_eq!(
+        to_json(&Event::TargetReset {
+            policy: "fail".to_string()
+        }),
+        json!({ "event": "target_reset", "policy": "fail" })
+    );
+}
+
+#[test]
+fn exit_after_lockup() {
+    // the core locked up outside of the firmware so the backtrace stops at its first frame
+    let outcome = RunOutcome {
+        exit_reason: ExitReason::Lockup,
+        backtrace: Backtrace {
+            frames: vec![subroutine("???", 0xEFFF_FFFE, None)],
+            top_exception: Some(TopException::Other),
+            corrupted: false,
+            incomplete: Some("debug information is missing".to_string()),
+            exception_frame: None,
+        },
+        ..hard_fault()
+    };
+
+    let events = Event::backtrace(&outcome.backtrace)
+        .iter()
+        .map(to_json)
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        events,
+        [
+            json!({
+                "event": "backtrace_frame",
+                "index": 0,
+                "function": "???",
+                "file": null,
+                "line": null,
+                "pc": 0xEFFF_FFFEu32,
+                "inlined": false,
+            }),
+            json!({
+                "event": "backtrace_incomplete",
+                "reason": "debug information is missing",
+            }),
+        ]
+    );
+    assert_eq!(
+        to_json(&Event::exit(&outcome)),
+        json!({
+            "event": "exit",
+            "reason": "lockup",
+            "stack_overflow": false,
+            "code": 132,
+        })
+    );
+}
+
+#[test]
+fn exit_after_watchpoint() {
+    let outcome = RunOutcome {
+        exit_reason: ExitReason::UnexpectedHalt {
+            reason: HaltReason(0b100),
+        },
+        halt_reason: Some(HaltReason(0b100)),
+        ..hard_fault()
+    };
+
+    assert_eq!(
+        to_json(&Event::exit(&outcome)),
+        json!({
+            "event": "exit",
+            "reason": "unexpected_halt",
+            "stack_overflow": false,
+            "halt_reason": ["DWTTRAP"],
+            "code": 2,
+        })
+    );
+}
+
+#[test]
+fn exit_after_timeout() {
+    let outcome = RunOutcome {
+        exit_reason: ExitReason::TimedOut,
+        ..hard_fault()
+    };
+
+    assert_eq!(
+        to_json(&Event::exit(&outcome)),
+        json!({
+            "event": "exit",
+            "reason": "timed_out",
+            "stack_overflow": false,
+            "code": 124,
+        })
+    );
+}
+
+#[test]
+fn stack_canary() {
+    assert_eq!(
+        to_json(&Event::stack_canary(CanaryState::Touched {
+            min_stack_usage: 1024,
+            heap_collision: true,
+        })),
+        json!({
+            "event": "stack_canary",
+            "intact": false,
+            "min_stack_usage": 1024,
+            "heap_collision": true,
+        })
+    );
+}
+
+#[test]
+fn stack_usage() {
+    let usage = StackUsage {
+        peak: 3072,
+        available: 4096,
+    };
+
+    assert_eq!(
+        to_json(&Event::stack_usage(usage)),
+        json!({
+            "event": "stack_usage",
+            "peak": 3072,
+            "available": 4096,
+            "headroom": 1024,
+            "percent_used": 75.0,
+        })
+    );
+}
+
+#[test]
+fn output() {
+    assert_eq!(
+        to_json(&Event::output("Terminal", b"Hello\n")),
+        json!({ "event": "output", "channel": "Terminal", "data": "Hello\n" })
+    );
+}
+
+#[test]
+fn log() {
+    let frame = LogFrame {
+        level: log::Level::Warn,
+        timestamp: 42,
+        message: "0.000042 WARN  x = 1".to_string(),
+        args: "x = 1".to_string(),
+        file: Some("src/main.rs".to_string()),
+        line: Some(12),
+        module_path: Some("app".to_string()),
+    };
+
+    assert_eq!(
+        to_json(&Event::log(&frame)),
+        json!({
+            "event": "log",
+            "level": "warn",
+            "timestamp": 42,
+            "message": "0.000042 WARN  x = 1",
+            "args": "x = 1",
+            "file": "src/main.rs",
+            "line": 12,
+            "module_path": "app",
+        })
+    );
+}
@@ -0,0 +1,92 @@
+//! Checks the decoding of the fault status registers
+
+use probe_run::{fault::FaultStatus, target::FakeTarget};
+
+const CFSR: u32 = 0xE000_ED28;
+const ID_PFR1: u32 = 0xE000_ED44;
+const SFSR: u32 = 0xE000_EDE4;
+
+/// A core whose SCB holds the given CFSR, HFSR, MMFAR, BFAR and AFSR values
+fn scb(cfsr: u32, hfsr: u32, mmfar: u32, bfar: u32) -> FakeTarget {
+    FakeTarget::new()
+        .with_words(CFSR, &[cfsr, hfsr, 0, mmfar, bfar, 0])
+        // ARMv7-M: no Security Extension
+        .with_words(ID_PFR1, &[0x0000_0200])
+}
+
+#[test]
+fn escalated_precise_bus_fault() {
+    // FORCED; BFARVALID | PRECISERR
+    let mut core = scb(0x0000_8200, 0x4000_0000, 0, 0x4000_1000);
+
+    let fault = FaultStatus::read(&mut core, Some(0x412)).unwrap();
+
+    assert_eq!(fault.pc, Some(0x412));
+    assert_eq!(fault.sfsr, None);
+    assert_eq!(
+        fault.causes(),
+        [
+            "escalated from BusFault",
+            "precise bus fault accessing 0x40001000"
+        ]
+    );
+    assert!(!fault.is_imprecise());
+    assert_eq!(
+        fault.to_string(),
+        "HFSR=0x40000000 CFSR=0x00008200 MMFAR=0x00000000 BFAR=0x40001000 AFSR=0x00000000"
+    );
+}
+
+#[test]
+fn usage_faults() {
+    // UNALIGNED | DIVBYZERO | UNDEFINSTR
+    let mut core = scb(0x0301_0000, 0x4000_0000, 0, 0);
+
+    let fault = FaultStatus::read(&mut core, None).unwrap();
+
+    assert_eq!(
+        fault.causes(),
+        [
+            "escalated from UsageFault",
+            "undefined instruction",
+            "unaligned access",
+            "divide by zero"
+        ]
+    );
+}
+
+#[test]
+fn fault_addresses_are_only_shown_when_valid() {
+    // DACCVIOL without MMARVALID; IMPRECISERR, which never has a valid BFAR
+    let mut core = scb(0x0000_0402, 0x4000_0000, 0xDEAD_BEEF, 0xDEAD_BEEF);
+
+    let fault = FaultStatus::read(&mut core, None).unwrap();
+
+    assert_eq!(
+        fault.causes(),
+        [
+            "escalated from MemManage and BusFault",
+            "memory protection violation",
+            "imprecise bus fault"
+        ]
+    );
+    assert!(fault.is_imprecise());
+}
+
+#[test]
+fn secure_fault() {
+    let mut core = FakeTarget::new()
+        .with_words(CFSR, &[0; 6])
+        // ARMv8-M with the Security Extension
+        .with_words(ID_PFR1, &[0x0000_0210])
+        // SFARVALID | AUVIOL
+        .with_words(SFSR, &[0x48, 0x1000_0000]);
+
+    let fault = FaultStatus::read(&mut core, None).unwrap();
+
+    assert_eq!(fault.sfsr, Some(0x48));
+    assert_eq!(
+        fault.causes(),
+        ["Secure memory access from the Non-secure state accessing 0x10000000"]
+    );
+}
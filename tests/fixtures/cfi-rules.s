@@ -0,0 +1,110 @@
+@ Minimal Cortex-M firmware whose unwind information uses less common CFI register rules
+@
+@ `Reset` calls `main`, which calls `with_same_value`, which calls `with_val_expression`, which
+@ calls `with_register`, which halts on a breakpoint. Each function describes its frame with a
+@ different rule:
+@
+@ - `Reset` marks the return address as undefined (`.cfi_undefined`)
+@ - `main` uses R7 as the frame pointer
+@ - `with_same_value` keeps R7 (`.cfi_same_value`) and saves LR at an address given by a DWARF
+@   expression (`DW_CFA_expression`)
+@ - `with_val_expression` recovers R7 with a DWARF expression (`DW_CFA_val_expression`)
+@ - `with_register` keeps the return address in R12 (`.cfi_register`)
+@
+@ Rebuild `cfi-rules.elf` with:
+@
+@     llvm-mc --triple=thumbv7m-none-eabi -g -filetype=obj cfi-rules.s -o cfi-rules.o
+@     rust-lld -flavor gnu -N -T hard-fault.x cfi-rules.o -o cfi-rules.elf
+
+    .syntax unified
+    .thumb
+    .cfi_sections .debug_frame
+
+    .section .vector_table, "a", %progbits
+    .word 0x20001000        @ initial stack pointer
+    .word Reset
+    .word DefaultHandler    @ NMI
+    .word DefaultHandler    @ HardFault
+
+    .text
+
+    .global Reset
+    .type Reset, %function
+    .thumb_func
+Reset:
+    .cfi_startproc
+    .cfi_undefined lr
+    bl main
+    b .
+    .cfi_endproc
+    .size Reset, . - Reset
+
+    .global main
+    .type main, %function
+    .thumb_func
+main:
+    .cfi_startproc
+    push {r7, lr}
+    .cfi_def_cfa_offset 8
+    .cfi_offset lr, -4
+    .cfi_offset r7, -8
+    mov r7, sp
+    .cfi_def_cfa_register r7
+    sub sp, #16
+    bl with_same_value
+    b .
+    .cfi_endproc
+    .size main, . - main
+
+    .global with_same_value
+    .type with_same_value, %function
+    .thumb_func
+with_same_value:
+    .cfi_startproc
+    .cfi_same_value r7
+    push {r4, lr}
+    .cfi_def_cfa_offset 8
+    .cfi_offset r4, -8
+    @ DW_CFA_expression lr, { DW_OP_breg13 (sp) 4 }
+    .cfi_escape 0x10, 0x0e, 0x02, 0x7d, 0x04
+    bl with_val_expression
+    pop {r4, pc}
+    .cfi_endproc
+    .size with_same_value, . - with_same_value
+
+    .global with_val_expression
+    .type with_val_expression, %function
+    .thumb_func
+with_val_expression:
+    .cfi_startproc
+    push {r7, lr}
+    .cfi_def_cfa_offset 8
+    .cfi_offset lr, -4
+    @ DW_CFA_val_expression r7, { DW_OP_breg13 (sp) 0; DW_OP_deref }
+    .cfi_escape 0x16, 0x07, 0x03, 0x7d, 0x00, 0x06
+    movs r7, #0
+    bl with_register
+    pop {r7, pc}
+    .cfi_endproc
+    .size with_val_expression, . - with_val_expression
+
+    .global with_register
+    .type with_register, %function
+    .thumb_func
+with_register:
+    .cfi_startproc
+    mov r12, lr
+    .cfi_register lr, r12
+    bkpt
+    bx r12
+    .cfi_endproc
+    .size with_register, . - with_register
+
+    .global DefaultHandler
+    .type DefaultHandler, %function
+    .thumb_func
+DefaultHandler:
+    .cfi_startproc
+    b .
+    .cfi_endproc
+    .size DefaultHandler, . - DefaultHandler
@@ -0,0 +1,79 @@
+@ Minimal Cortex-M firmware used by the backtrace tests
+@
+@ `Reset` calls `main`, which calls `foo`, which executes an undefined instruction and
+@ triggers a HardFault. Rebuild `hard-fault.elf` with:
+@
+@     llvm-mc --triple=thumbv7m-none-eabi -g -filetype=obj hard-fault.s -o hard-fault.o
+@     rust-lld -flavor gnu -N -T hard-fault.x hard-fault.o -o hard-fault.elf
+
+    .syntax unified
+    .thumb
+    .cfi_sections .debug_frame
+
+    .section .vector_table, "a", %progbits
+    .word 0x20001000        @ initial stack pointer
+    .word Reset
+    .word DefaultHandler    @ NMI
+    .word HardFault
+
+    .text
+
+    .global Reset
+    .type Reset, %function
+    .thumb_func
+Reset:
+    .cfi_startproc
+    push {r7, lr}
+    .cfi_def_cfa_offset 8
+    .cfi_offset lr, -4
+    .cfi_offset r7, -8
+    bl main
+    b .
+    .cfi_endproc
+    .size Reset, . - Reset
+
+    .global main
+    .type main, %function
+    .thumb_func
+main:
+    .cfi_startproc
+    push {r7, lr}
+    .cfi_def_cfa_offset 8
+    .cfi_offset lr, -4
+    .cfi_offset r7, -8
+    bl foo
+    b .
+    .cfi_endproc
+    .size main, . - main
+
+    .global foo
+    .type foo, %function
+    .thumb_func
+foo:
+    .cfi_startproc
+    push {r7, lr}
+    .cfi_def_cfa_offset 8
+    .cfi_offset lr, -4
+    .cfi_offset r7, -8
+    udf #0
+    pop {r7, pc}
+    .cfi_endproc
+    .size foo, . - foo
+
+    .global HardFault
+    .type HardFault, %function
+    .thumb_func
+HardFault:
+    .cfi_startproc
+    b .
+    .cfi_endproc
+    .size HardFault, . - HardFault
+
+    .global DefaultHandler
+    .type DefaultHandler, %function
+    .thumb_func
+DefaultHandler:
+    .cfi_startproc
+    b .
+    .cfi_endproc
+    .size DefaultHandler, . - DefaultHandler
@@ -0,0 +1,13 @@
+MEMORY
+{
+  FLASH : ORIGIN = 0x00000000, LENGTH = 64K
+  RAM : ORIGIN = 0x20000000, LENGTH = 4K
+}
+
+ENTRY(Reset);
+
+SECTIONS
+{
+  .vector_table ORIGIN(FLASH) : { KEEP(*(.vector_table)); } > FLASH
+  .text : { *(.text .text.*); . = ALIGN(4); } > FLASH
+}
@@ -0,0 +1,210 @@
+@ Minimal Cortex-M firmware whose `main` has local variables too large to be read as a whole
+@
+@ `Reset` calls `main`, which halts on a breakpoint. The debug information is written by hand and
+@ describes three variables of `main`:
+@
+@ - `packet`, a `Packet { len: u32, data: [u8; 65536] }` at 0x20000000
+@ - `buffer`, a `[u8; 65536]` at 0x20000010; like `rustc`'s, the array type has no size
+@ - `count`, a `u32` at the frame base, i.e. at SP
+@
+@ Rebuild `locals.elf` with:
+@
+@     llvm-mc --triple=thumbv7m-none-eabi -filetype=obj locals.s -o locals.o
+@     rust-lld -flavor gnu -N -T hard-fault.x locals.o -o locals.elf
+
+    .syntax unified
+    .thumb
+    .cfi_sections .debug_frame
+
+    .section .vector_table, "a", %progbits
+    .word 0x20001000        @ initial stack pointer
+    .word Reset
+    .word DefaultHandler    @ NMI
+    .word DefaultHandler    @ HardFault
+
+    .text
+
+    .global Reset
+    .type Reset, %function
+    .thumb_func
+Reset:
+    .cfi_startproc
+    .cfi_undefined lr
+    bl main
+    b .
+    .cfi_endproc
+    .size Reset, . - Reset
+
+    .global main
+    .type main, %function
+    .thumb_func
+main:
+.Lmain_begin:
+    .cfi_startproc
+    push {r7, lr}
+    .cfi_def_cfa_offset 8
+    .cfi_offset lr, -4
+    .cfi_offset r7, -8
+    sub sp, #8
+    .cfi_def_cfa_offset 16
+    bkpt
+    add sp, #8
+    pop {r7, pc}
+    .cfi_endproc
+.Lmain_end:
+    .size main, . - main
+
+    .global DefaultHandler
+    .type DefaultHandler, %function
+    .thumb_func
+DefaultHandler:
+    .cfi_startproc
+    b .
+    .cfi_endproc
+    .size DefaultHandler, . - DefaultHandler
+
+    .section .debug_abbrev, "", %progbits
+.Labbrev_begin:
+    .uleb128 1              @ DW_TAG_compile_unit, with children
+    .uleb128 0x11
+    .byte 1
+    .uleb128 0x03, 0x08     @ DW_AT_name, DW_FORM_string
+    .uleb128 0x13, 0x05     @ DW_AT_language, DW_FORM_data2
+    .uleb128 0x11, 0x01     @ DW_AT_low_pc, DW_FORM_addr
+    .uleb128 0x12, 0x06     @ DW_AT_high_pc, DW_FORM_data4
+    .byte 0, 0
+
+    .uleb128 2              @ DW_TAG_subprogram, with children
+    .uleb128 0x2e
+    .byte 1
+    .uleb128 0x03, 0x08     @ DW_AT_name, DW_FORM_string
+    .uleb128 0x11, 0x01     @ DW_AT_low_pc, DW_FORM_addr
+    .uleb128 0x12, 0x06     @ DW_AT_high_pc, DW_FORM_data4
+    .uleb128 0x40, 0x18     @ DW_AT_frame_base, DW_FORM_exprloc
+    .byte 0, 0
+
+    .uleb128 3              @ DW_TAG_variable
+    .uleb128 0x34
+    .byte 0
+    .uleb128 0x03, 0x08     @ DW_AT_name, DW_FORM_string
+    .uleb128 0x49, 0x13     @ DW_AT_type, DW_FORM_ref4
+    .uleb128 0x02, 0x18     @ DW_AT_location, DW_FORM_exprloc
+    .byte 0, 0
+
+    .uleb128 4              @ DW_TAG_base_type
+    .uleb128 0x24
+    .byte 0
+    .uleb128 0x03, 0x08     @ DW_AT_name, DW_FORM_string
+    .uleb128 0x3e, 0x0b     @ DW_AT_encoding, DW_FORM_data1
+    .uleb128 0x0b, 0x0b     @ DW_AT_byte_size, DW_FORM_data1
+    .byte 0, 0
+
+    .uleb128 5              @ DW_TAG_structure_type, with children
+    .uleb128 0x13
+    .byte 1
+    .uleb128 0x03, 0x08     @ DW_AT_name, DW_FORM_string
+    .uleb128 0x0b, 0x06     @ DW_AT_byte_size, DW_FORM_data4
+    .byte 0, 0
+
+    .uleb128 6              @ DW_TAG_member
+    .uleb128 0x0d
+    .byte 0
+    .uleb128 0x03, 0x08     @ DW_AT_name, DW_FORM_string
+    .uleb128 0x49, 0x13     @ DW_AT_type, DW_FORM_ref4
+    .uleb128 0x38, 0x0b     @ DW_AT_data_member_location, DW_FORM_data1
+    .byte 0, 0
+
+    .uleb128 7              @ DW_TAG_array_type, with children
+    .uleb128 0x01
+    .byte 1
+    .uleb128 0x49, 0x13     @ DW_AT_type, DW_FORM_ref4
+    .byte 0, 0
+
+    .uleb128 8              @ DW_TAG_subrange_type
+    .uleb128 0x21
+    .byte 0
+    .uleb128 0x49, 0x13     @ DW_AT_type, DW_FORM_ref4
+    .uleb128 0x37, 0x06     @ DW_AT_count, DW_FORM_data4
+    .byte 0, 0
+
+    .byte 0
+
+    .section .debug_info, "", %progbits
+.Lcu_begin:
+    .word .Lcu_end - .Lcu_version
+.Lcu_version:
+    .short 4                @ DWARF version
+    .word .Labbrev_begin
+    .byte 4                 @ address size
+
+    .uleb128 1              @ compile unit
+    .asciz "locals.rs"
+    .short 0x1c             @ DW_LANG_Rust
+    .word .Lmain_begin
+    .word .Lmain_end - .Lmain_begin
+
+    .uleb128 2              @ main
+    .asciz "main"
+    .word .Lmain_begin
+    .word .Lmain_end - .Lmain_begin
+    .uleb128 1
+    .byte 0x5d              @ DW_OP_reg13 (SP)
+
+    .uleb128 3              @ packet
+    .asciz "packet"
+    .word .Lpacket - .Lcu_begin
+    .uleb128 5
+    .byte 0x03              @ DW_OP_addr
+    .word 0x20000000
+
+    .uleb128 3              @ buffer
+    .asciz "buffer"
+    .word .Larray - .Lcu_begin
+    .uleb128 5
+    .byte 0x03              @ DW_OP_addr
+    .word 0x20000010
+
+    .uleb128 3              @ count
+    .asciz "count"
+    .word .Lu32 - .Lcu_begin
+    .uleb128 2
+    .byte 0x91, 0x00        @ DW_OP_fbreg 0
+
+    .byte 0                 @ end of main
+
+.Lu32:
+    .uleb128 4
+    .asciz "u32"
+    .byte 0x07              @ DW_ATE_unsigned
+    .byte 4
+
+.Lu8:
+    .uleb128 4
+    .asciz "u8"
+    .byte 0x07              @ DW_ATE_unsigned
+    .byte 1
+
+.Lpacket:
+    .uleb128 5
+    .asciz "Packet"
+    .word 65540
+    .uleb128 6
+    .asciz "len"
+    .word .Lu32 - .Lcu_begin
+    .byte 0
+    .uleb128 6
+    .asciz "data"
+    .word .Larray - .Lcu_begin
+    .byte 4
+    .byte 0                 @ end of Packet
+
+.Larray:
+    .uleb128 7
+    .word .Lu8 - .Lcu_begin
+    .uleb128 8
+    .word .Lu32 - .Lcu_begin
+    .word 65536
+    .byte 0                 @ end of [u8; 65536]
+
+    .byte 0                 @ end of the compile unit
+.Lcu_end:
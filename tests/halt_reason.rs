@@ -0,0 +1,102 @@
+//! Checks the decoding of the Debug Fault Status Register
+
+use probe_run::{
+    backtrace::TopException, config::ResetPolicy, halt_reason::HaltReason, target::FakeTarget,
+    ExitReason, LoopEnd, UNEXPECTED_HALT,
+};
+
+const DFSR: u32 = 0xE000_ED30;
+
+fn halt_reason(dfsr: u32) -> HaltReason {
+    HaltReason::read(&mut FakeTarget::new().with_words(DFSR, &[dfsr])).unwrap()
+}
+
+#[test]
+fn bkpt_instruction() {
+    let reason = halt_reason(0b10);
+
+    assert!(reason.is_breakpoint());
+    assert_eq!(reason.names(), ["BKPT"]);
+    assert_eq!(reason.to_string(), "BKPT (breakpoint)");
+}
+
+#[test]
+fn watchpoint_and_external_request() {
+    // reserved bits are ignored
+    let reason = halt_reason(0xFFFF_FF00 | 0b1_0100);
+
+    assert!(!reason.is_breakpoint());
+    assert_eq!(reason.names(), ["DWTTRAP", "EXTERNAL"]);
+    assert_eq!(
+        reason.to_string(),
+        "DWTTRAP (DWT watchpoint or other DWT event), EXTERNAL (external debug request)"
+    );
+}
+
+#[test]
+fn breakpoint_after_halt_request() {
+    // e.g. another debugger halted the core on a breakpoint
+    let reason = halt_reason(0b11);
+
+    assert!(!reason.is_breakpoint());
+    assert_eq!(reason.names(), ["HALTED", "BKPT"]);
+}
+
+#[test]
+fn nothing_recorded() {
+    let reason = halt_reason(0);
+
+    assert!(!reason.is_breakpoint());
+    assert_eq!(reason.to_string(), "unknown");
+}
+
+#[test]
+fn unreadable_dfsr() {
+    assert!(HaltReason::read(&mut FakeTarget::new()).is_err());
+
+    // the run still ends, as if the core halted on a breakpoint
+    let end = LoopEnd::default();
+    let halt_reason = end.halt_reason(&mut FakeTarget::new());
+    assert_eq!(halt_reason, None);
+    assert_eq!(
+        end.exit_reason(Some(&TopException::Other), halt_reason, ResetPolicy::Fail),
+        ExitReason::Halted
+    );
+}
+
+#[test]
+fn not_read_while_running() {
+    let end = LoopEnd {
+        interrupted: true,
+        ..LoopEnd::default()
+    };
+
+    // DFSR still holds the reason of an earlier halt
+    let mut target = FakeTarget::new().with_words(DFSR, &[0b100]);
+    assert_eq!(end.halt_reason(&mut target), None);
+}
+
+#[test]
+fn exit_after_watchpoint() {
+    let end = LoopEnd::default();
+    let halt_reason = end.halt_reason(&mut FakeTarget::new().with_words(DFSR, &[0b100]));
+
+    let exit_reason = end.exit_reason(Some(&TopException::Other), halt_reason, ResetPolicy::Fail);
+    assert_eq!(
+        exit_reason,
+        ExitReason::UnexpectedHalt {
+            reason: HaltReason(0b100)
+        }
+    );
+    assert_eq!(exit_reason.exit_code(), UNEXPECTED_HALT);
+}
+
+#[test]
+fn exit_after_bkpt_instruction() {
+    let end = LoopEnd::default();
+    let halt_reason = end.halt_reason(&mut FakeTarget::new().with_words(DFSR, &[0b10]));
+
+    let exit_reason = end.exit_reason(Some(&TopException::Other), halt_reason, ResetPolicy::Fail);
+    assert_eq!(exit_reason, ExitReason::Halted);
+    assert_eq!(exit_reason.exit_code(), 0);
+}
@@ -0,0 +1,31 @@
+//! Checks the detection of a locked up core
+
+use probe_run::{lockup, target::FakeTarget};
+
+const DHCSR: u32 = 0xE000_EDF0;
+const S_HALT: u32 = 1 << 17;
+const S_LOCKUP: u32 = 1 << 19;
+
+fn target(dhcsr: u32) -> FakeTarget {
+    FakeTarget::new().with_words(DHCSR, &[dhcsr])
+}
+
+#[test]
+fn running() {
+    assert!(!lockup::is_locked_up(&mut target(0)).unwrap());
+}
+
+#[test]
+fn locked_up() {
+    assert!(lockup::is_locked_up(&mut target(S_LOCKUP)).unwrap());
+}
+
+#[test]
+fn halted_after_locking_up() {
+    assert!(!lockup::is_locked_up(&mut target(S_LOCKUP | S_HALT)).unwrap());
+}
+
+#[test]
+fn unreadable_dhcsr() {
+    assert!(lockup::is_locked_up(&mut FakeTarget::new()).is_err());
+}
@@ -0,0 +1,77 @@
+//! Checks the reading and formatting of `--show-registers`
+
+use probe_rs::CoreRegisterAddress;
+use probe_run::{exception::Exception, registers::CoreRegisters, target::FakeTarget, Stacked};
+
+/// A core halted in the HardFault handler, where register `n` holds `n`
+fn target() -> FakeTarget {
+    let mut target = FakeTarget::new();
+    for reg in (0..=15).chain(Some(17)).chain(Some(18)) {
+        target = target.with_register(CoreRegisterAddress(reg), u32::from(reg));
+    }
+    target
+        // xPSR: Thumb state; IPSR = HardFault
+        .with_register(CoreRegisterAddress(16), 0x0100_0003)
+        // CONTROL = 2, FAULTMASK = 0, BASEPRI = 0x20, PRIMASK = 1
+        .with_register(CoreRegisterAddress(20), 0x0200_2001)
+}
+
+fn stacked() -> Stacked {
+    Stacked {
+        r0: 0,
+        r1: 1,
+        r2: 2,
+        r3: 3,
+        r12: 12,
+        lr: 0x1f,
+        pc: 0x22,
+        // IPSR = SysTick
+        xpsr: 0x0100_000F,
+        fpu_regs: None,
+    }
+}
+
+#[test]
+fn special_registers_are_unpacked() {
+    let registers = CoreRegisters::read(&mut target(), None).unwrap();
+
+    assert_eq!(registers.r4, 4);
+    assert_eq!(registers.r11, 11);
+    assert_eq!(registers.msp, 17);
+    assert_eq!(registers.psp, 18);
+    assert_eq!(registers.control, 2);
+    assert_eq!(registers.faultmask, 0);
+    assert_eq!(registers.basepri, 0x20);
+    assert_eq!(registers.primask, 1);
+    assert_eq!(registers.exception(), Exception(3));
+}
+
+#[test]
+fn display() {
+    let registers = CoreRegisters::read(&mut target(), Some(&stacked())).unwrap();
+
+    assert_eq!(
+        registers.to_string(),
+        "registers (HardFault):
+         r4 = 0x00000004        r5 = 0x00000005        r6 = 0x00000006        r7 = 0x00000007
+         r8 = 0x00000008        r9 = 0x00000009       r10 = 0x0000000a       r11 = 0x0000000b
+         sp = 0x0000000d        lr = 0x0000000e        pc = 0x0000000f      xpsr = 0x01000003
+        msp = 0x00000011       psp = 0x00000012   control = 0x00000002 faultmask = 0x00000000
+    basepri = 0x00000020   primask = 0x00000001
+exception frame (interrupted SysTick):
+         r0 = 0x00000000        r1 = 0x00000001        r2 = 0x00000002        r3 = 0x00000003
+        r12 = 0x0000000c        lr = 0x0000001f        pc = 0x00000022      xpsr = 0x0100000f
+"
+    );
+}
+
+#[test]
+fn exception_names() {
+    assert_eq!(Exception::from_xpsr(0x0100_0000).to_string(), "thread mode");
+    assert_eq!(Exception::from_xpsr(0x0100_0003).to_string(), "HardFault");
+    assert_eq!(Exception::from_xpsr(0x0100_000F).to_string(), "SysTick");
+    assert_eq!(Exception::from_xpsr(0x0100_0027).to_string(), "IRQ 23");
+    assert_eq!(Exception(8).to_string(), "reserved exception 8");
+    assert_eq!(Exception(39).irq(), Some(23));
+    assert_eq!(Exception(15).irq(), None);
+}
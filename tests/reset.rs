@@ -0,0 +1,105 @@
+//! Checks the detection of device resets
+
+use probe_run::{
+    reset::{self, RttWatch},
+    target::FakeTarget,
+    PC,
+};
+
+const RESET: u32 = 0x0000_0101;
+const RTT_ADDR: u32 = 0x2000_0000;
+const BUFFER_SIZE: u32 = 64;
+
+#[test]
+fn halted_at_the_reset_handler() {
+    let mut target = FakeTarget::new().with_register(PC, RESET & !1);
+
+    assert!(reset::at_reset_handler(&mut target, RESET).unwrap());
+}
+
+#[test]
+fn halted_elsewhere() {
+    let mut target = FakeTarget::new().with_register(PC, 0x0000_0200);
+
+    assert!(!reset::at_reset_handler(&mut target, RESET).unwrap());
+}
+
+/// RTT control block with one up channel whose offsets are `write` and `read`
+fn control_block(id: &[u8; 16], write: u32, read: u32) -> FakeTarget {
+    control_block_with(id, 1, write, read)
+}
+
+/// Like `control_block` but claims to have `max_up` up channels
+fn control_block_with(id: &[u8; 16], max_up: u32, write: u32, read: u32) -> FakeTarget {
+    let mut words = id
+        .chunks_exact(4)
+        .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect::<Vec<_>>();
+    // max up channels, max down channels
+    words.extend_from_slice(&[max_up, 0]);
+    // name, buffer, size, write offset, read offset, flags
+    words.extend_from_slice(&[0, 0x2000_0100, BUFFER_SIZE, write, read, 0]);
+    FakeTarget::new().with_words(RTT_ADDR, &words)
+}
+
+const ID: &[u8; 16] = b"SEGGER RTT\0\0\0\0\0\0";
+
+/// Starts watching a control block with offsets `(write, read)` and checks it once the offsets
+/// became `later`, after `consumed` bytes were read
+fn check(before: (u32, u32), later: (u32, u32), consumed: usize) -> bool {
+    let mut watch = RttWatch::new(&mut control_block(ID, before.0, before.1), RTT_ADDR).unwrap();
+    watch.consumed(0, consumed);
+    watch
+        .check(&mut control_block(ID, later.0, later.1))
+        .unwrap()
+}
+
+#[test]
+fn firmware_writes() {
+    assert!(!check((10, 10), (20, 10), 0));
+}
+
+#[test]
+fn firmware_writes_and_host_reads() {
+    assert!(!check((10, 10), (30, 20), 10));
+}
+
+#[test]
+fn write_offset_wraps_around() {
+    assert!(!check((60, 60), (6, 6), 10));
+}
+
+#[test]
+fn id_missing() {
+    let mut watch = RttWatch::new(&mut control_block(ID, 10, 10), RTT_ADDR).unwrap();
+
+    assert!(watch.check(&mut control_block(&[0; 16], 10, 10)).unwrap());
+}
+
+#[test]
+fn offsets_reset_under_an_intact_id() {
+    // the ID was copied back from `.data` before it could be seen missing
+    assert!(check((20, 20), (0, 0), 0));
+}
+
+#[test]
+fn read_offset_moved_by_the_firmware() {
+    assert!(check((20, 10), (20, 0), 0));
+}
+
+#[test]
+fn write_offset_went_backwards() {
+    // going from 20 to 15 would mean writing past the unread data at 10..20
+    assert!(check((20, 10), (15, 10), 0));
+}
+
+#[test]
+fn garbage_channel_count() {
+    let mut watch = RttWatch::new(&mut control_block(ID, 10, 10), RTT_ADDR).unwrap();
+
+    // only one descriptor is mapped; reading `u32::MAX` of them isn't even attempted
+    assert!(watch
+        .check(&mut control_block_with(ID, u32::MAX, 10, 10))
+        .unwrap());
+    assert!(RttWatch::new(&mut control_block_with(ID, u32::MAX, 10, 10), RTT_ADDR).is_err());
+}
@@ -0,0 +1,320 @@
+//! Services semihosting requests made by a fake target
+
+use std::{env, fs, path::PathBuf, process};
+
+use probe_rs::CoreRegisterAddress;
+use probe_run::{
+    config::OutputFormat,
+    semihosting::{Action, Semihosting},
+    target::{FakeTarget, TargetAccess as _},
+    PC,
+};
+
+const R0: CoreRegisterAddress = CoreRegisterAddress(0);
+const R1: CoreRegisterAddress = CoreRegisterAddress(1);
+
+const CODE: u32 = 0x100;
+const RAM: u32 = 0x2000_0000;
+
+const BKPT_SEMIHOSTING: [u8; 2] = [0xAB, 0xBE];
+const BKPT_0: [u8; 2] = [0x00, 0xBE];
+
+/// Target halted on `instruction` about to request `operation`; `ram` is placed at `RAM`
+fn halted_at(instruction: [u8; 2], operation: u32, parameter: u32, ram: Vec<u8>) -> FakeTarget {
+    FakeTarget::new()
+        .with_register(PC, CODE)
+        .with_register(R0, operation)
+        .with_register(R1, parameter)
+        .with_memory(CODE, instruction.to_vec())
+        .with_memory(RAM, ram)
+}
+
+/// An empty directory to use as the semihosting root
+fn root(name: &str) -> PathBuf {
+    let root = env::temp_dir().join(format!("probe-run-semihosting-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    root
+}
+
+/// Services `operation` with the parameter block `params`, followed by `data`; returns R0
+fn request(semihosting: &mut Semihosting, operation: u32, params: &[u32], data: &[u8]) -> u32 {
+    let mut ram = words(params);
+    ram.extend_from_slice(data);
+    let mut target = halted_at(BKPT_SEMIHOSTING, operation, RAM, ram);
+    semihosting.service(&mut target, &mut vec![]).unwrap();
+    target.read_core_reg(R0).unwrap()
+}
+
+fn words(words: &[u32]) -> Vec<u8> {
+    words
+        .iter()
+        .flat_map(|word| word.to_le_bytes().to_vec())
+        .collect()
+}
+
+#[test]
+fn other_breakpoints_are_ignored() {
+    let mut target = halted_at(BKPT_0, 0x18, 0x20026, vec![]);
+
+    let action = Semihosting::new(OutputFormat::Human)
+        .service(&mut target, &mut vec![])
+        .unwrap();
+
+    assert_eq!(action, None);
+}
+
+#[test]
+fn write0() {
+    let mut target = halted_at(BKPT_SEMIHOSTING, 0x04, RAM, b"Hello\n\0".to_vec());
+    let mut output = vec![];
+
+    let action = Semihosting::new(OutputFormat::Human)
+        .service(&mut target, &mut output)
+        .unwrap();
+
+    assert_eq!(action, Some(Action::Resume));
+    assert_eq!(output, b"Hello\n");
+    // execution continues after the `bkpt` instruction
+    assert_eq!(target.read_core_reg(PC).unwrap(), CODE + 2);
+}
+
+#[test]
+fn open_console_and_write() {
+    let mut semihosting = Semihosting::new(OutputFormat::Human);
+    let mut output = vec![];
+
+    // SYS_OPEN(":tt", "w")
+    let mut ram = words(&[RAM + 12, 4, 3]);
+    ram.extend_from_slice(b":tt\0");
+    let mut target = halted_at(BKPT_SEMIHOSTING, 0x01, RAM, ram);
+    semihosting.service(&mut target, &mut output).unwrap();
+    let handle = target.read_core_reg(R0).unwrap();
+    assert_ne!(handle, u32::MAX);
+
+    // SYS_WRITE(handle, "Hi!\n", 4)
+    let mut ram = words(&[handle, RAM + 12, 4]);
+    ram.extend_from_slice(b"Hi!\n");
+    let mut target = halted_at(BKPT_SEMIHOSTING, 0x05, RAM, ram);
+    semihosting.service(&mut target, &mut output).unwrap();
+
+    assert_eq!(output, b"Hi!\n");
+    // no bytes were left unwritten
+    assert_eq!(target.read_core_reg(R0).unwrap(), 0);
+}
+
+#[test]
+fn exit() {
+    let success = 0x20026;
+    let mut target = halted_at(BKPT_SEMIHOSTING, 0x18, success, vec![]);
+
+    let action = Semihosting::new(OutputFormat::Human)
+        .service(&mut target, &mut vec![])
+        .unwrap();
+
+    assert_eq!(action, Some(Action::Exit(0)));
+}
+
+#[test]
+fn exit_extended() {
+    let mut target = halted_at(BKPT_SEMIHOSTING, 0x20, RAM, words(&[0x20026, 42]));
+
+    let action = Semihosting::new(OutputFormat::Human)
+        .service(&mut target, &mut vec![])
+        .unwrap();
+
+    assert_eq!(action, Some(Action::Exit(42)));
+}
+
+#[test]
+fn files() {
+    let root = root("files");
+    fs::write(root.join("input.bin"), b"golden vector").unwrap();
+    let mut semihosting = Semihosting::new(OutputFormat::Human).with_root(root.clone());
+
+    // SYS_OPEN("input.bin", "rb")
+    let input = request(&mut semihosting, 0x01, &[RAM + 12, 1, 9], b"input.bin");
+    assert_ne!(input, u32::MAX);
+    // SYS_FLEN(input)
+    assert_eq!(request(&mut semihosting, 0x0C, &[input], &[]), 13);
+    // SYS_SEEK(input, 7)
+    assert_eq!(request(&mut semihosting, 0x0A, &[input, 7], &[]), 0);
+
+    // SYS_READ(input, buffer, 16): 6 bytes are left, so 10 are not read
+    let mut ram = words(&[input, RAM + 12, 16]);
+    ram.extend_from_slice(&[0; 16]);
+    let mut target = halted_at(BKPT_SEMIHOSTING, 0x06, RAM, ram);
+    semihosting.service(&mut target, &mut vec![]).unwrap();
+    assert_eq!(target.read_core_reg(R0).unwrap(), 10);
+    let mut buffer = [0; 6];
+    target.read_8(RAM + 12, &mut buffer).unwrap();
+    assert_eq!(&buffer, b"vector");
+
+    // SYS_OPEN("out/result.bin", "wb"), SYS_WRITE(output, "result", 6), SYS_CLOSE(output)
+    fs::create_dir(root.join("out")).unwrap();
+    let output = request(
+        &mut semihosting,
+        0x01,
+        &[RAM + 12, 5, 14],
+        b"out/result.bin",
+    );
+    assert_ne!(output, u32::MAX);
+    assert_eq!(
+        request(&mut semihosting, 0x05, &[output, RAM + 12, 6], b"result"),
+        0
+    );
+    assert_eq!(request(&mut semihosting, 0x02, &[output], &[]), 0);
+
+    assert_eq!(fs::read(root.join("out/result.bin")).unwrap(), b"result");
+}
+
+#[test]
+fn paths_may_not_escape_the_root() {
+    let root = root("escape");
+    fs::create_dir(root.join("dir")).unwrap();
+    let mut semihosting = Semihosting::new(OutputFormat::Human).with_root(root.join("dir"));
+
+    for name in &["../secret", "/etc/passwd", "a/../../secret"] {
+        // SYS_OPEN(name, "wb")
+        let handle = request(
+            &mut semihosting,
+            0x01,
+            &[RAM + 12, 5, name.len() as u32],
+            name.as_bytes(),
+        );
+        assert_eq!(handle, u32::MAX, "{}", name);
+    }
+    assert!(!root.join("secret").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinks_may_not_escape_the_root() {
+    use std::os::unix::fs::symlink;
+
+    let root = root("symlinks");
+    fs::create_dir(root.join("dir")).unwrap();
+    // `outside.bin` doesn't exist, so the link dangles
+    symlink(root.join("outside.bin"), root.join("dir/dangling.bin")).unwrap();
+    fs::write(root.join("dir/inside.bin"), b"").unwrap();
+    symlink(root.join("dir/inside.bin"), root.join("dir/link.bin")).unwrap();
+    let mut semihosting = Semihosting::new(OutputFormat::Human).with_root(root.join("dir"));
+
+    // SYS_OPEN("dangling.bin", "wb")
+    let handle = request(&mut semihosting, 0x01, &[RAM + 12, 5, 12], b"dangling.bin");
+    assert_eq!(handle, u32::MAX);
+    assert!(!root.join("outside.bin").exists());
+
+    // SYS_OPEN("link.bin", "wb") follows a link to a file inside the root
+    let handle = request(&mut semihosting, 0x01, &[RAM + 12, 5, 8], b"link.bin");
+    assert_ne!(handle, u32::MAX);
+}
+
+#[test]
+fn file_names_are_bounded() {
+    let root = root("names");
+    let mut semihosting = Semihosting::new(OutputFormat::Human).with_root(root);
+
+    // SYS_OPEN(name, "rb", u32::MAX) fails without reading the name
+    let handle = request(
+        &mut semihosting,
+        0x01,
+        &[RAM + 12, 1, u32::MAX],
+        b"input.bin",
+    );
+    assert_eq!(handle, u32::MAX);
+    // SYS_ERRNO reports ENAMETOOLONG
+    assert_eq!(request(&mut semihosting, 0x13, &[], &[]), 36);
+}
+
+#[test]
+fn files_need_a_root() {
+    let mut semihosting = Semihosting::new(OutputFormat::Human);
+
+    // SYS_OPEN("input.bin", "rb")
+    let handle = request(&mut semihosting, 0x01, &[RAM + 12, 1, 9], b"input.bin");
+
+    assert_eq!(handle, u32::MAX);
+}
+
+#[test]
+fn files_larger_than_a_chunk() {
+    let root = root("chunks");
+    let data = (0..10_000).map(|i| i as u8).collect::<Vec<_>>();
+    let mut semihosting = Semihosting::new(OutputFormat::Human).with_root(root.clone());
+
+    // SYS_OPEN("data.bin", "w+b"), SYS_WRITE(file, data, 10000)
+    let file = request(&mut semihosting, 0x01, &[RAM + 12, 7, 8], b"data.bin");
+    assert_ne!(file, u32::MAX);
+    assert_eq!(
+        request(&mut semihosting, 0x05, &[file, RAM + 12, 10_000], &data),
+        0
+    );
+    assert_eq!(fs::read(root.join("data.bin")).unwrap(), data);
+
+    // SYS_SEEK(file, 0), SYS_READ(file, buffer, 10000)
+    assert_eq!(request(&mut semihosting, 0x0A, &[file, 0], &[]), 0);
+    let mut ram = words(&[file, RAM + 12, 10_000]);
+    ram.extend_from_slice(&[0; 10_000]);
+    let mut target = halted_at(BKPT_SEMIHOSTING, 0x06, RAM, ram);
+    semihosting.service(&mut target, &mut vec![]).unwrap();
+    assert_eq!(target.read_core_reg(R0).unwrap(), 0);
+    let mut buffer = vec![0; 10_000];
+    target.read_8(RAM + 12, &mut buffer).unwrap();
+    assert_eq!(buffer, data);
+}
+
+#[test]
+fn lengths_are_not_trusted() {
+    let root = root("lengths");
+    fs::write(root.join("input.bin"), b"golden vector").unwrap();
+    let mut semihosting = Semihosting::new(OutputFormat::Human).with_root(root);
+
+    // SYS_WRITE to a handle that was never opened doesn't touch the data
+    assert_eq!(
+        request(&mut semihosting, 0x05, &[42, RAM + 12, u32::MAX], &[]),
+        u32::MAX
+    );
+
+    // SYS_READ(input, buffer, u32::MAX) stops at the end of the file
+    let input = request(&mut semihosting, 0x01, &[RAM + 12, 1, 9], b"input.bin");
+    assert_ne!(input, u32::MAX);
+    let mut ram = words(&[input, RAM + 12, u32::MAX]);
+    ram.extend_from_slice(&[0; 13]);
+    let mut target = halted_at(BKPT_SEMIHOSTING, 0x06, RAM, ram);
+    semihosting.service(&mut target, &mut vec![]).unwrap();
+    assert_eq!(target.read_core_reg(R0).unwrap(), u32::MAX - 13);
+    let mut buffer = [0; 13];
+    target.read_8(RAM + 12, &mut buffer).unwrap();
+    assert_eq!(&buffer, b"golden vector");
+}
+
+#[test]
+fn write0_spanning_blocks() {
+    // the string starts at an unaligned address and is longer than a block
+    let string = (0..100).map(|i| b'a' + i % 26).collect::<Vec<_>>();
+    let mut ram = vec![0xFF; 3];
+    ram.extend_from_slice(&string);
+    ram.resize(256, 0);
+    let mut target = halted_at(BKPT_SEMIHOSTING, 0x04, RAM + 3, ram);
+    let mut output = vec![];
+
+    Semihosting::new(OutputFormat::Human)
+        .service(&mut target, &mut output)
+        .unwrap();
+
+    assert_eq!(output, string);
+}
+
+#[test]
+fn write0_is_truncated() {
+    // no NUL byte within the limit
+    let mut target = halted_at(BKPT_SEMIHOSTING, 0x04, RAM, vec![b'a'; 5000]);
+    let mut output = vec![];
+
+    Semihosting::new(OutputFormat::Human)
+        .service(&mut target, &mut output)
+        .unwrap();
+
+    assert_eq!(output, vec![b'a'; 4096]);
+}
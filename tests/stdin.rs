@@ -0,0 +1,47 @@
+//! Checks how stdin is split into the chunks forwarded to the device
+
+use probe_run::{config::StdinMode, stdin::Splitter};
+
+#[test]
+fn line_mode_waits_for_the_newline() {
+    let mut splitter = Splitter::new(StdinMode::Line);
+
+    assert_eq!(splitter.received(b"self"), b"");
+    assert_eq!(splitter.received(b"test\nsta"), b"selftest\n");
+    assert_eq!(splitter.received(b"tus\n"), b"status\n");
+    assert_eq!(splitter.finish(), b"");
+}
+
+#[test]
+fn line_mode_sends_every_complete_line_at_once() {
+    let mut splitter = Splitter::new(StdinMode::Line);
+
+    assert_eq!(splitter.received(b"a\nb\nc"), b"a\nb\n");
+    assert_eq!(splitter.received(b"\r\n"), b"c\r\n");
+}
+
+#[test]
+fn line_mode_sends_the_unterminated_last_line_at_the_end() {
+    let mut splitter = Splitter::new(StdinMode::Line);
+
+    assert_eq!(splitter.received(b"reboot"), b"");
+    assert_eq!(splitter.finish(), b"reboot");
+    assert_eq!(splitter.finish(), b"");
+}
+
+#[test]
+fn raw_mode_sends_every_read() {
+    let mut splitter = Splitter::new(StdinMode::Raw);
+
+    assert_eq!(splitter.received(b"k"), b"k");
+    assert_eq!(splitter.received(b"\x1b[A"), b"\x1b[A");
+    assert_eq!(splitter.finish(), b"");
+}
+
+#[test]
+fn off_mode_sends_nothing() {
+    let mut splitter = Splitter::new(StdinMode::Off);
+
+    assert_eq!(splitter.received(b"selftest\n"), b"");
+    assert_eq!(splitter.finish(), b"");
+}
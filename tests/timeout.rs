@@ -0,0 +1,109 @@
+//! Checks the parsing of `--timeout` and how a run that exceeds it ends
+
+use std::time::Duration;
+
+use probe_run::{
+    backtrace::TopException,
+    config::{ResetPolicy, Timeout},
+    exception::Exception,
+    halt_reason::HaltReason,
+    Deadline, ExitReason, LoopEnd, TIMED_OUT,
+};
+
+fn timeout(s: &str) -> Result<Duration, String> {
+    s.parse::<Timeout>()
+        .map(|timeout| timeout.0)
+        .map_err(|e| e.to_string())
+}
+
+#[test]
+fn units() {
+    assert_eq!(timeout("250ms"), Ok(Duration::from_millis(250)));
+    assert_eq!(timeout("90"), Ok(Duration::from_secs(90)));
+    assert_eq!(timeout("90s"), Ok(Duration::from_secs(90)));
+    assert_eq!(timeout("5m"), Ok(Duration::from_secs(5 * 60)));
+    assert_eq!(timeout("2h"), Ok(Duration::from_secs(2 * 60 * 60)));
+}
+
+#[test]
+fn unknown_unit() {
+    assert_eq!(
+        timeout("5d"),
+        Err("unknown unit `d` in timeout `5d`; expected one of `ms`, `s`, `m` or `h`".to_string())
+    );
+}
+
+#[test]
+fn too_long() {
+    let minutes = format!("{}m", u64::MAX / 60 + 1);
+    let hours = format!("{}h", u64::MAX / (60 * 60) + 1);
+
+    assert_eq!(
+        timeout(&minutes),
+        Err(format!("timeout `{}` is too long", minutes))
+    );
+    assert_eq!(
+        timeout(&hours),
+        Err(format!("timeout `{}` is too long", hours))
+    );
+    // the longest timeout in hours still parses
+    assert!(timeout(&format!("{}h", u64::MAX / (60 * 60))).is_ok());
+}
+
+#[test]
+fn deadline() {
+    assert!(Deadline::start(Some(Duration::from_secs(0))).expired());
+    assert!(!Deadline::start(Some(Duration::from_secs(60 * 60))).expired());
+    assert!(!Deadline::start(None).expired());
+    // too far in the future to represent
+    assert!(!Deadline::start(Some(Duration::from_secs(u64::MAX))).expired());
+}
+
+#[test]
+fn timed_out() {
+    let end = LoopEnd {
+        timed_out: true,
+        ..LoopEnd::default()
+    };
+
+    // `probe-run` halts the core, so DFSR isn't read
+    assert!(end.still_running());
+    let exit_reason = end.exit_reason(Some(&TopException::Other), None, ResetPolicy::Fail);
+    assert_eq!(exit_reason, ExitReason::TimedOut);
+    assert_eq!(exit_reason.exit_code(), TIMED_OUT);
+}
+
+#[test]
+fn timed_out_in_the_hard_fault_handler() {
+    let end = LoopEnd {
+        timed_out: true,
+        ..LoopEnd::default()
+    };
+
+    let top_exception = TopException::HardFault {
+        exception: Exception::HARD_FAULT,
+        stack_overflow: false,
+    };
+    assert_eq!(
+        end.exit_reason(Some(&top_exception), None, ResetPolicy::Fail),
+        ExitReason::HardFault {
+            exception: Exception::HARD_FAULT,
+            stack_overflow: false,
+        }
+    );
+}
+
+#[test]
+fn halted_before_the_timeout() {
+    let end = LoopEnd::default();
+
+    assert!(!end.still_running());
+    assert_eq!(
+        end.exit_reason(
+            Some(&TopException::Other),
+            Some(HaltReason(0b10)),
+            ResetPolicy::Fail
+        ),
+        ExitReason::Halted
+    );
+}
@@ -0,0 +1,65 @@
+//! Checks the setup of the DEMCR vector catches
+
+use probe_run::{
+    exception::Exception,
+    target::{FakeTarget, TargetAccess as _},
+    vector_catch::VectorCatch,
+};
+
+const DEMCR: u32 = 0xE000_EDFC;
+const TRCENA: u32 = 1 << 24;
+
+#[test]
+fn hard_fault() {
+    let mut core = FakeTarget::new().with_words(DEMCR, &[TRCENA]);
+
+    let vector_catch = VectorCatch::enable(&mut core, &[Exception::HARD_FAULT], false).unwrap();
+
+    // VC_HARDERR; the trace enable bit is left alone
+    assert_eq!(core.read_word_32(DEMCR).unwrap(), TRCENA | 1 << 10);
+    assert!(vector_catch.catches(Exception::HARD_FAULT));
+    assert!(!vector_catch.catches(Exception::BUS_FAULT));
+    assert!(!vector_catch.catches_reset());
+}
+
+#[test]
+fn reset() {
+    let mut core = FakeTarget::new().with_words(DEMCR, &[0]);
+
+    let vector_catch = VectorCatch::enable(&mut core, &[], true).unwrap();
+
+    // VC_CORERESET
+    assert_eq!(core.read_word_32(DEMCR).unwrap(), 1);
+    assert!(vector_catch.catches_reset());
+}
+
+#[test]
+fn configurable_faults() {
+    let mut core = FakeTarget::new().with_words(DEMCR, &[0]);
+
+    let vector_catch =
+        VectorCatch::enable(&mut core, &Exception::CONFIGURABLE_FAULTS, false).unwrap();
+
+    // VC_MMERR, VC_NOCPERR, VC_CHKERR, VC_STATERR, VC_BUSERR and VC_SFERR
+    assert_eq!(core.read_word_32(DEMCR).unwrap(), 0x0000_09F0);
+    for &fault in &Exception::CONFIGURABLE_FAULTS {
+        assert!(vector_catch.catches(fault));
+    }
+    assert!(!vector_catch.catches(Exception::HARD_FAULT));
+}
+
+#[test]
+fn restore() {
+    // VC_BUSERR was already set, e.g. by another debugger
+    let mut core = FakeTarget::new().with_words(DEMCR, &[TRCENA | 1 << 8]);
+
+    let vector_catch = VectorCatch::enable(
+        &mut core,
+        &[Exception::HARD_FAULT, Exception::MEM_MANAGE],
+        true,
+    )
+    .unwrap();
+    vector_catch.restore(&mut core).unwrap();
+
+    assert_eq!(core.read_word_32(DEMCR).unwrap(), TRCENA | 1 << 8);
+}